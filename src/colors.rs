@@ -0,0 +1,92 @@
+// mapping from DVG intensity (z) to the colour and thickness a vector is
+// drawn with
+
+use sdl2::pixels::Color;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct ColorScheme {
+    colors: [Color; 16],
+    widths: [u8; 16], // in pixels
+}
+
+impl ColorScheme {
+    // what the real monitor did: brightness varies with intensity, and the
+    // brighter lines bloom wider, from 1 pixel up to 3 at full intensity
+    pub fn monochrome() -> ColorScheme {
+        let mut colors = [Color::RGBA(255, 255, 255, 0); 16];
+        let mut widths = [1; 16];
+        for (z, (color, width)) in colors.iter_mut().zip(widths.iter_mut()).enumerate() {
+            *color = Color::RGBA(255, 255, 255, z as u8 * 17);
+            *width = 1 + z as u8 / 6;
+        }
+        ColorScheme { colors, widths }
+    }
+
+    // not authentic, but picks out the mid intensities in colour
+    pub fn classic() -> ColorScheme {
+        let mut scheme = ColorScheme::monochrome();
+        scheme.widths = [1; 16];
+        scheme.colors[7] = Color::RGBA(255, 0, 0, 255);
+        scheme.colors[8] = Color::RGBA(0, 255, 0, 255);
+        scheme.colors[9] = Color::RGBA(0, 0, 255, 255);
+        scheme.colors[10] = Color::RGBA(255, 255, 0, 255);
+        scheme.colors[11] = Color::RGBA(255, 0, 255, 255);
+        scheme.colors[12] = Color::RGBA(0, 255, 255, 255);
+        scheme
+    }
+
+    pub fn preset(name: &str) -> Option<ColorScheme> {
+        match name {
+            "classic" => Some(ColorScheme::classic()),
+            "monochrome" => Some(ColorScheme::monochrome()),
+            _ => None,
+        }
+    }
+
+    // a scheme file has lines of "z r g b [a]" in decimal, with '#' starting
+    // a comment. intensities not listed keep their monochrome colour, and
+    // all of them keep the monochrome thickness
+    pub fn parse(text: &str) -> Result<ColorScheme, String> {
+        let mut scheme = ColorScheme::monochrome();
+        for (number, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = code.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let values: Result<Vec<u8>, _> = fields.iter().map(|f| f.parse::<u8>()).collect();
+            match values {
+                Ok(ref v) if (v.len() == 4 || v.len() == 5) && v[0] < 16 => {
+                    let alpha = if v.len() == 5 { v[4] } else { 255 };
+                    scheme.colors[v[0] as usize] = Color::RGBA(v[1], v[2], v[3], alpha);
+                }
+                _ => return Err(format!("Invalid colour on line {}: '{}'", number + 1, line)),
+            }
+        }
+        Ok(scheme)
+    }
+
+    pub fn from_file(path: &Path) -> Result<ColorScheme, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        ColorScheme::parse(&text)
+    }
+
+    // accept either a preset name or a scheme file
+    pub fn load(name: &str) -> Result<ColorScheme, String> {
+        match ColorScheme::preset(name) {
+            Some(scheme) => Ok(scheme),
+            None => ColorScheme::from_file(Path::new(name)),
+        }
+    }
+
+    pub fn color(&self, z: u16) -> Color {
+        self.colors[(z & 0xF) as usize]
+    }
+
+    pub fn width(&self, z: u16) -> u8 {
+        self.widths[(z & 0xF) as usize]
+    }
+}
@@ -0,0 +1,325 @@
+// the command line. the options most of the emulator depends on are parsed
+// into fields, the rest are looked up by name where they're used
+
+use clap::{App, Arg, ArgMatches};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// where the vector display was first plugged in
+pub const DEFAULT_SERIAL_PORT: &str = "/dev/ttyACM2";
+
+pub const DEFAULT_BAUD_RATE: u32 = 921_600;
+
+pub const MAX_WINDOW_SCALE: u32 = 8;
+
+pub const MAX_SPEED: f32 = 100.0;
+
+// how fast the game runs compared to the real machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedMode {
+    Realtime,
+    Unlimited, // as fast as the host can go, for benchmarking
+    Multiplier(f32),
+}
+
+impl SpeedMode {
+    // "max" or a multiple of real time, e.g. 2 or 0.5
+    pub fn from_name(name: &str) -> Option<SpeedMode> {
+        match name {
+            "max" => Some(SpeedMode::Unlimited),
+            _ => match name.parse::<f32>() {
+                Ok(speed) if speed == 1.0 => Some(SpeedMode::Realtime),
+                Ok(speed) if speed > 0.0 && speed <= MAX_SPEED => {
+                    Some(SpeedMode::Multiplier(speed))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    // how long emulating the given time should take, or None not to wait
+    pub fn wall_time(self, emulated: Duration) -> Option<Duration> {
+        match self {
+            SpeedMode::Realtime => Some(emulated),
+            SpeedMode::Unlimited => None,
+            SpeedMode::Multiplier(speed) => {
+                let nanos = emulated.as_secs() as f64 * 1e9 + emulated.subsec_nanos() as f64;
+                Some(Duration::from_nanos((nanos / speed as f64) as u64))
+            }
+        }
+    }
+}
+
+// options taking a value, as name, value name and help
+const OPTIONS: &[(&str, &str, &str)] = &[
+    ("disassemble", "START-END", "Print a disassembly of a hex address range and exit"),
+    ("audio-buffer", "SAMPLES", "Mixer buffer size, a power of two from 256 to 8192"),
+    ("audio-rate", "HZ", "Mixer sample rate"),
+    ("dvg-program", "FILE", "Render a hand written display list instead of the game"),
+    ("panel", "PORT", "Also read the controls from a panel on this serial port"),
+    ("serial-delay-us", "MICROSECONDS", "Pause after each serial display command"),
+    ("serial-packet", "VECTORS", "Write this many serial display commands at a time"),
+    ("serial-frame-sync", "MARKER", "Send a sync packet of this marker after each frame"),
+    ("serial-exclusions", "FILE", "Rectangles of the screen not sent to the serial display"),
+    ("serial-skip-z", "LIST", "Intensities not sent to the serial display"),
+    ("geometry-scale", "FACTOR", "Make the picture bigger or smaller, 0.1 to 8"),
+    ("beam-trace", "VECTORS", "Animate the beam, presenting every so many vectors"),
+    ("sound-fade", "MS", "How long the thrust and UFO loops take to fade"),
+    ("sound-dir", "DIR", "Where to find the sound effect samples"),
+    ("volume", "LEVEL", "Master volume from 0 to 128"),
+    ("effect-volume", "LIST", "Volumes of individual effects, e.g. thump_low=32"),
+    ("colors", "SCHEME", "monochrome, classic or a file of z r g b lines"),
+    ("coinage", "SETTING", "free, 1c1c, 1c2c or 2c1c"),
+    ("lives", "3|4", "Ships per game"),
+    ("language", "NAME", "english, german, french or spanish"),
+    ("keys", "FILE", "Rebind the game controls"),
+    ("state-file", "FILE", "Where F5 saves the machine's state and F9 loads it from"),
+    ("rewind-interval", "MS", "How often a state is kept for rewinding"),
+    ("rewind-depth", "STATES", "How many states are kept for rewinding, 0 to turn it off"),
+    ("record-gif", "FILE", "Record the picture as an animated GIF, finished on quit"),
+    ("record", "FILE", "Record the game controls"),
+    ("play", "FILE", "Play back a recording of the game controls"),
+    ("watchdog", "NMIS", "NMIs without the watchdog cleared before a reset, 0 for never"),
+    ("break", "LIST", "Comma separated hex addresses to stop at"),
+    ("watch", "START-END", "Print writes to a hex address range"),
+    ("watch-reads", "START-END", "Print reads from a hex address range"),
+    ("coverage", "FILE", "On exit, write the ROM ranges run as code and read as data"),
+    ("trace", "FILE", "Write a nestest style line per instruction"),
+    ("functional-test", "FILE", "Run Klaus Dormann's 6502 functional test and exit"),
+    ("single-step-tests", "PATH", "Run Tom Harte's single step CPU tests and exit"),
+    ("websocket", "ADDRESS:PORT", "Serve display lists to WebSocket clients"),
+    ("headless", "FRAMES", "Run this many frames without a window and exit"),
+    ("gamma", "GAMMA", "Curve from vector intensity to brightness, 0.1 to 5"),
+    ("phosphor", "DECAY", "Let vectors fade, losing this fraction of brightness a frame"),
+    ("speed", "SPEED", "A multiple of the real speed, or max to run flat out"),
+    ("turbo", "SPEED", "How fast holding tab fast forwards, max by default"),
+];
+
+// options that are on or off, as name and help
+const FLAGS: &[(&str, &str)] = &[
+    ("strict-rom", "Refuse to run a ROM that isn't a known revision"),
+    ("serial-ack", "Wait for the serial display to acknowledge each command"),
+    ("sound-test", "Keys 1 to 9 play the sound effects instead of the game"),
+    ("step", "Stop before the first instruction and step through from the terminal"),
+    ("strict-dvg", "Stop with an error if the display list is broken"),
+    ("turbo-sound", "Keep playing the sound while fast forwarding"),
+    ("vsync", "Show frames in step with the monitor's refresh"),
+    ("cocktail", "Turn the picture upside down for player 2"),
+    ("god-mode", "Ships are never lost"),
+    ("turbo-fire", "Holding fire shoots as fast as the game allows"),
+    ("profile", "On exit, print how often each op code and address was executed"),
+    ("illegal-nop", "Run undocumented op codes as NOPs"),
+    ("open-bus", "Unmapped reads return the last byte on the data bus"),
+    ("accurate", "Emulate hardware quirks the game doesn't depend on"),
+];
+
+pub struct Config {
+    pub debug: bool,
+    pub rom: Option<PathBuf>,
+    pub rom_set: Option<Vec<PathBuf>>,
+    pub serial: Option<String>, // None with --no-serial
+    pub baud: u32,
+    pub scale: Option<u32>,     // None to fit the screen
+    pub speed: SpeedMode,
+    pub mute: bool,
+    matches: ArgMatches<'static>,
+}
+
+impl Config {
+    fn app() -> App<'static, 'static> {
+        let mut app = App::new("asteroids-emu")
+            .version(crate_version!())
+            .about("Runs the Asteroids arcade game ROM")
+            // as it was before there were options
+            .arg(
+                Arg::with_name("mode")
+                    .index(1)
+                    .possible_values(&["debug"])
+                    .hidden(true),
+            )
+            .arg(Arg::with_name("debug").long("debug").help(
+                "Print what the CPU and DVG are doing, and find what drew a vector by clicking it",
+            ))
+            .arg(
+                Arg::with_name("rom")
+                    .long("rom")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("The ROM to run instead of assets/asteroids.rom"),
+            )
+            .arg(
+                Arg::with_name("rom-set")
+                    .long("rom-set")
+                    .takes_value(true)
+                    .value_name("FILES")
+                    .conflicts_with("rom")
+                    .help("Comma separated dumps of the four ROM chips"),
+            )
+            .arg(
+                Arg::with_name("serial")
+                    .long("serial")
+                    .takes_value(true)
+                    .value_name("DEVICE")
+                    .help("The serial port of the vector display"),
+            )
+            .arg(
+                Arg::with_name("baud")
+                    .long("baud")
+                    .takes_value(true)
+                    .value_name("RATE")
+                    .help("Baud rate of the vector display and control panel"),
+            )
+            .arg(
+                Arg::with_name("no-serial")
+                    .long("no-serial")
+                    .conflicts_with("serial")
+                    .help("Don't look for a vector display"),
+            )
+            .arg(
+                Arg::with_name("scale")
+                    .long("scale")
+                    .takes_value(true)
+                    .value_name("N")
+                    .help("Draw the picture at a whole multiple of 1024x832, letterboxed"),
+            )
+            .arg(
+                Arg::with_name("mute")
+                    .long("mute")
+                    .help("Start with the sound off"),
+            );
+        for &(name, value_name, help) in OPTIONS {
+            app = app.arg(
+                Arg::with_name(name)
+                    .long(name)
+                    .takes_value(true)
+                    .value_name(value_name)
+                    .help(help),
+            );
+        }
+        for &(name, help) in FLAGS {
+            app = app.arg(Arg::with_name(name).long(name).help(help));
+        }
+        app
+    }
+
+    // --help, --version and mistakes in the options are reported by clap,
+    // which exits. values clap can't check are returned as errors
+    pub fn from_args<I, T>(args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Config::app().get_matches_from(args);
+        let scale = match matches.value_of("scale") {
+            Some(value) => match value.parse::<u32>() {
+                Ok(scale) if scale >= 1 && scale <= MAX_WINDOW_SCALE => Some(scale),
+                _ => {
+                    return Err(format!(
+                        "Window scale must be a whole number from 1 to {}",
+                        MAX_WINDOW_SCALE
+                    ))
+                }
+            },
+            None => None,
+        };
+        let serial = if matches.is_present("no-serial") {
+            None
+        } else {
+            Some(
+                matches
+                    .value_of("serial")
+                    .unwrap_or(DEFAULT_SERIAL_PORT)
+                    .to_string(),
+            )
+        };
+        let baud = match matches.value_of("baud") {
+            Some(value) => match value.parse::<u32>() {
+                Ok(baud) if baud > 0 => baud,
+                _ => return Err(format!("Invalid baud rate '{}'", value)),
+            },
+            None => DEFAULT_BAUD_RATE,
+        };
+        let speed = match matches.value_of("speed") {
+            Some(name) => SpeedMode::from_name(name).ok_or_else(|| {
+                format!(
+                    "Invalid speed '{}', expected max or a multiple of real time up to {}",
+                    name, MAX_SPEED
+                )
+            })?,
+            None => SpeedMode::Realtime,
+        };
+        Ok(Config {
+            debug: matches.is_present("debug") || matches.value_of("mode") == Some("debug"),
+            rom: matches.value_of("rom").map(PathBuf::from),
+            rom_set: matches
+                .value_of("rom-set")
+                .map(|list| list.split(',').map(PathBuf::from).collect()),
+            serial,
+            baud,
+            scale,
+            speed,
+            mute: matches.is_present("mute"),
+            matches,
+        })
+    }
+
+    // the value of one of the other options
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.matches.value_of(name)
+    }
+
+    // whether one of the other flags was given
+    pub fn flag(&self, name: &str) -> bool {
+        self.matches.is_present(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the arguments after the program name
+    fn from(args: &[&str]) -> Result<Config, String> {
+        Config::from_args(Some("asteroids-emu").into_iter().chain(args.iter().cloned()))
+    }
+
+    fn config(args: &[&str]) -> Config {
+        from(args).unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&[]);
+        assert!(!config.debug);
+        assert_eq!(config.rom, None);
+        assert_eq!(config.serial, Some(DEFAULT_SERIAL_PORT.to_string()));
+        assert_eq!(config.baud, DEFAULT_BAUD_RATE);
+        assert_eq!(config.scale, None);
+        assert_eq!(config.speed, SpeedMode::Realtime);
+        assert!(!config.mute);
+    }
+
+    #[test]
+    fn options() {
+        assert!(config(&["--debug"]).debug);
+        // as it was before there were options
+        assert!(config(&["debug"]).debug);
+        assert_eq!(config(&["--rom", "test.rom"]).rom, Some(PathBuf::from("test.rom")));
+        assert_eq!(config(&["--serial", "/dev/ttyUSB0"]).serial, Some("/dev/ttyUSB0".to_string()));
+        assert_eq!(config(&["--no-serial"]).serial, None);
+        assert_eq!(config(&["--scale", "2"]).scale, Some(2));
+        assert!(config(&["--mute"]).mute);
+        assert_eq!(config(&["--speed", "max"]).speed, SpeedMode::Unlimited);
+        assert!(config(&["--step"]).flag("step"));
+        assert_eq!(config(&["--break", "6800"]).value("break"), Some("6800"));
+    }
+
+    #[test]
+    fn values_out_of_range() {
+        assert!(from(&["--scale", "0"]).is_err());
+        assert!(from(&["--scale", "9"]).is_err());
+        assert!(from(&["--baud", "fast"]).is_err());
+        assert!(from(&["--speed", "0"]).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+// run standard 6502 test programs against the CPU, with plain RAM in place
+// of the Asteroids hardware
+
+use serde_json::{self, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cpu::{Bus, Cpu, CpuState, Instruction};
+
+// where Klaus Dormann's 6502_functional_test.bin starts, and the address of
+// the JMP * it reaches once every test has passed, for the binary as
+// assembled with the default options. decimal mode is tested too
+const FUNCTIONAL_TEST_START: u16 = 0x0400;
+const FUNCTIONAL_TEST_SUCCESS: u16 = 0x3469;
+const FUNCTIONAL_TEST_CASE: u16 = 0x0200; // number of the test being run
+
+// failures reported per file of single step tests, as one bug tends to
+// fail most of the cases for its op code
+const MAX_REPORTED_FAILURES: usize = 5;
+
+// 64K of RAM with nothing mapped, for running any 6502 code on the CPU
+// without the Asteroids ROMs or hardware, which Memory always has
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> FlatMemory {
+        FlatMemory {
+            bytes: vec![0; 0x10000],
+        }
+    }
+
+    // initial from address 0, with anything past FFFF left off and the rest
+    // of memory zero
+    pub fn from_bytes(initial: &[u8]) -> FlatMemory {
+        let mut memory = FlatMemory::new();
+        memory.load(0, initial);
+        memory
+    }
+
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        let start = addr as usize;
+        let end = (start + data.len()).min(self.bytes.len());
+        self.bytes[start..end].copy_from_slice(&data[..end - start]);
+    }
+}
+
+impl Bus for FlatMemory {
+    fn read(&self, addr: u16) -> u8 {
+        self.bytes[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        self.bytes[addr as usize] = byte;
+    }
+}
+
+// the test traps failures (and finally success) with a jump or branch to
+// itself, so it runs until an instruction leaves PC where it was. returns
+// the number of cycles taken on success, or a description of the failure
+pub fn run_functional_test(path: &Path) -> Result<u64, String> {
+    let image = fs::read(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let mut memory = FlatMemory::from_bytes(&image);
+    let mut cpu = Cpu::new(false, false);
+    cpu.restore(CpuState {
+        a: 0,
+        x: 0,
+        y: 0,
+        pc: FUNCTIONAL_TEST_START,
+        s: 0xFF,
+        p: 0x24,
+        cycle: 0,
+    });
+    loop {
+        let pc = cpu.pc();
+        cpu.execute_instruction(&mut memory);
+        if cpu.pc() == pc {
+            break;
+        }
+    }
+    if cpu.pc() == FUNCTIONAL_TEST_SUCCESS {
+        Ok(cpu.cycle)
+    } else {
+        Err(format!(
+            "Functional test ${:02X} failed at {:04X} after {} cycles (A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{:02X})",
+            memory.read(FUNCTIONAL_TEST_CASE),
+            cpu.pc(),
+            cpu.cycle,
+            cpu.a(),
+            cpu.x(),
+            cpu.y(),
+            cpu.s(),
+            cpu.p()
+        ))
+    }
+}
+
+pub struct SingleStepResults {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped,
+}
+
+// Tom Harte's SingleStepTests (https://github.com/SingleStepTests/65x02),
+// a file of cases per op code, each giving the registers and RAM before and
+// after a single instruction and the bus cycles it takes. path is either a
+// single file, e.g. 69.json to look at ADC #, or a directory of them.
+// undocumented op codes that decode as INVALID are skipped
+pub fn run_single_step_tests(path: &Path) -> Result<SingleStepResults, String> {
+    let files = if path.is_dir() {
+        let entries = fs::read_dir(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| file.extension().map_or(false, |ext| ext == "json"))
+            .collect();
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let mut results = SingleStepResults {
+        passed: 0,
+        failed: 0,
+        skipped: 0,
+    };
+    for file in files {
+        let text = fs::read_to_string(&file)
+            .map_err(|e| format!("Error reading {}: {}", file.display(), e))?;
+        let cases: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Error parsing {}: {}", file.display(), e))?;
+        let cases = cases
+            .as_array()
+            .ok_or_else(|| format!("{} isn't a list of test cases", file.display()))?;
+        let mut reported = 0;
+        for case in cases {
+            match run_single_step_case(case)? {
+                Outcome::Passed => results.passed += 1,
+                Outcome::Skipped => results.skipped += 1,
+                Outcome::Failed(message) => {
+                    results.failed += 1;
+                    if reported < MAX_REPORTED_FAILURES {
+                        println!("{}: {}", file.display(), message);
+                        reported += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn json_number(value: &Value, name: &str) -> Result<u64, String> {
+    value[name]
+        .as_u64()
+        .ok_or_else(|| format!("Test case without a number for \"{}\"", name))
+}
+
+// the registers and the [address, value] pairs of RAM of an "initial" or
+// "final" state
+fn single_step_state(value: &Value, cycle: u64) -> Result<(CpuState, Vec<(u16, u8)>), String> {
+    let state = CpuState {
+        a: json_number(value, "a")? as u8,
+        x: json_number(value, "x")? as u8,
+        y: json_number(value, "y")? as u8,
+        pc: json_number(value, "pc")? as u16,
+        s: json_number(value, "s")? as u8,
+        p: json_number(value, "p")? as u8,
+        cycle,
+    };
+    let ram = value["ram"]
+        .as_array()
+        .ok_or_else(|| "Test case without RAM".to_string())?
+        .iter()
+        .map(|pair| match (pair[0].as_u64(), pair[1].as_u64()) {
+            (Some(addr), Some(byte)) => Ok((addr as u16, byte as u8)),
+            _ => Err(format!("Invalid RAM entry {}", pair)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((state, ram))
+}
+
+fn run_single_step_case(case: &Value) -> Result<Outcome, String> {
+    let name = case["name"].as_str().unwrap_or("?");
+    let cycles = case["cycles"]
+        .as_array()
+        .ok_or_else(|| format!("Test case {} without cycles", name))?
+        .len() as u64;
+    let (initial, ram) = single_step_state(&case["initial"], 0)?;
+    let (expected, expected_ram) = single_step_state(&case["final"], cycles)?;
+    let mut memory = FlatMemory::new();
+    for (addr, byte) in ram {
+        memory.write(addr, byte);
+    }
+    if Cpu::decode_at(initial.pc, &memory).instruction == Instruction::INVALID {
+        return Ok(Outcome::Skipped);
+    }
+    let mut cpu = Cpu::new(false, false);
+    cpu.restore(initial);
+    cpu.execute_instruction(&mut memory);
+    let state = cpu.snapshot();
+    if state != expected {
+        return Ok(Outcome::Failed(format!(
+            "{}: expected {:?}, got {:?}",
+            name, expected, state
+        )));
+    }
+    for (addr, byte) in expected_ram {
+        let actual = memory.read(addr);
+        if actual != byte {
+            return Ok(Outcome::Failed(format!(
+                "{}: expected {:02X} at {:04X}, got {:02X}",
+                name, byte, addr, actual
+            )));
+        }
+    }
+    Ok(Outcome::Passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // where cargo test looks for the test programs, which aren't part of the
+    // repository. a test whose files aren't there passes without running
+    fn test_file(name: &str) -> Option<PathBuf> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets").join(name);
+        if path.exists() {
+            Some(path)
+        } else {
+            println!("{} not found, skipping", path.display());
+            None
+        }
+    }
+
+    #[test]
+    fn program_from_bytes() {
+        // LDA #$05, CLC, ADC #$03, STA $40 from address 0
+        let mut memory = FlatMemory::from_bytes(&[0xA9, 0x05, 0x18, 0x69, 0x03, 0x85, 0x40]);
+        let mut cpu = Cpu::new(false, false);
+        for _ in 0..4 {
+            cpu.execute_instruction(&mut memory);
+        }
+        assert_eq!(memory.read(0x40), 0x08);
+        assert_eq!(cpu.a(), 0x08);
+        assert_eq!(cpu.pc(), 0x0007);
+        assert_eq!(cpu.cycle, 9);
+    }
+
+    #[test]
+    fn functional_test() {
+        if let Some(path) = test_file("6502_functional_test.bin") {
+            if let Err(message) = run_functional_test(&path) {
+                panic!("{}", message);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// play with a game controller, through SDL's game controller mappings so
+// any pad it knows about gets the same layout:
+//
+//   left stick or d-pad left/right - rotate
+//   A - fire
+//   B - thrust
+//   left or right shoulder - hyperspace
+//   Start - 1 player start
+//   Back - insert a coin in the left slot
+//
+// controllers can be plugged in and out while running, and the keyboard
+// keeps working alongside them
+
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+
+use input::{apply_control, Control};
+use memory::{CoinSlot, Memory};
+
+// how far the stick has to be pushed (out of 32767) to count as held, well
+// clear of the drift of a worn stick at rest
+const STICK_THRESHOLD: i16 = 12000;
+
+// which way a stick axis is pushed: -1, 0 or 1
+fn axis_direction(value: i16) -> i8 {
+    if value <= -STICK_THRESHOLD {
+        -1
+    } else if value >= STICK_THRESHOLD {
+        1
+    } else {
+        0
+    }
+}
+
+fn button_control(button: Button) -> Option<Control> {
+    match button {
+        Button::A => Some(Control::Fire),
+        Button::B => Some(Control::Thrust),
+        Button::LeftShoulder | Button::RightShoulder => Some(Control::Hyperspace),
+        Button::Start => Some(Control::Start),
+        Button::Back => Some(Control::Coin(CoinSlot::Left)),
+        _ => None,
+    }
+}
+
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    open: Vec<GameController>,
+    stick: i8, // direction of the left stick
+    dpad_left: bool,
+    dpad_right: bool,
+    rotation: i8, // the rotation last written, -1 left and 1 right
+}
+
+impl Controllers {
+    pub fn new(subsystem: GameControllerSubsystem) -> Controllers {
+        Controllers {
+            subsystem,
+            open: Vec::new(),
+            stick: 0,
+            dpad_left: false,
+            dpad_right: false,
+            rotation: 0,
+        }
+    }
+
+    // the stick and d-pad together make one rotation, which is only written
+    // when it changes so the keyboard's rotate keys aren't overridden
+    fn update_rotation(&mut self, memory: &mut Memory) {
+        let rotation = if self.dpad_left && !self.dpad_right {
+            -1
+        } else if self.dpad_right && !self.dpad_left {
+            1
+        } else {
+            self.stick
+        };
+        if rotation == self.rotation {
+            return;
+        }
+        if self.rotation != 0 {
+            let held = if self.rotation < 0 {
+                Control::RotateLeft
+            } else {
+                Control::RotateRight
+            };
+            apply_control(held, false, memory);
+        }
+        if rotation < 0 {
+            apply_control(Control::RotateLeft, true, memory);
+        } else if rotation > 0 {
+            apply_control(Control::RotateRight, true, memory);
+        }
+        self.rotation = rotation;
+    }
+
+    fn set_dpad(&mut self, button: Button, pressed: bool, memory: &mut Memory) -> bool {
+        match button {
+            Button::DPadLeft => self.dpad_left = pressed,
+            Button::DPadRight => self.dpad_right = pressed,
+            _ => return false,
+        }
+        self.update_rotation(memory);
+        true
+    }
+
+    // events that aren't to do with controllers are ignored
+    pub fn handle_event(&mut self, event: &Event, memory: &mut Memory) {
+        match *event {
+            // also sent at start up for controllers already plugged in
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(controller) => {
+                    println!("Controller connected: {}", controller.name());
+                    self.open.push(controller);
+                }
+                Err(e) => println!("Error opening controller {}: {}", which, e),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.open.retain(|controller| controller.instance_id() != which);
+                println!("Controller disconnected");
+                // let go of anything it was holding
+                self.stick = 0;
+                self.dpad_left = false;
+                self.dpad_right = false;
+                self.update_rotation(memory);
+            }
+            Event::ControllerAxisMotion {
+                axis: Axis::LeftX,
+                value,
+                ..
+            } => {
+                self.stick = axis_direction(value);
+                self.update_rotation(memory);
+            }
+            Event::ControllerButtonDown { button, .. } => {
+                if !self.set_dpad(button, true, memory) {
+                    if let Some(control) = button_control(button) {
+                        apply_control(control, true, memory);
+                    }
+                }
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                if !self.set_dpad(button, false, memory) {
+                    if let Some(control) = button_control(button) {
+                        apply_control(control, false, memory);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// which bytes of the game ROM have been executed or read as data
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+const ROM_START: u16 = 0x6800;
+const ROM_SIZE: usize = 6144;
+
+pub struct Coverage {
+    code: [u8; ROM_SIZE / 8],
+    data: [u8; ROM_SIZE / 8],
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            code: [0; ROM_SIZE / 8],
+            data: [0; ROM_SIZE / 8],
+        }
+    }
+
+    // the ROM is also mirrored at E800-FFFF, where the vectors live
+    fn rom_offset(addr: u16) -> Option<usize> {
+        let addr = addr & 0x7FFF;
+        if addr >= ROM_START {
+            Some((addr - ROM_START) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn mark(bits: &mut [u8], addr: u16) {
+        if let Some(offset) = Coverage::rom_offset(addr) {
+            bits[offset / 8] |= 1 << (offset % 8);
+        }
+    }
+
+    fn is_set(bits: &[u8], offset: usize) -> bool {
+        bits[offset / 8] & (1 << (offset % 8)) != 0
+    }
+
+    // op code and operand bytes of an executed instruction
+    pub fn mark_code(&mut self, addr: u16, length: u16) {
+        for i in 0..length {
+            Coverage::mark(&mut self.code, addr.wrapping_add(i));
+        }
+    }
+
+    pub fn mark_data(&mut self, addr: u16) {
+        Coverage::mark(&mut self.data, addr);
+    }
+
+    // one line per run of bytes with the same kind of coverage, e.g.
+    // "6800-6812 code", followed by the totals
+    pub fn write_summary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let kind = |offset: usize| match (
+            Coverage::is_set(&self.code, offset),
+            Coverage::is_set(&self.data, offset),
+        ) {
+            (true, true) => Some("code+data"),
+            (true, false) => Some("code"),
+            (false, true) => Some("data"),
+            (false, false) => None,
+        };
+        let mut code_bytes = 0;
+        let mut data_bytes = 0;
+        let mut start = 0;
+        for offset in 0..ROM_SIZE {
+            if Coverage::is_set(&self.code, offset) {
+                code_bytes += 1;
+            }
+            if Coverage::is_set(&self.data, offset) {
+                data_bytes += 1;
+            }
+            if offset + 1 == ROM_SIZE || kind(offset + 1) != kind(start) {
+                if let Some(name) = kind(start) {
+                    writeln!(
+                        out,
+                        "{:04X}-{:04X} {}",
+                        ROM_START as usize + start,
+                        ROM_START as usize + offset,
+                        name
+                    )?;
+                }
+                start = offset + 1;
+            }
+        }
+        writeln!(
+            out,
+            "{} of {} bytes executed, {} read as data",
+            code_bytes, ROM_SIZE, data_bytes
+        )
+    }
+
+    pub fn dump(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        self.write_summary(&mut file)
+    }
+}
@@ -1,9 +1,31 @@
 // emulate MOS 6502
 
-use memory::Memory;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::io::Write;
 
-#[derive(Debug)]
-enum Instruction {
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use coverage::Coverage;
+use profile::Profile;
+
+const FLAG_BREAK: u8 = 0b10000;
+const FLAG_UNUSED: u8 = 0b100000;
+
+// whatever the processor is wired to. reads take &self, so anything with
+// side effects on read has to use interior mutability
+pub trait Bus {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, byte: u8);
+    // told the cycle count before each instruction, for anything that
+    // depends on the time
+    fn set_cycle(&mut self, _cycle: u64) {}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instruction {
     ADC,
     AND,
     ASL,
@@ -60,11 +82,20 @@ enum Instruction {
     TXA,
     TXS,
     TYA,
+    // undocumented, but stable, combinations of two instructions
+    LAX,
+    SAX,
+    DCP,
+    ISC,
+    SLO,
+    RLA,
+    SRE,
+    RRA,
     INVALID,
 }
 
-#[derive(Copy, Clone)]
-enum AddressingMode {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AddressingMode {
     Immediate,
     ZeroPage,
     ZeroPageOffsetX,
@@ -81,7 +112,26 @@ enum AddressingMode {
 }
 
 impl AddressingMode {
-    fn operand_string(&self, operand: Option<u16>) -> String {
+    /// Number of operand bytes following the op code.
+    pub fn operand_length(&self) -> u16 {
+        match *self {
+            AddressingMode::Immediate |
+            AddressingMode::ZeroPage |
+            AddressingMode::ZeroPageOffsetX |
+            AddressingMode::ZeroPageOffsetY |
+            AddressingMode::OffsetXIndirect |
+            AddressingMode::IndirectOffsetY => 1,
+            AddressingMode::Absolute |
+            AddressingMode::AbsoluteOffsetX |
+            AddressingMode::AbsoluteOffsetY |
+            AddressingMode::IndirectLocation |
+            AddressingMode::AbsoluteLocation => 2,
+            AddressingMode::Accumulator |
+            AddressingMode::NA => 0,
+        }
+    }
+
+    pub fn operand_string(&self, operand: Option<u16>) -> String {
         let op:u16 = match operand {
             None => 0,
             Some(n) => n,
@@ -104,11 +154,107 @@ impl AddressingMode {
     }
 }
 
-struct DecodedInstruction {
-    address: u16,
-    instruction: Instruction,
-    addressing_mode: AddressingMode,
-    operand: Option<u16>,
+/// A single instruction as it sits in memory, decoded but not executed.
+#[derive(Debug, Copy, Clone)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    pub instruction: Instruction,
+    pub addressing_mode: AddressingMode,
+    pub operand: Option<u16>,
+}
+
+impl DecodedInstruction {
+    /// Length of the instruction in bytes, including the op code.
+    pub fn length(&self) -> u16 {
+        1 + self.addressing_mode.operand_length()
+    }
+
+    fn is_branch(&self) -> bool {
+        match self.instruction {
+            Instruction::BCC | Instruction::BCS | Instruction::BEQ |
+            Instruction::BMI | Instruction::BNE | Instruction::BPL |
+            Instruction::BVC | Instruction::BVS => true,
+            _ => false,
+        }
+    }
+}
+
+// mnemonic and operand, e.g. "LDA #$10", with branches showing where they go
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = match self.operand {
+            Some(offset) if self.is_branch() => {
+                let next = self.address.wrapping_add(self.length());
+                format!("${:04X}", next.wrapping_add(offset as u8 as i8 as u16))
+            }
+            _ => self.addressing_mode.operand_string(self.operand),
+        };
+        let mnemonic = match self.instruction {
+            Instruction::INVALID => "???".to_string(),
+            instruction => format!("{:?}", instruction),
+        };
+        if operand.is_empty() {
+            write!(f, "{}", mnemonic)
+        } else {
+            write!(f, "{} {}", mnemonic, operand)
+        }
+    }
+}
+
+/// The instruction at `addr` as text, along with its length in bytes, without
+/// running it.
+pub fn disassemble<B: Bus>(memory: &B, addr: u16) -> (String, u16) {
+    let decoded_instruction = Cpu::decode_at(addr, memory);
+    (decoded_instruction.to_string(), decoded_instruction.length())
+}
+
+// a copy of the programmer visible registers
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+}
+
+// the flags in P. B and bit 5 aren't there, they only exist when P is
+// pushed to the stack
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Flag {
+    Carry,
+    Zero,
+    IrqDisable,
+    Decimal,
+    Overflow,
+    Negative,
+}
+
+impl Flag {
+    fn mask(self) -> u8 {
+        match self {
+            Flag::Carry => 0b1,
+            Flag::Zero => 0b10,
+            Flag::IrqDisable => 0b100,
+            Flag::Decimal => 0b1000,
+            Flag::Overflow => 0b1000000,
+            Flag::Negative => 0b10000000,
+        }
+    }
+}
+
+// everything needed to put the processor back as it was, for save states
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub pc: u16,
+    pub s: u8,
+    pub p: u8,
+    pub cycle: u64,
 }
 
 pub struct Cpu {
@@ -121,20 +267,105 @@ pub struct Cpu {
     p: u8,
     pub cycle: u64,
     debug_mode: bool,
+    illegal_as_nop: bool,   // rather than hanging on undocumented op codes
+    dummy_writes: bool,     // of read-modify-write instructions
+    coverage: Option<Coverage>,
+    profile: Option<Profile>,
+    trace: VecDeque<u16>, // recently executed addresses, kept in debug mode
+    breakpoints: HashSet<u16>,
+    trace_log: Option<Box<dyn Write>>,
 }
 
+const TRACE_LENGTH: usize = 32;
+
 impl Cpu {
-    pub fn new(debug_mode: bool) -> Cpu {
-        Cpu {a: 0, x: 0, y: 0, pc: 0, previous_pc: 0, s: 0, p: 0, cycle: 0, debug_mode}
+    // illegal_as_nop skips over undocumented op codes as if they were NOPs
+    // of the same length (see decode_undocumented). otherwise they panic
+    pub fn new(debug_mode: bool, illegal_as_nop: bool) -> Cpu {
+        Cpu {a: 0, x: 0, y: 0, pc: 0, previous_pc: 0, s: 0, p: FLAG_UNUSED, cycle: 0, debug_mode,
+              illegal_as_nop, dummy_writes: false,
+              coverage: None, profile: None, trace: VecDeque::new(),
+              breakpoints: HashSet::new(), trace_log: None}
     }
 
-    fn get_word(addr: u16, memory: &Memory) -> u16 {
-        (memory.get_byte(addr)) as u16 |
-        ((memory.get_byte(addr + 1) as u16) << 8)
+    pub fn set_dummy_writes(&mut self, dummy_writes: bool) {
+        self.dummy_writes = dummy_writes;
     }
 
-    fn page_cross_penalty(addr: u16, offset: u8) -> u64 {
-        if addr & 0xFF00 == (addr + offset as u16) & 0xFF00 {
+    pub fn registers(&self) -> Registers {
+        Registers {a: self.a, x: self.x, y: self.y, s: self.s, p: self.p, pc: self.pc}
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn s(&self) -> u8 {
+        self.s
+    }
+
+    pub fn p(&self) -> u8 {
+        self.p
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.flag_set(flag.mask())
+    }
+
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {a: self.a, x: self.x, y: self.y, pc: self.pc, s: self.s, p: self.p,
+                  cycle: self.cycle}
+    }
+
+    // breakpoints, coverage and the debug trace are left alone
+    pub fn restore(&mut self, state: CpuState) {
+        self.a = state.a;
+        self.x = state.x;
+        self.y = state.y;
+        self.pc = state.pc;
+        self.previous_pc = state.pc;
+        self.s = state.s;
+        self.p = state.p;
+        self.cycle = state.cycle;
+    }
+
+    // the high byte of a word at $FFFF comes from $0000
+    fn get_word<B: Bus>(addr: u16, memory: &B) -> u16 {
+        (memory.read(addr)) as u16 |
+        ((memory.read(addr.wrapping_add(1)) as u16) << 8)
+    }
+
+    // a pointer in the zero page never leaves it, so one at $FF takes its
+    // high byte from $00
+    fn get_zero_page_word<B: Bus>(addr: u8, memory: &B) -> u16 {
+        (memory.read(addr as u16)) as u16 |
+        ((memory.read(addr.wrapping_add(1) as u16) as u16) << 8)
+    }
+
+    // the NMOS JMP ($xxFF) bug: the high byte of the target comes from $xx00,
+    // as the pointer's low byte wraps without carrying into its high byte
+    fn get_indirect_jump_word<B: Bus>(addr: u16, memory: &B) -> u16 {
+        let high = (addr & 0xFF00) | (addr.wrapping_add(1) & 0x00FF);
+        (memory.read(addr)) as u16 |
+        ((memory.read(high) as u16) << 8)
+    }
+
+    // the extra cycle an indexed read takes when adding the index to the
+    // un-indexed base address carries into the high byte
+    fn page_cross_penalty(base: u16, index: u8) -> u64 {
+        if base & 0xFF00 == base.wrapping_add(index as u16) & 0xFF00 {
             0
         }
         else {
@@ -142,56 +373,211 @@ impl Cpu {
         }
     }
 
-    pub fn reset(&mut self, memory: &Memory) {
+    // address of the instruction being (or last) executed
+    pub fn instruction_address(&self) -> u16 {
+        self.previous_pc
+    }
+
+    // the addresses of the last few instructions, oldest first. only
+    // recorded in debug mode
+    pub fn recent_addresses(&self) -> Vec<u16> {
+        self.trace.iter().cloned().collect()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // whether the next instruction is at a breakpoint. checked by the caller
+    // between instructions, keeping execute_instruction itself as it was
+    pub fn at_breakpoint(&self) -> bool {
+        !self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc)
+    }
+
+    // run until the next instruction is at a breakpoint, returning its
+    // address. at least one instruction is run, so that starting from a
+    // breakpoint carries on to the next one. with no breakpoints it never
+    // returns. the main loop can't use it, as it has the display and NMI to
+    // see to after every instruction, but code run on FlatMemory can
+    #[allow(dead_code)]
+    pub fn run_until_breakpoint<B: Bus>(&mut self, memory: &mut B) -> u16 {
+        loop {
+            self.execute_instruction(memory);
+            if self.at_breakpoint() {
+                return self.pc;
+            }
+        }
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn dump_coverage(&self, path: &str) -> io::Result<()> {
+        match self.coverage {
+            Some(ref coverage) => coverage.dump(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn enable_profile(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    // executions by op code and by address, empty if profiling isn't on
+    pub fn profile_report(&self) -> String {
+        match self.profile {
+            Some(ref profile) => profile.report(),
+            None => String::new(),
+        }
+    }
+
+    // one line per instruction, before it's executed, in the style of the
+    // nestest log so that runs can be diffed:
+    //
+    // 7CF3  A2 FE     LDX #$FE                        A:00 X:00 Y:00 P:24 SP:FF CYC:6
+    pub fn set_trace_log(&mut self, log: Box<dyn Write>) {
+        self.trace_log = Some(log);
+    }
+
+    fn write_trace_line<B: Bus>(&mut self, decoded_instruction: &DecodedInstruction,
+                                memory: &B) {
+        let bytes: Vec<String> = (0..decoded_instruction.length())
+            .map(|i| format!("{:02X}", memory.read(decoded_instruction.address.wrapping_add(i))))
+            .collect();
+        let line = format!("{:04X}  {:<8}  {:<30}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                           decoded_instruction.address, bytes.join(" "),
+                           decoded_instruction.to_string(),
+                           self.a, self.x, self.y, self.p, self.s, self.cycle);
+        let failed = match self.trace_log {
+            Some(ref mut log) => writeln!(log, "{}", line).is_err(),
+            None => false,
+        };
+        if failed {
+            println!("Error writing the trace log, so it's been stopped");
+            self.trace_log = None;
+        }
+    }
+
+    pub fn reset<B: Bus>(&mut self, memory: &B) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
-        self.p = 0b100;     // start with IRQ disable
+        self.p = 0b100 | FLAG_UNUSED;     // start with IRQ disable
         self.s = 0xFD;
         self.pc = Cpu::get_word(0xFFFC, memory);
-        self.cycle = 6;
+        // the cycle count carries on across resets so that anything timed
+        // from it (like the NMI) keeps going
+        self.cycle += 6;
     }
 
-    fn load_byte_from_pc(&mut self, memory: &Memory) -> u8 {
-        let byte = memory.get_byte(self.pc);
-        self.pc += 1;
+    fn next_byte<B: Bus>(pc: &mut u16, memory: &B) -> u8 {
+        let byte = memory.read(*pc);
+        *pc = pc.wrapping_add(1);
         byte
     }
 
-    fn load_word_from_pc(&mut self, memory: &Memory) -> u16 {
-        self.load_byte_from_pc(memory) as u16 | 
-        ((self.load_byte_from_pc(memory) as u16) << 8)
+    fn next_word<B: Bus>(pc: &mut u16, memory: &B) -> u16 {
+        Cpu::next_byte(pc, memory) as u16 |
+        ((Cpu::next_byte(pc, memory) as u16) << 8)
     }
 
-    fn fetch_operand(&mut self, memory: &mut Memory,
+    fn fetch_operand<B: Bus>(pc: &mut u16, memory: &B,
                      addressing_mode: AddressingMode) -> Option<u16> {
-        match addressing_mode {
-            AddressingMode::Immediate |
-            AddressingMode::ZeroPage |
-            AddressingMode::ZeroPageOffsetX |
-            AddressingMode::ZeroPageOffsetY |
-            AddressingMode::OffsetXIndirect |
-            AddressingMode::IndirectOffsetY =>
-                Some(self.load_byte_from_pc(memory) as u16),
-            AddressingMode::Absolute |
-            AddressingMode::AbsoluteOffsetX |
-            AddressingMode::AbsoluteOffsetY |
-            AddressingMode::IndirectLocation |
-            AddressingMode::AbsoluteLocation =>
-                Some(self.load_word_from_pc(memory)),
+        match addressing_mode.operand_length() {
+            1 => Some(Cpu::next_byte(pc, memory) as u16),
+            2 => Some(Cpu::next_word(pc, memory)),
             _ => None
         }
     }
 
-    fn fetch_instruction(&mut self, memory: &mut Memory) -> DecodedInstruction {
-        let address = self.pc;
-        let op_code = self.load_byte_from_pc(memory);
-        let bad_opcode = || {
-            panic!("Invalid op code {:02X} encountered at address {:04X}. Processor hung.",
-                   op_code, address)
-        };
-        // for now, any undocumented instructions will hang the processor
+    fn fetch_instruction<B: Bus>(&mut self, memory: &B) -> DecodedInstruction {
+        let decoded_instruction = Cpu::decode_at(self.pc, memory);
+        self.pc = self.pc.wrapping_add(decoded_instruction.length());
+        decoded_instruction
+    }
+
+    /// Decode the instruction at `address` without touching the processor
+    /// state, e.g. for disassembly or tracing. Undocumented op codes decode
+    /// as `Instruction::INVALID`.
+    pub fn decode_at<B: Bus>(address: u16, memory: &B) -> DecodedInstruction {
+        Cpu::decode_documented(address, memory)
+            .unwrap_or_else(|| Cpu::decode_undocumented(address, memory))
+    }
+
+    // the stable undocumented instructions, where the bottom two bits of the
+    // op code are set, which behave like the two documented instructions
+    // they combine. the rest of those (the immediate xB column and the
+    // unstable 93 9B 9F BB) are INVALID
+    fn combined_instruction(op_code: u8) -> Instruction {
         match op_code {
+            op if op & 0x1F == 0x0B => Instruction::INVALID,
+            0x93 | 0x9B | 0x9F | 0xBB => Instruction::INVALID,
+            op => match op >> 5 {
+                0b000 => Instruction::SLO,
+                0b001 => Instruction::RLA,
+                0b010 => Instruction::SRE,
+                0b011 => Instruction::RRA,
+                0b100 => Instruction::SAX,
+                0b101 => Instruction::LAX,
+                0b110 => Instruction::DCP,
+                _ => Instruction::ISC,
+            },
+        }
+    }
+
+    // undocumented op codes are given the addressing mode of their
+    // column in the op code matrix, so they're the right number of bytes long:
+    //   1A 3A 5A 7A DA FA           - single byte NOPs
+    //   80 82 89 C2 E2              - NOP #imm
+    //   04 44 64, 14 34 54 74 D4 F4 - NOP zp and zp,X
+    //   0C, 1C 3C 5C 7C DC FC       - NOP abs and abs,X
+    // the rest of the x2 column (KIL, which jams a real 6502) is taken as a
+    // single byte and the others use their neighbours' modes
+    fn decode_undocumented<B: Bus>(address: u16, memory: &B) -> DecodedInstruction {
+        let mut pc = address;
+        let op_code = Cpu::next_byte(&mut pc, memory);
+        let instruction = if op_code & 0b11 == 0b11 {
+            Cpu::combined_instruction(op_code)
+        } else {
+            Instruction::INVALID
+        };
+        let addressing_mode = match op_code & 0x1F {
+            // as with LDX and STX, X is the register being loaded or stored
+            0x17 if instruction == Instruction::LAX || instruction == Instruction::SAX =>
+                AddressingMode::ZeroPageOffsetY,
+            0x1F if instruction == Instruction::LAX => AddressingMode::AbsoluteOffsetY,
+            0x00 | 0x09 | 0x0B => AddressingMode::Immediate,
+            0x02 if op_code >= 0x80 => AddressingMode::Immediate,
+            0x01 | 0x03 => AddressingMode::OffsetXIndirect,
+            0x04..=0x07 => AddressingMode::ZeroPage,
+            0x0C..=0x0F => AddressingMode::Absolute,
+            0x10 => AddressingMode::Immediate,
+            0x11 | 0x13 => AddressingMode::IndirectOffsetY,
+            0x14..=0x17 => AddressingMode::ZeroPageOffsetX,
+            0x19 | 0x1B => AddressingMode::AbsoluteOffsetY,
+            0x1C..=0x1F => AddressingMode::AbsoluteOffsetX,
+            _ => AddressingMode::NA,
+        };
+        DecodedInstruction {
+            address,
+            instruction,
+            addressing_mode,
+            operand: Cpu::fetch_operand(&mut pc, memory, addressing_mode)}
+    }
+
+    fn decode_documented<B: Bus>(address: u16, memory: &B) -> Option<DecodedInstruction> {
+        let mut pc = address;
+        let op_code = Cpu::next_byte(&mut pc, memory);
+        Some(match op_code {
             0x00 => DecodedInstruction {
                 address,
                 instruction: Instruction::BRK,
@@ -201,7 +587,7 @@ impl Cpu {
                 address,
                 instruction: Instruction::JSR,
                 addressing_mode: AddressingMode::AbsoluteLocation,
-                operand: Some(self.load_word_from_pc(memory))},
+                operand: Some(Cpu::next_word(&mut pc, memory))},
             0x40 => DecodedInstruction {
                 address,
                 instruction: Instruction::RTI,
@@ -338,7 +724,7 @@ impl Cpu {
                     address,
                     instruction,
                     addressing_mode: AddressingMode::Immediate, // we cheat and use immediate for relative
-                    operand: self.fetch_operand(memory, AddressingMode::Immediate)}
+                    operand: Cpu::fetch_operand(&mut pc, memory, AddressingMode::Immediate)}
             }
             op if op & 0b11 == 0b01 => {
                 let addressing_mode = match (op & 0b11100) >> 2 {
@@ -366,14 +752,14 @@ impl Cpu {
                 // the one bad combination here is STA in immediate mode
                 if let Instruction::STA = instruction {
                     if let AddressingMode::Immediate = addressing_mode {
-                        bad_opcode();
+                        return None;
                     }
                 }
                 DecodedInstruction {
                     address,
                     instruction,
                     addressing_mode,
-                    operand: self.fetch_operand(memory, addressing_mode)}
+                    operand: Cpu::fetch_operand(&mut pc, memory, addressing_mode)}
             },
             op if op & 0b11 == 0b10 => {
                 let instruction = match (op & 0b11100000) >> 5 {
@@ -431,13 +817,13 @@ impl Cpu {
                     _ => false,
                 };
                 if bad_combination {
-                    bad_opcode();
+                    return None;
                 }
                 DecodedInstruction {
                     address,
                     instruction,
                     addressing_mode,
-                    operand: self.fetch_operand(memory, addressing_mode)}
+                    operand: Cpu::fetch_operand(&mut pc, memory, addressing_mode)}
             },
             op if op & 0b11 == 0b00 => {
                 let addressing_mode = match (op & 0b11100) >> 2 {
@@ -468,7 +854,7 @@ impl Cpu {
                     _ => Instruction::INVALID,
                 };
                 if let Instruction::INVALID = instruction {
-                    bad_opcode();
+                    return None;
                 }
                 // weed out instructions with incompatible addressing modes
                 let bad_combination = match addressing_mode {
@@ -503,16 +889,16 @@ impl Cpu {
                     _ => false,
                 };
                 if bad_combination {
-                    bad_opcode();
+                    return None;
                 }
                 DecodedInstruction {
                     address,
                     instruction,
                     addressing_mode,
-                    operand: self.fetch_operand(memory, addressing_mode)}
+                    operand: Cpu::fetch_operand(&mut pc, memory, addressing_mode)}
             },
-            _ => {bad_opcode()},
-        }
+            _ => return None,
+        })
     }
 
     fn show_processor_state(&self) {
@@ -521,10 +907,7 @@ impl Cpu {
     }
 
     fn show_instruction(decoded_instruction: &DecodedInstruction) {
-        print!("{:04X} {:?} {}",
-               decoded_instruction.address,
-               decoded_instruction.instruction,
-               decoded_instruction.addressing_mode.operand_string(decoded_instruction.operand));
+        print!("{:04X} {}", decoded_instruction.address, decoded_instruction);
     }
 
     fn flag_set(&self, mask: u8) -> bool {
@@ -532,23 +915,42 @@ impl Cpu {
     }
 
     fn carry_set(&self) -> bool {
-        self.flag_set(0b1)
+        self.flag(Flag::Carry)
     }
 
     fn zero_set(&self) -> bool {
-        self.flag_set(0b10)
+        self.flag(Flag::Zero)
+    }
+
+    fn irq_disable_set(&self) -> bool {
+        self.flag(Flag::IrqDisable)
     }
 
     fn decimal_set(&self) -> bool {
-        self.flag_set(0b1000)
+        self.flag(Flag::Decimal)
     }
 
     fn overflow_set(&self) -> bool {
-        self.flag_set(0b1000000)
+        self.flag(Flag::Overflow)
     }
 
     fn negative_set(&self) -> bool {
-        self.flag_set(0b10000000)
+        self.flag(Flag::Negative)
+    }
+
+    // P as pushed to the stack. B only exists there, set by BRK and PHP and
+    // clear for an interrupt, and the unused bit 5 always reads as 1
+    fn pushed_flags(&self, brk: bool) -> u8 {
+        if brk {
+            self.p | FLAG_BREAK | FLAG_UNUSED
+        } else {
+            (self.p & !FLAG_BREAK) | FLAG_UNUSED
+        }
+    }
+
+    // PLP and RTI can't set B, or clear bit 5, in P itself
+    fn pull_flags(&mut self, byte: u8) {
+        self.p = (byte & !FLAG_BREAK) | FLAG_UNUSED;
     }
 
     fn update_flag(&mut self, set: bool, mask: u8) {
@@ -576,10 +978,6 @@ impl Cpu {
         self.update_flag(set, 0b1000);
     }
 
-    fn update_brk_command(&mut self, set: bool) {
-        self.update_flag(set, 0b10000);
-    }
-
     fn update_overflow(&mut self, set: bool) {
         self.update_flag(set, 0b1000000);
     }
@@ -588,85 +986,106 @@ impl Cpu {
         self.update_flag(byte & 0x80 == 0x80, 0b10000000);
     }
 
-    fn realise_operand(&self, decoded_instruction: &DecodedInstruction, memory: &Memory) -> u16 {
+    // the memory location an instruction reads or writes, if any
+    fn effective_address<B: Bus>(&self, decoded_instruction: &DecodedInstruction,
+                         memory: &B) -> Option<u16> {
+        let op = if let Some(val) = decoded_instruction.operand {val} else {0};
+        match decoded_instruction.addressing_mode {
+            AddressingMode::ZeroPage => Some(op),
+            AddressingMode::ZeroPageOffsetX => Some((op + self.x as u16) & 0xFF),
+            AddressingMode::ZeroPageOffsetY => Some((op + self.y as u16) & 0xFF),
+            AddressingMode::Absolute => Some(op),
+            // indexing past $FFFF wraps round to the zero page
+            AddressingMode::AbsoluteOffsetX => Some(op.wrapping_add(self.x as u16)),
+            AddressingMode::AbsoluteOffsetY => Some(op.wrapping_add(self.y as u16)),
+            AddressingMode::OffsetXIndirect =>
+                Some(Cpu::get_zero_page_word((op as u8).wrapping_add(self.x), memory)),
+            AddressingMode::IndirectOffsetY =>
+                Some(Cpu::get_zero_page_word(op as u8, memory).wrapping_add(self.y as u16)),
+            _ => None,
+        }
+    }
+
+    fn record_coverage<B: Bus>(&mut self, decoded_instruction: &DecodedInstruction,
+                       memory: &B) {
+        // JMP ($xxxx) reads its two byte target from the operand address
+        let (data_address, data_length) = match decoded_instruction.addressing_mode {
+            AddressingMode::IndirectLocation => (decoded_instruction.operand, 2),
+            _ => (self.effective_address(decoded_instruction, memory), 1),
+        };
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.mark_code(decoded_instruction.address, decoded_instruction.length());
+            if let Some(addr) = data_address {
+                for i in 0..data_length {
+                    coverage.mark_data(addr.wrapping_add(i));
+                }
+            }
+        }
+    }
+
+    fn realise_operand<B: Bus>(&self, decoded_instruction: &DecodedInstruction, memory: &B) -> u16 {
         let op = if let Some(val) = decoded_instruction.operand {val} else {0};
         match decoded_instruction.addressing_mode {
             AddressingMode::Immediate => op,
-            AddressingMode::ZeroPage => memory.get_byte(op) as u16,
-            AddressingMode::ZeroPageOffsetX =>
-                memory.get_byte((op + self.x as u16) & 0xFF) as u16,
-            AddressingMode::ZeroPageOffsetY =>
-                memory.get_byte((op + self.y as u16) & 0xFF) as u16,
-            AddressingMode::Absolute => memory.get_byte(op) as u16,
-            AddressingMode::AbsoluteOffsetX =>
-                memory.get_byte(op + self.x as u16) as u16,
-            AddressingMode::AbsoluteOffsetY =>
-                memory.get_byte(op + self.y as u16) as u16,
             AddressingMode::Accumulator => self.a as u16,
-            AddressingMode::OffsetXIndirect => {
-                let addr = Cpu::get_word((op + self.x as u16) & 0xFF, memory);
-                memory.get_byte(addr) as u16
-            },
-            AddressingMode::IndirectOffsetY => {
-                let addr = Cpu::get_word(op, memory) + self.y as u16;
-                memory.get_byte(addr) as u16
-            },
-            AddressingMode::IndirectLocation => Cpu::get_word(op, memory),
+            AddressingMode::IndirectLocation => Cpu::get_indirect_jump_word(op, memory),
             AddressingMode::AbsoluteLocation => op,
-            _ => 0,
+            _ => match self.effective_address(decoded_instruction, memory) {
+                Some(addr) => memory.read(addr) as u16,
+                None => 0,
+            },
         }
     }
 
-    fn store_to_operand(&mut self, store: u8,
+    fn store_to_operand<B: Bus>(&mut self, store: u8,
                         decoded_instruction: &DecodedInstruction,
-                        memory: &mut Memory) {
-        let op = if let Some(val) = decoded_instruction.operand {val} else {0};
-        match decoded_instruction.addressing_mode {
-            AddressingMode::ZeroPage => {
-                memory.set_byte(op, store);
-            },
-            AddressingMode::ZeroPageOffsetX => {
-                memory.set_byte((op + self.x as u16) & 0xFF, store);
-            },
-            AddressingMode::ZeroPageOffsetY => {
-                memory.set_byte( (op + self.y as u16) & 0xFF, store);
-            },
-            AddressingMode::Absolute => {
-                memory.set_byte(op, store);
-            },
-            AddressingMode::AbsoluteOffsetX => {
-                memory.set_byte(op + self.x as u16, store);
-            },
-            AddressingMode::AbsoluteOffsetY => {
-                memory.set_byte(op + self.y as u16, store);
-            },
-            AddressingMode::Accumulator => {
-                self.a = store;
-            },
-            AddressingMode::OffsetXIndirect => {
-                let addr = Cpu::get_word((op + self.x as u16) & 0xFF, memory);
-                memory.set_byte(addr, store);
-            },
-            AddressingMode::IndirectOffsetY => {
-                let addr = Cpu::get_word(op, memory) + self.y as u16;
-                memory.set_byte(addr, store);
-            },
-            _ => {},
+                        memory: &mut B) {
+        if let AddressingMode::Accumulator = decoded_instruction.addressing_mode {
+            self.a = store;
+        } else if let Some(addr) = self.effective_address(decoded_instruction, memory) {
+            memory.write(addr, store);
         }
+    }
 
+    // the final write of a read-modify-write instruction. the 6502 first
+    // writes the unmodified value back, which only matters to hardware that
+    // notices writes, so it's left out unless dummy_writes is set
+    fn write_modified<B: Bus>(&mut self, original: u8, result: u8,
+                              decoded_instruction: &DecodedInstruction,
+                              memory: &mut B) {
+        if self.dummy_writes &&
+           decoded_instruction.addressing_mode != AddressingMode::Accumulator {
+            self.store_to_operand(original, decoded_instruction, memory);
+        }
+        self.store_to_operand(result, decoded_instruction, memory);
     }
 
     fn branch(&mut self, op: u16) {
         if op & 0x80 == 0x80 {
-            self.pc -= 0x100 - op;
+            self.pc = self.pc.wrapping_sub(0x100 - op);
         }
         else {
-            self.pc += op;
+            self.pc = self.pc.wrapping_add(op);
         }
     }
 
-    fn push_byte(&mut self, store: u8, memory: &mut Memory) {
-        memory.set_byte(0x100 + self.s as u16, store);
+    // whether a branch goes, which the flags still say after it has
+    fn branch_taken(&self, instruction: Instruction) -> bool {
+        match instruction {
+            Instruction::BCC => !self.carry_set(),
+            Instruction::BCS => self.carry_set(),
+            Instruction::BEQ => self.zero_set(),
+            Instruction::BMI => self.negative_set(),
+            Instruction::BNE => !self.zero_set(),
+            Instruction::BPL => !self.negative_set(),
+            Instruction::BVC => !self.overflow_set(),
+            Instruction::BVS => self.overflow_set(),
+            _ => false,
+        }
+    }
+
+    fn push_byte<B: Bus>(&mut self, store: u8, memory: &mut B) {
+        memory.write(0x100 + self.s as u16, store);
         if self.s == 0 {
             self.s = 0xFF;
         }
@@ -675,22 +1094,22 @@ impl Cpu {
         }
     }
 
-    fn pop_byte(&mut self, memory: &Memory) -> u8 {
+    fn pop_byte<B: Bus>(&mut self, memory: &B) -> u8 {
         if self.s == 0xFF {
             self.s = 0;
         }
         else {
             self.s += 1;
         }
-        memory.get_byte(0x100 + self.s as u16)
+        memory.read(0x100 + self.s as u16)
     }
 
-    fn push_word(&mut self, store: u16, memory: &mut Memory) {
+    fn push_word<B: Bus>(&mut self, store: u16, memory: &mut B) {
         self.push_byte((store >> 8) as u8, memory);
         self.push_byte((store & 0xFF) as u8, memory);
     }
 
-    fn pop_word(&mut self, memory: &mut Memory) -> u16 {
+    fn pop_word<B: Bus>(&mut self, memory: &mut B) -> u16 {
         self.pop_byte(memory) as u16 | ((self.pop_byte(memory) as u16) << 8)
     }
 
@@ -708,55 +1127,173 @@ impl Cpu {
         result
     }
 
+    // ASL and ROL, returning the result
+    fn shift_left(&mut self, byte: u8, carry_in: bool) -> u8 {
+        let result = byte << 1 | if carry_in {1} else {0};
+        self.update_carry(byte & 0x80 == 0x80);
+        self.update_zero(result == 0);
+        self.update_negative_from_byte(result);
+        result
+    }
+
+    // LSR and ROR, returning the result
+    fn shift_right(&mut self, byte: u8, carry_in: bool) -> u8 {
+        let result = byte >> 1 | if carry_in {0x80} else {0};
+        self.update_carry(byte & 0x1 == 0x1);
+        self.update_zero(result == 0);
+        self.update_negative_from_byte(result);
+        result
+    }
+
+    fn add_with_carry(&mut self, op: u16) {
+        let carry_in = if self.carry_set() {1} else {0};
+        let a = self.a as u16;
+        if self.decimal_set() {
+            // NMOS behaviour as described in
+            // http://www.6502.org/tutorials/decimal_mode.html
+            // which also covers digits outside the decimal range
+            let mut low = (a & 0xF) + (op & 0xF) + carry_in;
+            if low >= 0xA {
+                low = ((low + 0x6) & 0xF) + 0x10;
+            }
+            let mut sum = (a & 0xF0) + (op & 0xF0) + low;
+            // N and V come from the sum before the high digit is
+            // adjusted, with the high digits treated as signed
+            let signed = (a & 0xF0) as u8 as i8 as i16 +
+                (op & 0xF0) as u8 as i8 as i16 + low as i16;
+            self.update_negative_from_byte(sum as u8);
+            self.update_overflow(signed < -128 || signed > 127);
+            if sum >= 0xA0 {
+                sum += 0x60;
+            }
+            self.update_carry(sum >= 0x100);
+            // whereas Z reflects the plain binary sum
+            self.update_zero((a + op + carry_in) & 0xFF == 0);
+            self.a = sum as u8;
+        }
+        else {
+            let result = a + op + carry_in;
+            self.update_carry(result > 0xFF);
+            let sum = result & 0xFF;
+            self.update_negative_from_byte(sum as u8);
+            self.update_zero(sum == 0);
+            // overflow when both inputs have the same sign and the sum the
+            // other one
+            self.update_overflow((a ^ sum) & (op ^ sum) & 0x80 != 0);
+            self.a = sum as u8;
+        }
+    }
+
+    fn subtract_with_carry(&mut self, op: u16) {
+        let borrow = if self.carry_set() {0} else {1};
+        let a = self.a as i16;
+        let op = op as i16 & 0xFF;
+        // on the NMOS 6502 all the flags come from the binary difference,
+        // even in decimal mode, with carry meaning no borrow
+        let difference = a - op - borrow;
+        let sub = difference as u8;
+        self.update_carry(difference >= 0);
+        self.update_negative_from_byte(sub);
+        self.update_zero(sub == 0);
+        // as for ADC, with the operand inverted
+        self.update_overflow((a ^ difference) & (!op ^ difference) & 0x80 != 0);
+        self.a = if self.decimal_set() {
+            // as described in http://www.6502.org/tutorials/decimal_mode.html
+            // each digit that borrowed has 6 taken off it, which also covers
+            // digits outside the decimal range
+            let mut low = (a & 0xF) - (op & 0xF) - borrow;
+            if low < 0 {
+                low = ((low - 0x6) & 0xF) - 0x10;
+            }
+            let mut result = (a & 0xF0) - (op & 0xF0) + low;
+            if result < 0 {
+                result -= 0x60;
+            }
+            result as u8
+        }
+        else {
+            sub
+        };
+    }
+
     fn compare(&mut self, compare_to: u8, byte: u8) {
         self.update_carry(compare_to >= byte);
         self.update_zero(compare_to == byte);
         self.update_negative_from_byte((compare_to as u16 + 0x100 - byte as u16) as u8);
     }
 
-    pub fn initiate_nmi(&mut self, memory: &mut Memory) {
+    pub fn initiate_nmi<B: Bus>(&mut self, memory: &mut B) {
         let pc = self.pc;
-        let p = self.p;
+        let p = self.pushed_flags(false);
         self.push_word(pc, memory);
         self.push_byte(p, memory);
         self.pc = Cpu::get_word(0xFFFA, memory);
         self.cycle += 7;
     }
 
-    pub fn execute_instruction(&mut self, memory: &mut Memory) {
+    // a maskable interrupt, ignored (taking no time) while interrupts are
+    // disabled. returns whether it was taken
+    pub fn initiate_irq<B: Bus>(&mut self, memory: &mut B) -> bool {
+        if self.irq_disable_set() {
+            return false;
+        }
+        let pc = self.pc;
+        let p = self.pushed_flags(false);  // B clear tells the handler it wasn't BRK
+        self.push_word(pc, memory);
+        self.push_byte(p, memory);
+        self.update_irq_disable(true);
+        self.pc = Cpu::get_word(0xFFFE, memory);
+        self.cycle += 7;
+        true
+    }
+
+    // whole instructions until at least budget cycles have gone by, so the
+    // last one can overrun it. returns the cycles actually run
+    pub fn run_for_cycles<B: Bus>(&mut self, memory: &mut B, budget: u64) -> u64 {
+        let start = self.cycle;
+        while self.cycle - start < budget {
+            self.execute_instruction(memory);
+        }
+        self.cycle - start
+    }
+
+    pub fn execute_instruction<B: Bus>(&mut self, memory: &mut B) {
+        memory.set_cycle(self.cycle);
         self.previous_pc = self.pc;
         if self.debug_mode {
             self.show_processor_state();
+            if self.trace.len() == TRACE_LENGTH {
+                self.trace.pop_front();
+            }
+            self.trace.push_back(self.pc);
+        }
+        if self.trace_log.is_some() {
+            let decoded_instruction = Cpu::decode_at(self.pc, memory);
+            self.write_trace_line(&decoded_instruction, memory);
         }
         let decoded_instruction = self.fetch_instruction(memory);
+        if let Instruction::INVALID = decoded_instruction.instruction {
+            if !self.illegal_as_nop {
+                panic!("Invalid op code {:02X} encountered at address {:04X}. Processor hung.",
+                       memory.read(decoded_instruction.address),
+                       decoded_instruction.address);
+            }
+        }
         if self.debug_mode {
             Cpu::show_instruction(&decoded_instruction);
         }
-        let op = self.realise_operand(&decoded_instruction, &memory);
+        if self.coverage.is_some() {
+            self.record_coverage(&decoded_instruction, memory);
+        }
+        if let Some(ref mut profile) = self.profile {
+            profile.record(decoded_instruction.address,
+                           memory.read(decoded_instruction.address),
+                           decoded_instruction.instruction);
+        }
+        let op = self.realise_operand(&decoded_instruction, memory);
         match decoded_instruction.instruction {
             Instruction::ADC => {
-                let sum = if self.decimal_set() {
-                    // not sure what should happen when digits are outside decimal range
-                    let rhs = (self.a & 0xF) as u16 + (op & 0xF) +
-                        if self.carry_set() {1} else {0};
-                    let carry = if rhs > 9 {1} else {0};
-                    let lhs = ((self.a & 0xF0) >> 4) as u16 +((op & 0xF0) >> 4) + carry;
-                    self.update_carry(lhs > 9);
-                    ((lhs % 10) << 4) | (rhs % 10)
-                }
-                else {
-                    let result = self.a as u16 + op +
-                        if self.carry_set() {1} else {0};
-                    self.update_carry(result > 0xFF);
-                    result & 0xFF
-                };
-                // same rules seem to apply for negative and overflow for bcd
-                self.update_negative_from_byte(sum as u8);
-                self.update_zero(sum == 0);
-                let overflow = (self.a < 128 && sum > 127) ||
-                    (self.a > 127 && sum < 128);
-                self.update_overflow(overflow);
-                self.a = sum as u8;
+                self.add_with_carry(op);
             }
             Instruction::AND => {
                 let result = self.a & op as u8;
@@ -764,13 +1301,12 @@ impl Cpu {
                 self.update_negative_from_byte(result);
                 self.a = result;
             },
+            // like the other shifts and rotates, in accumulator mode op is A
+            // and the result goes back to A, so memory is never touched and
+            // carry comes from bit 7 (bit 0 for LSR and ROR) of A
             Instruction::ASL => {
-                let result = op << 1;
-                self.update_carry(result & 0x100 == 0x100);
-                self.update_negative_from_byte(result as u8);
-                self.update_zero(result & 0xFF == 0);
-                self.store_to_operand((result & 0xFF) as u8,
-                    &decoded_instruction, memory);
+                let result = self.shift_left(op as u8, false);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::BCC => {
                 if ! self.carry_set() {
@@ -810,11 +1346,11 @@ impl Cpu {
             },
             Instruction::BRK => {
                 let ret_addr = self.pc;
-                let flags = self.p;
+                let flags = self.pushed_flags(true);
                 self.push_word(ret_addr, memory);
                 self.push_byte(flags, memory);
                 self.pc = Cpu::get_word(0xFFFE, memory);
-                self.update_brk_command(true);
+                self.update_irq_disable(true);
             },
             Instruction::BVC => {
                 if ! self.overflow_set() {
@@ -852,7 +1388,7 @@ impl Cpu {
             },
             Instruction::DEC => {
                 let result = self.decrement_byte(op as u8);
-                self.store_to_operand(result as u8, &decoded_instruction, memory);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::DEX => {
                 let x = self.x;
@@ -870,7 +1406,7 @@ impl Cpu {
             },
             Instruction::INC => {
                 let result = self.increment_byte(op as u8);
-                self.store_to_operand(result as u8, &decoded_instruction, memory);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::INX => {
                 let x = self.x;
@@ -884,7 +1420,7 @@ impl Cpu {
                 self.pc = op;
             },
             Instruction::JSR => {
-                let ret_addr = self.pc - 1;
+                let ret_addr = self.pc.wrapping_sub(1);
                 self.push_word(ret_addr, memory);
                 self.pc = op;
             },
@@ -904,11 +1440,8 @@ impl Cpu {
                 self.y = op as u8;
             },
             Instruction::LSR => {
-                self.update_carry(op & 0x1 == 0x1);
-                let result = op >> 1;
-                self.update_zero(result == 0);
-                self.update_negative_from_byte(result as u8);
-                self.store_to_operand(result as u8, &decoded_instruction, memory);
+                let result = self.shift_right(op as u8, false);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::NOP => {},
             Instruction::ORA => {
@@ -922,7 +1455,7 @@ impl Cpu {
                 self.push_byte(push, memory);
             },
             Instruction::PHP => {
-                let push = self.p;
+                let push = self.pushed_flags(true);
                 self.push_byte(push, memory);
             },
             Instruction::PLA => {
@@ -932,63 +1465,29 @@ impl Cpu {
                 self.a = result;
             },
             Instruction::PLP => {
-                self.p = self.pop_byte(memory);
+                let flags = self.pop_byte(memory);
+                self.pull_flags(flags);
             },
             Instruction::ROL => {
-                let result = op << 1 | if self.carry_set() {1} else {0};
-                self.update_carry(result & 0x100 == 0x100);
-                self.update_zero(result & 0xFF == 0);
-                self.update_negative_from_byte(result as u8);
-                self.store_to_operand((result & 0xFF) as u8,
-                    &decoded_instruction, memory);
-
+                let carry = self.carry_set();
+                let result = self.shift_left(op as u8, carry);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::ROR => {
-                let carry = op & 0x1 == 0x1;
-                let result = op >> 1 | if self.carry_set() {0x80} else {0};
-                self.update_carry(carry);
-                self.update_zero(result == 0);
-                self.update_negative_from_byte(result as u8);
-                self.store_to_operand(result as u8, &decoded_instruction, memory);
+                let carry = self.carry_set();
+                let result = self.shift_right(op as u8, carry);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
             },
             Instruction::RTI => {
-                self.p = self.pop_byte(memory);
+                let flags = self.pop_byte(memory);
+                self.pull_flags(flags);
                 self.pc = self.pop_word(memory);
             },
             Instruction::RTS => {
-                self.pc = self.pop_word(memory) + 1;
+                self.pc = self.pop_word(memory).wrapping_add(1);
             },
             Instruction::SBC => {
-                let sub = if self.decimal_set() {
-                    // this is highly likely wrong...
-                    // it is not exercised by the asteroids rom and who knows
-                    // what actually happens when you subtract, say, CA from F3?
-                    let lhs_a = (self.a & 0xF0) >> 4;
-                    let lhs_o = (op as u8 & 0xF0) >> 4;
-                    let rhs_a = self.a & 0xF;
-                    let rhs_o = op as u8 & 0xF;
-                    let borrow1 = if self.carry_set() {0} else {1};
-                    let borrow2 = if rhs_a >= rhs_o + borrow1 {0} else {1};
-                    let borrow3 = if lhs_a >= lhs_o + borrow2 {0} else {1};
-                    let rhs = rhs_a + borrow2 * 10 - rhs_o - borrow1;
-                    let lhs = lhs_a + borrow3 * 10 - lhs_o - borrow2;
-                    self.update_carry(borrow3 == 0);
-                    (lhs % 10) << 4 | (rhs % 10)
-                }
-                else {
-                    let sub_tot = op + if self.carry_set() {0} else {1};
-                    let new_carry = sub_tot <= self.a as u16;
-                    self.update_carry(new_carry);
-                    let result = self.a as u16 + 0x100 - sub_tot;
-                    (result & 0xFF) as u8
-                };
-                // same rules seem to apply for negative and overflow for bcd
-                self.update_negative_from_byte(sub as u8);
-                self.update_zero(sub == 0);
-                let overflow = (self.a < 128 && (sub & 0xFF) > 127) ||
-                    (self.a > 127 && (sub & 0xFF) < 128);
-                self.update_overflow(overflow);
-                self.a = sub as u8;
+                self.subtract_with_carry(op);
             },
             Instruction::SEC => {
                 self.update_carry(true);
@@ -1044,6 +1543,58 @@ impl Cpu {
                 self.update_negative_from_byte(result);
                 self.a = result;
             },
+            Instruction::LAX => {
+                self.update_zero(op == 0);
+                self.update_negative_from_byte(op as u8);
+                self.a = op as u8;
+                self.x = op as u8;
+            },
+            Instruction::SAX => {
+                let result = self.a & self.x;
+                self.store_to_operand(result, &decoded_instruction, memory);
+            },
+            Instruction::DCP => {
+                let result = (op as u8).wrapping_sub(1);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                let a = self.a;
+                self.compare(a, result);
+            },
+            Instruction::ISC => {
+                let result = (op as u8).wrapping_add(1);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                self.subtract_with_carry(result as u16);
+            },
+            Instruction::SLO => {
+                let result = self.shift_left(op as u8, false);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                let a = self.a | result;
+                self.update_zero(a == 0);
+                self.update_negative_from_byte(a);
+                self.a = a;
+            },
+            Instruction::RLA => {
+                let carry = self.carry_set();
+                let result = self.shift_left(op as u8, carry);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                let a = self.a & result;
+                self.update_zero(a == 0);
+                self.update_negative_from_byte(a);
+                self.a = a;
+            },
+            Instruction::SRE => {
+                let result = self.shift_right(op as u8, false);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                let a = self.a ^ result;
+                self.update_zero(a == 0);
+                self.update_negative_from_byte(a);
+                self.a = a;
+            },
+            Instruction::RRA => {
+                let carry = self.carry_set();
+                let result = self.shift_right(op as u8, carry);
+                self.write_modified(op as u8, result, &decoded_instruction, memory);
+                self.add_with_carry(result as u16);
+            },
             Instruction::INVALID => {},
         }
 	let cycles = self.instruction_cycles(&decoded_instruction, memory);
@@ -1053,18 +1604,21 @@ impl Cpu {
         }
     }
 
-    fn instruction_cycles(&self, decoded_instruction: &DecodedInstruction,
-            memory: &Memory) -> u64 {
+    fn instruction_cycles<B: Bus>(&self, decoded_instruction: &DecodedInstruction,
+            memory: &B) -> u64 {
         
         match decoded_instruction.instruction {
             Instruction::ADC | Instruction::AND | Instruction::BIT |
             Instruction::CMP | Instruction::CPX | Instruction::CPY |
             Instruction::EOR | Instruction::LDA | Instruction::LDX |
-            Instruction::LDY | Instruction::ORA | Instruction::SBC => {
+            Instruction::LDY | Instruction::ORA | Instruction::SBC |
+            Instruction::LAX | Instruction::INVALID => {
                 match decoded_instruction.addressing_mode {
+                    AddressingMode::NA => 2,    // undocumented single byte NOPs
                     AddressingMode::Immediate => 2,
                     AddressingMode::ZeroPage => 3,
-                    AddressingMode::ZeroPageOffsetX => 4,
+                    AddressingMode::ZeroPageOffsetX |
+                    AddressingMode::ZeroPageOffsetY => 4,
                     AddressingMode::Absolute => 4,
                     AddressingMode::AbsoluteOffsetX => {
                         4 +
@@ -1088,7 +1642,7 @@ impl Cpu {
                     AddressingMode::IndirectOffsetY => {
                         5 +
                         if let Some(a) = decoded_instruction.operand {
-                            let addr = Cpu::get_word(a, memory);
+                            let addr = Cpu::get_zero_page_word(a as u8, memory);
                             Cpu::page_cross_penalty(addr, self.y)
                         }
                         else {
@@ -1101,6 +1655,7 @@ impl Cpu {
             Instruction::ASL | Instruction::DEC | Instruction::INC |
             Instruction::LSR | Instruction::ROL | Instruction::ROR => {
                 match decoded_instruction.addressing_mode {
+                    // no memory read or write, so no extra cycles for them
                     AddressingMode::Accumulator => 2,
                     AddressingMode::ZeroPage => 5,
                     AddressingMode::ZeroPageOffsetX => 6,
@@ -1109,12 +1664,31 @@ impl Cpu {
                     _ => 0,
                 }
             },
+            // the undocumented read-modify-write instructions always take
+            // the extra indexing cycle, like the documented ones
+            Instruction::DCP | Instruction::ISC | Instruction::SLO |
+            Instruction::RLA | Instruction::SRE | Instruction::RRA => {
+                match decoded_instruction.addressing_mode {
+                    AddressingMode::ZeroPage => 5,
+                    AddressingMode::ZeroPageOffsetX => 6,
+                    AddressingMode::Absolute => 6,
+                    AddressingMode::AbsoluteOffsetX |
+                    AddressingMode::AbsoluteOffsetY => 7,
+                    AddressingMode::OffsetXIndirect |
+                    AddressingMode::IndirectOffsetY => 8,
+                    _ => 0,
+                }
+            },
             Instruction::BCC | Instruction::BCS | Instruction::BEQ |
             Instruction::BMI | Instruction::BNE | Instruction::BPL |
             Instruction::BVC | Instruction::BVS => {
-                2 + 
-                if self.pc != self.previous_pc + 2 {
-                    if self.pc & 0xFF00 == (self.previous_pc + 2) & 0xFF00 {
+                // one more if taken, even to the next instruction, and
+                // another if that's on a different page to the one after
+                // the branch
+                let next = decoded_instruction.address.wrapping_add(2);
+                2 +
+                if self.branch_taken(decoded_instruction.instruction) {
+                    if self.pc & 0xFF00 == next & 0xFF00 {
                         1
                     }
                     else {
@@ -1142,10 +1716,12 @@ impl Cpu {
             Instruction::JSR | Instruction::RTI | Instruction::RTS => 6,
             Instruction::PHA | Instruction::PHP => 3,
             Instruction::PLA | Instruction::PLP => 4,
-            Instruction::STA | Instruction::STX | Instruction::STY => {
+            Instruction::STA | Instruction::STX | Instruction::STY |
+            Instruction::SAX => {
                 match decoded_instruction.addressing_mode {
                     AddressingMode::ZeroPage => 3,
-                    AddressingMode::ZeroPageOffsetX => 4,
+                    AddressingMode::ZeroPageOffsetX |
+                    AddressingMode::ZeroPageOffsetY => 4,
                     AddressingMode::Absolute => 4,
 		    AddressingMode::AbsoluteOffsetX => 5,
                     AddressingMode::AbsoluteOffsetY => 5,
@@ -1154,7 +1730,362 @@ impl Cpu {
                     _ => 0,
                 }
             },
-            Instruction::INVALID => 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conformance::FlatMemory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAM: u16 = 0x0200;
+
+    // the program at 0200 on plain RAM, run for the given number of
+    // instructions
+    fn run(program: &[u8], instructions: usize) -> (Cpu, FlatMemory) {
+        let mut memory = FlatMemory::new();
+        memory.load(PROGRAM, program);
+        let mut cpu = Cpu::new(false, false);
+        cpu.restore(CpuState { a: 0, x: 0, y: 0, pc: PROGRAM, s: 0xFF, p: 0x24, cycle: 0 });
+        for _ in 0..instructions {
+            cpu.execute_instruction(&mut memory);
+        }
+        (cpu, memory)
+    }
+
+    // how many cycles the last of the instructions took
+    fn last_cycles(program: &[u8], instructions: usize) -> u64 {
+        run(program, instructions).0.cycle - run(program, instructions - 1).0.cycle
+    }
+
+    // N, V, Z and C, in that order
+    fn flags(cpu: &Cpu) -> (bool, bool, bool, bool) {
+        (
+            cpu.flag(Flag::Negative),
+            cpu.flag(Flag::Overflow),
+            cpu.flag(Flag::Zero),
+            cpu.flag(Flag::Carry),
+        )
+    }
+
+    #[test]
+    fn decimal_adc_carries_out_of_the_high_digit() {
+        // SED, CLC, LDA #$99, ADC #$01
+        let (cpu, _) = run(&[0xF8, 0x18, 0xA9, 0x99, 0x69, 0x01], 4);
+        assert_eq!(cpu.a(), 0x00);
+        // N comes from the sum before the high digit is adjusted, and Z from
+        // the binary sum 9A
+        assert_eq!(flags(&cpu), (true, false, false, true));
+    }
+
+    #[test]
+    fn decimal_adc_overflow() {
+        // SED, CLC, LDA #$79, ADC #$10
+        let (cpu, _) = run(&[0xF8, 0x18, 0xA9, 0x79, 0x69, 0x10], 4);
+        assert_eq!(cpu.a(), 0x89);
+        assert_eq!(flags(&cpu), (true, true, false, false));
+    }
+
+    #[test]
+    fn decimal_sbc_borrows() {
+        // SED, SEC, LDA #$00, SBC #$01
+        let (cpu, _) = run(&[0xF8, 0x38, 0xA9, 0x00, 0xE9, 0x01], 4);
+        assert_eq!(cpu.a(), 0x99);
+        // the flags come from the binary difference FF
+        assert_eq!(flags(&cpu), (true, false, false, false));
+
+        // SED, SEC, LDA #$10, SBC #$01, a borrow from the high digit only
+        let (cpu, _) = run(&[0xF8, 0x38, 0xA9, 0x10, 0xE9, 0x01], 4);
+        assert_eq!(cpu.a(), 0x09);
+        assert!(cpu.flag(Flag::Carry));
+    }
+
+    #[test]
+    fn decimal_sbc_takes_the_borrow_in() {
+        // SED, CLC, LDA #$50, SBC #$25
+        let (cpu, _) = run(&[0xF8, 0x18, 0xA9, 0x50, 0xE9, 0x25], 4);
+        assert_eq!(cpu.a(), 0x24);
+        assert!(cpu.flag(Flag::Carry));
+    }
+
+    #[test]
+    fn overflow_for_each_combination_of_signs() {
+        // A, the operand, and the result and V for ADC with carry clear
+        // then SBC with carry set
+        let cases = [
+            (0x50, 0x50, (0xA0, true), (0x00, false)),
+            (0x50, 0xB0, (0x00, false), (0xA0, true)),
+            (0xD0, 0x70, (0x40, false), (0x60, true)),
+            (0xD0, 0x90, (0x60, true), (0x40, false)),
+        ];
+        for &(a, operand, (sum, adc_overflow), (difference, sbc_overflow)) in &cases {
+            // CLC, LDA #a, ADC #operand
+            let (cpu, _) = run(&[0x18, 0xA9, a, 0x69, operand], 3);
+            assert_eq!(cpu.a(), sum, "{:02X} + {:02X}", a, operand);
+            assert_eq!(cpu.flag(Flag::Overflow), adc_overflow, "{:02X} + {:02X}", a, operand);
+            // SEC, LDA #a, SBC #operand
+            let (cpu, _) = run(&[0x38, 0xA9, a, 0xE9, operand], 3);
+            assert_eq!(cpu.a(), difference, "{:02X} - {:02X}", a, operand);
+            assert_eq!(cpu.flag(Flag::Overflow), sbc_overflow, "{:02X} - {:02X}", a, operand);
+        }
+    }
+
+    #[test]
+    fn page_cross_penalty() {
+        // LDX #$01, then LDA $0280,X staying in the page and LDA $02FF,X
+        // crossing it
+        assert_eq!(last_cycles(&[0xA2, 0x01, 0xBD, 0x80, 0x02], 2), 4);
+        assert_eq!(last_cycles(&[0xA2, 0x01, 0xBD, 0xFF, 0x02], 2), 5);
+        // the same with LDY and LDA $02FF,Y
+        assert_eq!(last_cycles(&[0xA0, 0x01, 0xB9, 0x80, 0x02], 2), 4);
+        assert_eq!(last_cycles(&[0xA0, 0x01, 0xB9, 0xFF, 0x02], 2), 5);
+        // LDA ($10),Y with Y 0 then 1 and the pointer at 0010 set to 02FF
+        // by LDA #$FF, STA $10, LDA #$02, STA $11
+        let pointer = [0xA9, 0xFF, 0x85, 0x10, 0xA9, 0x02, 0x85, 0x11];
+        assert_eq!(last_cycles(&[&pointer[..], &[0xA0, 0x00, 0xB1, 0x10]].concat(), 6), 5);
+        assert_eq!(last_cycles(&[&pointer[..], &[0xA0, 0x01, 0xB1, 0x10]].concat(), 6), 6);
+        // stores always take the extra cycle, LDX #$01 and STA $0280,X
+        assert_eq!(last_cycles(&[0xA2, 0x01, 0x9D, 0x80, 0x02], 2), 5);
+    }
+
+    #[test]
+    fn indexing_past_ffff_wraps_to_page_zero() {
+        // LDA #$42, STA $00, LDA #$00, LDX #$01, then LDA $FFFF,X reading
+        // 0000, which counts as crossing a page
+        let program = [0xA9, 0x42, 0x85, 0x00, 0xA9, 0x00, 0xA2, 0x01, 0xBD, 0xFF, 0xFF];
+        let (cpu, _) = run(&program, 5);
+        assert_eq!(cpu.a(), 0x42);
+        assert_eq!(last_cycles(&program, 5), 5);
+    }
+
+    #[test]
+    fn branch_cycles() {
+        // SEC, BCC +$10 not taken
+        assert_eq!(last_cycles(&[0x38, 0x90, 0x10], 2), 2);
+        // CLC, BCC +$10 to 0213, in the same page
+        assert_eq!(run(&[0x18, 0x90, 0x10], 2).0.pc(), 0x0213);
+        assert_eq!(last_cycles(&[0x18, 0x90, 0x10], 2), 3);
+        // CLC, BCC -$10 back to 01F3, in the page before
+        assert_eq!(run(&[0x18, 0x90, 0xF0], 2).0.pc(), 0x01F3);
+        assert_eq!(last_cycles(&[0x18, 0x90, 0xF0], 2), 4);
+    }
+
+    #[test]
+    fn zero_page_pointers_wrap() {
+        // 77 at 1234, with the pointer to it split between 00FF and 0000 by
+        // LDA #$77, STA $1234, LDA #$34, STA $FF, LDA #$12, STA $00. a
+        // pointer read from 00FF and 0100 would be 0034, which is 0
+        let pointer = [
+            0xA9, 0x77, 0x8D, 0x34, 0x12, 0xA9, 0x34, 0x85, 0xFF, 0xA9, 0x12, 0x85, 0x00,
+        ];
+        // LDX #$01, LDA ($FE,X)
+        let (cpu, _) = run(&[&pointer[..], &[0xA2, 0x01, 0xA1, 0xFE]].concat(), 8);
+        assert_eq!(cpu.a(), 0x77);
+        // LDY #$00, LDA ($FF),Y
+        let (cpu, _) = run(&[&pointer[..], &[0xA0, 0x00, 0xB1, 0xFF]].concat(), 8);
+        assert_eq!(cpu.a(), 0x77);
+    }
+
+    #[test]
+    fn jsr_and_rts_at_the_top_of_memory() {
+        // JSR $0300 at FFFD, so the next instruction is at 0000, and RTS
+        let mut memory = FlatMemory::new();
+        memory.load(0xFFFD, &[0x20, 0x00, 0x03]);
+        memory.load(0x0300, &[0x60]);
+        let mut cpu = Cpu::new(false, false);
+        cpu.restore(CpuState { a: 0, x: 0, y: 0, pc: 0xFFFD, s: 0xFF, p: 0x24, cycle: 0 });
+        cpu.execute_instruction(&mut memory);
+        assert_eq!(cpu.pc(), 0x0300);
+        // the address of the JSR's last byte
+        assert_eq!(memory.read(0x01FF), 0xFF);
+        assert_eq!(memory.read(0x01FE), 0xFF);
+        cpu.execute_instruction(&mut memory);
+        assert_eq!(cpu.pc(), 0x0000);
+        assert_eq!(cpu.s(), 0xFF);
+    }
+
+    #[test]
+    fn jmp_indirect_pointer_wraps_within_its_page() {
+        // JMP ($03FF), with 34 at 03FF, 12 at 0300 and 56 at 0400
+        let mut memory = FlatMemory::new();
+        memory.load(PROGRAM, &[0x6C, 0xFF, 0x03]);
+        memory.load(0x0300, &[0x12]);
+        memory.load(0x03FF, &[0x34, 0x56]);
+        let mut cpu = Cpu::new(false, false);
+        cpu.restore(CpuState { a: 0, x: 0, y: 0, pc: PROGRAM, s: 0xFF, p: 0x24, cycle: 0 });
+        cpu.execute_instruction(&mut memory);
+        assert_eq!(cpu.pc(), 0x1234);
+        assert_eq!(cpu.cycle, 5);
+    }
+
+    #[test]
+    fn run_until_breakpoint_stops_there() {
+        // LDX #$00, then INX and BNE -3 looping until X wraps round, then NOP
+        let program = [0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0xEA];
+        let (mut cpu, mut memory) = run(&program, 0);
+        cpu.add_breakpoint(0x0202);
+        cpu.add_breakpoint(0x0205);
+        assert_eq!(cpu.run_until_breakpoint(&mut memory), 0x0202);
+        assert_eq!(cpu.x(), 0x00);
+        // from the breakpoint on round the loop to it again
+        assert_eq!(cpu.run_until_breakpoint(&mut memory), 0x0202);
+        assert_eq!(cpu.x(), 0x01);
+        cpu.remove_breakpoint(0x0202);
+        assert_eq!(cpu.run_until_breakpoint(&mut memory), 0x0205);
+        assert_eq!(cpu.x(), 0x00);
+        assert_eq!(cpu.pc(), 0x0205);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        // LDA #$80, LDX #$05, DEX, TXS, SEC, then PHA and a loop back to DEX
+        let program = [0xA9, 0x80, 0xA2, 0x05, 0xCA, 0x9A, 0x38, 0x48, 0x4C, 0x04, 0x02];
+        let (mut cpu, mut memory) = run(&program, 2);
+        let state = cpu.snapshot();
+        for _ in 0..7 {
+            cpu.execute_instruction(&mut memory);
+        }
+        let after = cpu.snapshot();
+        assert_ne!(after, state);
+        cpu.restore(state);
+        assert_eq!(cpu.snapshot(), state);
+        for _ in 0..7 {
+            cpu.execute_instruction(&mut memory);
+        }
+        assert_eq!(cpu.snapshot(), after);
+    }
+
+    #[test]
+    fn irq_only_taken_with_interrupts_enabled() {
+        // SEI, CLI, with the IRQ vector pointing at 1234
+        let (mut cpu, mut memory) = run(&[0x78, 0x58], 1);
+        memory.load(0xFFFE, &[0x34, 0x12]);
+        let cycle = cpu.cycle;
+        assert!(!cpu.initiate_irq(&mut memory));
+        assert_eq!((cpu.pc(), cpu.s(), cpu.cycle), (0x0201, 0xFF, cycle));
+
+        cpu.execute_instruction(&mut memory);
+        let cycle = cpu.cycle;
+        assert!(cpu.initiate_irq(&mut memory));
+        assert_eq!((cpu.pc(), cpu.s(), cpu.cycle), (0x1234, 0xFC, cycle + 7));
+        assert!(cpu.flag(Flag::IrqDisable));
+        // the return address, then P with B clear
+        assert_eq!((memory.read(0x01FF), memory.read(0x01FE)), (0x02, 0x02));
+        assert_eq!(memory.read(0x01FD) & FLAG_BREAK, 0);
+    }
+
+    // B is only ever set in P as it's pushed by BRK and PHP, and bit 5 is
+    // always set there
+    #[test]
+    fn pushed_and_pulled_flags() {
+        // BRK
+        let (_, memory) = run(&[0x00], 1);
+        assert_eq!(memory.read(0x01FD), 0x34);
+        // PHP
+        let (_, memory) = run(&[0x08], 1);
+        assert_eq!(memory.read(0x01FF), 0x34);
+        // NMI
+        let (mut cpu, mut memory) = run(&[], 0);
+        cpu.initiate_nmi(&mut memory);
+        assert_eq!(memory.read(0x01FD), 0x24);
+        // IRQ, after CLI
+        let (mut cpu, mut memory) = run(&[0x58], 1);
+        cpu.initiate_irq(&mut memory);
+        assert_eq!(memory.read(0x01FD), 0x20);
+        // PLP of FF and of 00, pushed with LDA and PHA
+        let (cpu, _) = run(&[0xA9, 0xFF, 0x48, 0x28], 3);
+        assert_eq!(cpu.p(), 0xEF);
+        let (cpu, _) = run(&[0xA9, 0x00, 0x48, 0x28], 3);
+        assert_eq!(cpu.p(), 0x20);
+        // RTI to 0210 with FF for P, pushed with LDA and PHA
+        let (cpu, _) = run(&[0xA9, 0x02, 0x48, 0xA9, 0x10, 0x48, 0xA9, 0xFF, 0x48, 0x40], 7);
+        assert_eq!((cpu.pc(), cpu.p()), (0x0210, 0xEF));
+    }
+
+    #[test]
+    fn lax_zero_page() {
+        // LDA #$85, STA $10, LDA #$00, LAX $10
+        let program = [0xA9, 0x85, 0x85, 0x10, 0xA9, 0x00, 0xA7, 0x10];
+        let (cpu, _) = run(&program, 4);
+        assert_eq!((cpu.a(), cpu.x()), (0x85, 0x85));
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        assert_eq!(last_cycles(&program, 4), 3);
+    }
+
+    #[test]
+    fn dcp_absolute() {
+        // LDA #$43, STA $0300, then DCP $0300 decrementing it to 42 and
+        // comparing A with it, first with A 42 and then with A 40
+        let (cpu, memory) = run(&[0xA9, 0x43, 0x8D, 0x00, 0x03, 0xA9, 0x42, 0xCF, 0x00, 0x03], 4);
+        assert_eq!(memory.read(0x0300), 0x42);
+        assert_eq!(cpu.a(), 0x42);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+        let program = [0xA9, 0x43, 0x8D, 0x00, 0x03, 0xA9, 0x40, 0xCF, 0x00, 0x03];
+        let (cpu, _) = run(&program, 4);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        assert_eq!(last_cycles(&program, 4), 6);
+    }
+
+    // a trace log the test can still read once the CPU has it
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_log_lines() {
+        // LDX #$FE, STA $0300,X, JMP ($0300)
+        let mut memory = FlatMemory::new();
+        memory.load(PROGRAM, &[0xA2, 0xFE, 0x9D, 0x00, 0x03, 0x6C, 0x00, 0x03]);
+        let mut cpu = Cpu::new(false, false);
+        cpu.restore(CpuState { a: 0, x: 0, y: 0, pc: PROGRAM, s: 0xFD, p: 0x24, cycle: 7 });
+        let log = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_trace_log(Box::new(SharedLog(log.clone())));
+        for _ in 0..3 {
+            cpu.execute_instruction(&mut memory);
+        }
+        let text = String::from_utf8(log.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0200  A2 FE     LDX #$FE                        A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+                "0202  9D 00 03  STA $0300,X                     A:00 X:FE Y:00 P:A4 SP:FD CYC:9",
+                "0205  6C 00 03  JMP ($0300)                     A:00 X:FE Y:00 P:A4 SP:FD CYC:14",
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_and_flags_after_loads() {
+        // LDA #$42, LDX #$00, LDY #$80
+        let (cpu, _) = run(&[0xA9, 0x42], 1);
+        assert_eq!(cpu.a(), 0x42);
+        assert!(!cpu.flag(Flag::Zero));
+        assert!(!cpu.flag(Flag::Negative));
+        let (cpu, _) = run(&[0xA9, 0x42, 0xA2, 0x00], 2);
+        assert_eq!(cpu.x(), 0x00);
+        assert!(cpu.flag(Flag::Zero));
+        let (cpu, _) = run(&[0xA9, 0x42, 0xA2, 0x00, 0xA0, 0x80], 3);
+        assert!(!cpu.flag(Flag::Zero));
+        assert!(cpu.flag(Flag::Negative));
+        assert!(cpu.flag(Flag::IrqDisable));
+        let registers = cpu.registers();
+        assert_eq!(
+            (registers.a, registers.x, registers.y, registers.s, registers.pc),
+            (0x42, 0x00, 0x80, 0xFF, 0x0206)
+        );
+        assert_eq!(registers.p, cpu.p());
+        assert_eq!(cpu.p(), 0xA4);
+    }
+}
@@ -0,0 +1,176 @@
+// a 6502 debugger on the terminal. with --step the game stops before its
+// first instruction, and it stops again at each breakpoint. while stopped,
+// the instruction about to run is printed with the registers, and commands
+// are read from stdin:
+//
+//   enter or s      - run one instruction
+//   s N             - run N instructions
+//   n               - run one instruction, but a JSR runs until it returns
+//   c               - carry on running until the next breakpoint
+//   m START[-END]   - print memory, 16 bytes if there's no end
+//   b ADDR          - set a breakpoint
+//   d               - delete the breakpoint here
+//   x               - clear all breakpoints
+//
+// addresses are in hex. the window isn't updated while stopped
+
+use std::io;
+
+use cpu::{self, Flag};
+use machine::Machine;
+
+const JSR: u8 = 0x20;
+
+const DUMP_ROW: usize = 16;
+
+// as they're printed, a letter when set and a dot when clear
+const FLAG_LETTERS: [(Flag, char); 6] = [
+    (Flag::Negative, 'N'),
+    (Flag::Overflow, 'V'),
+    (Flag::Decimal, 'D'),
+    (Flag::IrqDisable, 'I'),
+    (Flag::Zero, 'Z'),
+    (Flag::Carry, 'C'),
+];
+
+pub struct Debugger {
+    stepping: bool,
+    remaining: u32, // instructions left to run before stopping again
+    // stepping over a JSR, where it returns to and the stack pointer before
+    // it was called
+    step_over: Option<(u16, u8)>,
+}
+
+impl Debugger {
+    pub fn new(stepping: bool) -> Debugger {
+        Debugger {
+            stepping,
+            remaining: 0,
+            step_over: None,
+        }
+    }
+
+    // whether the instruction about to run is where to stop. a breakpoint
+    // stops even part way through stepping over or running N instructions
+    fn should_stop(&mut self, pc: u16, s: u8, at_breakpoint: bool) -> bool {
+        if at_breakpoint {
+            self.stepping = true;
+            self.remaining = 0;
+            self.step_over = None;
+            return true;
+        }
+        if let Some((return_address, called_s)) = self.step_over {
+            // the stack pointer tells a return from this call apart from
+            // one out of a recursive call to the same subroutine
+            if pc != return_address || s != called_s {
+                return false;
+            }
+            self.step_over = None;
+        }
+        if !self.stepping {
+            return false;
+        }
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return false;
+        }
+        true
+    }
+
+    // called before each instruction is run, returning once it's time to
+    // run it
+    pub fn before_instruction(&mut self, machine: &mut Machine) {
+        let registers = machine.cpu.registers();
+        let (pc, s) = (registers.pc, registers.s);
+        if !self.should_stop(pc, s, machine.cpu.at_breakpoint()) {
+            return;
+        }
+        let (text, length) = cpu::disassemble(&machine.memory, pc);
+        let flags: String = FLAG_LETTERS
+            .iter()
+            .map(|&(flag, letter)| if machine.cpu.flag(flag) { letter } else { '.' })
+            .collect();
+        println!(
+            "{:04X} {}  A: {:02X} X: {:02X} Y: {:02X} S: {:02X} P: {} cycle: {}",
+            pc,
+            text,
+            registers.a,
+            registers.x,
+            registers.y,
+            s,
+            flags,
+            machine.cpu.cycle
+        );
+        loop {
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line).map(|read| read == 0).unwrap_or(true) {
+                // nothing more to read, so let it run
+                self.stepping = false;
+                return;
+            }
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) | (Some("s"), None) => return,
+                (Some("s"), Some(count)) => match count.parse::<u32>() {
+                    Ok(count) if count > 0 => {
+                        self.remaining = count - 1;
+                        return;
+                    }
+                    _ => println!("Invalid number of instructions '{}'", count),
+                },
+                (Some("n"), None) => {
+                    if machine.memory.peek(pc) == JSR {
+                        self.step_over = Some((pc.wrapping_add(length), s));
+                    }
+                    return;
+                }
+                (Some("c"), None) => {
+                    self.stepping = false;
+                    return;
+                }
+                (Some("m"), Some(range)) => match parse_range(range) {
+                    Some((start, end)) => dump(machine, start, end),
+                    None => println!("Invalid address range '{}'", range),
+                },
+                (Some("b"), Some(address)) => match parse_address(address) {
+                    Some(address) => machine.cpu.add_breakpoint(address),
+                    None => println!("Invalid address '{}'", address),
+                },
+                (Some("d"), None) => machine.cpu.remove_breakpoint(pc),
+                (Some("x"), None) => machine.cpu.clear_breakpoints(),
+                _ => println!(
+                    "Enter or s [N] to step, n to step over, c to continue, m START[-END] \
+                     to show memory, b ADDR to set a breakpoint, d to delete this one or \
+                     x to clear them all"
+                ),
+            }
+        }
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_start_matches('$'), 16).ok()
+}
+
+// START-END, or just START for a row's worth
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let mut parts = text.splitn(2, '-');
+    let start = parse_address(parts.next()?)?;
+    let end = match parts.next() {
+        Some(end) => parse_address(end)?,
+        None => start.saturating_add(DUMP_ROW as u16 - 1),
+    };
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn dump(machine: &Machine, start: u16, end: u16) {
+    let bytes = machine.memory.dump(start, (end - start) as usize + 1);
+    for (row, chunk) in bytes.chunks(DUMP_ROW).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        println!("{:04X}: {}", start as usize + row * DUMP_ROW, hex.join(" "));
+    }
+}
@@ -1,13 +1,14 @@
 // Emulate Atari Digital Vector Generator / display
-use sdl2::gfx::primitives::DrawRenderer;
 use sdl2::pixels;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
 use serialport::prelude::*;
 use std::thread::sleep;
 use std::time::Duration;
 
+use colors::ColorScheme;
+use exclusions::{ExclusionRect, SerialFilter};
+use game::CURRENT_PLAYER;
 use memory::Memory;
+use render::{RenderTarget, SvgTarget};
 
 #[derive(Debug)]
 enum Instruction {
@@ -20,21 +21,86 @@ enum Instruction {
     SVEC, // 0xF
 }
 
+// a single beam movement. z is the intensity, with zero being a blanked move
+// and pc the word address of the DVG instruction that made it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub x0: i16,
+    pub y0: i16,
+    pub x1: i16,
+    pub y1: i16,
+    pub z: u16,
+    pub pc: u16,
+}
+
+impl Segment {
+    // squared distance from a point to the nearest point on the segment
+    fn distance_squared(&self, x: i16, y: i16) -> f64 {
+        let (x0, y0) = (self.x0 as f64, self.y0 as f64);
+        let (dx, dy) = (self.x1 as f64 - x0, self.y1 as f64 - y0);
+        let (px, py) = (x as f64 - x0, y as f64 - y0);
+        let length_squared = dx * dx + dy * dy;
+        let t = if length_squared == 0.0 {
+            0.0
+        } else {
+            ((px * dx + py * dy) / length_squared).max(0.0).min(1.0)
+        };
+        let (ex, ey) = (px - t * dx, py - t * dy);
+        ex * ex + ey * ey
+    }
+}
+
+// something that consumes a whole frame's worth of beam movements
+pub trait VectorSink {
+    fn send_frame(&mut self, segments: &[Segment]);
+}
+
+// the receiver executes each command over i2c before it's ready for the
+// next, and USB bandwidth isn't the limit, so by default we pace commands
+const DEFAULT_SERIAL_DELAY: Duration = Duration::from_micros(50);
+
+// pause after each step of --beam-trace so the sweep can be followed
+const BEAM_TRACE_DELAY: Duration = Duration::from_millis(2);
+
+// the monitor's brightness isn't linear in the intensity, and a straight
+// mapping to alpha looks washed out on a modern screen
+pub const DEFAULT_GAMMA: f64 = 2.2;
+
+// word address the DVG starts executing from on a go signal
+const START_PC: u16 = 1;
+
+// a display list that hasn't reached HALT by now is stuck in a loop
+const MAX_DISPLAY_LIST_INSTRUCTIONS: usize = 4096;
+
 pub struct Dvg {
     pc: u16,
     x: i16,
     y: i16,
-    sf: i16,
+    sf: u16, // global scale, as set by LABS
     stack: [u16; 4],
     sp: usize,
     debug_mode: bool,
     serialoutput: bool,
-    packet: [u8; 60],
-    packetidx: i16,
+    colors: ColorScheme,
+    serial_delay: Duration,
+    serial_wait_for_ack: bool,
+    serial_frame_sync: Option<u8>,
+    serial_filter: SerialFilter,
+    hud: Vec<ExclusionRect>, // the scores and messages, hidden on screen
+    hud_hidden: bool,
+    geometry_scale: f64,
+    gamma: f64,
+    cocktail: bool,
+    strict_stack: bool, // panic on a stack overflow or underflow, rather than halt
+    frame_error_reported: bool,
+    beam_trace: Option<usize>, // vectors drawn between presents when animating
+    last_frame: Vec<Segment>, // only kept in debug mode
+    packet: Vec<u8>,       // serial commands waiting to be written together
+    packet_vectors: usize, // how many commands make a full packet
 }
 
 impl Dvg {
-    pub fn new(debug_mode: bool, serialoutput: bool) -> Dvg {
+    pub fn new(debug_mode: bool, serialoutput: bool, colors: ColorScheme) -> Dvg {
         Dvg {
             pc: 0,
             x: 0,
@@ -44,26 +110,162 @@ impl Dvg {
             sp: 0,
             debug_mode,
             serialoutput,
-            packet: [0; 60],
-            packetidx: 0,
+            colors,
+            serial_delay: DEFAULT_SERIAL_DELAY,
+            serial_wait_for_ack: false,
+            serial_frame_sync: None,
+            serial_filter: SerialFilter::new(),
+            hud: ExclusionRect::defaults(),
+            hud_hidden: false,
+            geometry_scale: 1.0,
+            gamma: DEFAULT_GAMMA,
+            cocktail: false,
+            strict_stack: false,
+            frame_error_reported: false,
+            beam_trace: None,
+            last_frame: Vec::new(),
+            packet: Vec::new(),
+            packet_vectors: 1,
         }
     }
 
     fn reset(&mut self) {
-        self.pc = 1;
+        self.pc = START_PC;
         self.x = 0;
         self.y = 0;
         self.sf = 0;
         self.stack = [0; 4];
         self.sp = 0;
-        self.packet = [0; 60];
-        self.packetidx = 0;
+        self.packet.clear();
+    }
+
+    // forget the last frame and blank the screen, as after a reset
+    pub fn clear<R: RenderTarget>(&mut self, canvas: &mut R) {
+        self.reset();
+        self.last_frame.clear();
+        self.draw(&[], canvas);
+        canvas.present();
     }
 
+    // either wait a fixed time after each serial command, which may be zero
+    // for fast receivers, or wait for the receiver to send back a byte
+    // acknowledging the command (giving up after the port's timeout)
+    pub fn set_serial_pacing(&mut self, delay: Duration, wait_for_ack: bool) {
+        self.serial_delay = delay;
+        self.serial_wait_for_ack = wait_for_ack;
+    }
+
+    // gather this many serial commands into each write, rather than writing
+    // them one at a time. the pacing then applies to each packet. what's left
+    // at the end of a frame is written then
+    pub fn set_serial_packet_size(&mut self, vectors: usize) {
+        self.packet_vectors = vectors.max(1);
+    }
+
+    // optionally end each frame on the serial display with a packet whose
+    // first byte is the marker, so firmware that supports it can swap buffers.
+    // the marker must not be a valid intensity (0-15)
+    pub fn set_serial_frame_sync(&mut self, marker: Option<u8>) {
+        self.serial_frame_sync = marker;
+    }
+
+    // scale the whole picture, on screen and on the serial display, on top
+    // of the scaling the DVG instructions themselves do
+    pub fn set_geometry_scale(&mut self, scale: f64) {
+        self.geometry_scale = scale;
+    }
+
+    // the curve the colour scheme's alpha goes through on screen
+    pub fn set_gamma(&mut self, gamma: f64) {
+        self.gamma = gamma;
+    }
+
+    fn apply_gamma(&self, color: pixels::Color) -> pixels::Color {
+        let alpha = (color.a as f64 / 255.0).powf(self.gamma) * 255.0;
+        pixels::Color::RGBA(color.r, color.g, color.b, alpha.round() as u8)
+    }
+
+    // in a cocktail cabinet the players sit facing each other, so the
+    // picture is turned round during player 2's turns
+    pub fn set_cocktail(&mut self, cocktail: bool) {
+        self.cocktail = cocktail;
+    }
+
+    // the DVG has a 4 deep stack, so going past either end means the display
+    // list is broken, as does one that never reaches HALT. by default the
+    // frame just stops there, drawing what it has, but it can be made to
+    // panic instead to catch it in the act
+    pub fn set_strict_stack(&mut self, strict: bool) {
+        self.strict_stack = strict;
+    }
+
+    // end the frame early. a panic in strict mode, otherwise a warning the
+    // first time it happens
+    fn frame_error(&mut self, message: &str, memory: &mut Memory) {
+        if self.strict_stack {
+            panic!("{}", message);
+        }
+        if !self.frame_error_reported {
+            println!("{} at {:03X}, halting the frame (only reported once)", message, self.pc);
+            self.frame_error_reported = true;
+        }
+        memory.mapped_io.halt = 0;
+    }
+
+    // rather than showing each frame all at once, animate the beam drawing
+    // it, presenting the canvas every `step` vectors
+    pub fn set_beam_trace(&mut self, step: Option<usize>) {
+        self.beam_trace = step;
+    }
+
+    // what isn't sent to the serial display
+    pub fn serial_filter(&self) -> &SerialFilter {
+        &self.serial_filter
+    }
+
+    pub fn serial_filter_mut(&mut self) -> &mut SerialFilter {
+        &mut self.serial_filter
+    }
+
+    // leave the scores and messages out of the window, for a clean picture.
+    // this is separate from the serial filter, so neither affects the other.
+    // returns whether the HUD is now hidden
+    pub fn toggle_hud(&mut self) -> bool {
+        self.hud_hidden = !self.hud_hidden;
+        self.hud_hidden
+    }
+
+    fn on_screen(&self, segment: &Segment) -> bool {
+        !self.hud_hidden || !self.hud.iter().any(|rect| rect.contains(segment.x1, segment.y1))
+    }
+
+    // write a hand assembled display list to where the DVG will start
+    // executing, so it can be rendered without running the 6502 at all
+    pub fn load_program(memory: &mut Memory, words: &[u16]) {
+        memory.load_dvg_words(START_PC, words);
+    }
+
+    // parse whitespace separated hex words, ignoring anything after a '#'
+    pub fn parse_program(text: &str) -> Result<Vec<u16>, String> {
+        let mut words = Vec::new();
+        for line in text.lines() {
+            let code = line.split('#').next().unwrap_or("");
+            for token in code.split_whitespace() {
+                let word = u16::from_str_radix(token.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Invalid DVG word '{}'", token))?;
+                words.push(word);
+            }
+        }
+        Ok(words)
+    }
+
+    // the DVG program counter is a 12 bit word address relative to 0x4000 as
+    // the 6502 sees it, so 000-7FF is vector RAM, 800-BFF is vector ROM and
+    // C00-FFF is unpopulated. the counter simply rolls over from FFF to 000
     fn load_from_pc(&mut self, memory: &Memory) -> u16 {
-        let addr = self.pc * 2 + 0x4000;
-        self.pc += 1;
-        (memory.get_byte(addr) as u16) | ((memory.get_byte(addr + 1) as u16) << 8)
+        let addr = (self.pc & 0xFFF) * 2 + 0x4000;
+        self.pc = (self.pc + 1) & 0xFFF;
+        memory.read_word(addr)
     }
 
     fn instruction_from_word(word: u16) -> Instruction {
@@ -79,22 +281,86 @@ impl Dvg {
         }
     }
 
-    fn screen_y(y: i16, h: u32) -> i16 {
+    pub fn screen_y(y: i16, h: u32) -> i16 {
         // we find we have to flip y
         // also, y 0 thru 95 and 928 thru 1023 are not used
         (h as i32 - ((y - 96) as i32 * h as i32 / 832)) as i16
     }
 
-    fn screen_x(x: i16, w: u32) -> i16 {
+    pub fn screen_x(x: i16, w: u32) -> i16 {
         (x as i32 * w as i32 / 1024) as i16
     }
 
+    // the inverse of screen_x and screen_y, for working out what was clicked
+    pub fn dvg_position(x: i32, y: i32, w: u32, h: u32) -> (i16, i16) {
+        let dvg_x = x * 1024 / w as i32;
+        let dvg_y = (h as i32 - y) * 832 / h as i32 + 96;
+        (dvg_x as i16, dvg_y as i16)
+    }
+
+    // the visible vector in the last frame closest to a DVG position. only
+    // available in debug mode
+    pub fn segment_near(&self, x: i16, y: i16) -> Option<Segment> {
+        self.last_frame
+            .iter()
+            .filter(|segment| segment.z != 0)
+            .min_by(|a, b| {
+                a.distance_squared(x, y)
+                    .partial_cmp(&b.distance_squared(x, y))
+                    .unwrap()
+            })
+            .cloned()
+    }
+
+    // the instruction at a DVG word address as disassemble shows it, found
+    // by following the display list so the global scale is the one in force
+    pub fn describe_instruction(memory: &Memory, pc: u16) -> String {
+        let address = format!("{:03X} ", pc);
+        Dvg::disassemble(memory)
+            .into_iter()
+            .find(|line| line.starts_with(&address))
+            .unwrap_or_else(|| format!("{:03X}  not in the display list", pc))
+    }
+
+    fn pace(&self, port: &mut Box<dyn SerialPort>) {
+        if self.serial_wait_for_ack {
+            let mut ack = [0u8; 1];
+            port.read_exact(&mut ack).ok();
+        } else if self.serial_delay > Duration::from_micros(0) {
+            sleep(self.serial_delay);
+        }
+    }
+
+    // the frame sync packet is the same size as a command, but with the
+    // marker where the intensity would be
+    fn write_packet(&mut self, port: &mut Box<dyn SerialPort>) {
+        if self.packet.is_empty() {
+            return;
+        }
+        port.write(&self.packet).ok();
+        self.packet.clear();
+        self.pace(port);
+    }
+
+    // write out a partly filled packet at the end of the frame
+    fn flush_packet(&mut self, port: &mut Option<Box<dyn SerialPort>>) {
+        if let (true, Some(port)) = (self.serialoutput, port) {
+            self.write_packet(port);
+        }
+    }
+
+    fn send_frame_sync(&mut self, port: &mut Option<Box<dyn SerialPort>>) {
+        if let (true, Some(marker), Some(port)) = (self.serialoutput, self.serial_frame_sync, port) {
+            port.write(&[marker, 0, 0, 0, 0]).ok();
+            self.pace(port);
+        }
+    }
+
     fn send_command(
         &mut self,
         x: i16,
         y: i16,
         z: u16,
-        canvas: &mut Canvas<Window>,
         port: &mut Option<Box<dyn SerialPort>>,
     ) {
         if self.serialoutput {
@@ -103,23 +369,11 @@ impl Dvg {
             if let Some(port) = port {
                 // x and y are 0 to 1024
 
-                // skip text and other nonsense
-                if y > 850 && y < 1000 {
+                // skip text, bullets and anything else configured
+                if !self.serial_filter.passes(x, y, z) {
                     return;
                 }
 
-                if x > 150 && y > 835 && x < 220 && y < 870 {
-                    return; // LIVES
-                }
-
-                if x > 395 && y > 785 && x < 640 && y < 825 {
-                    return; // PRESS START
-                }
-
-                if x > 395 && y > 120 && x < 585 && y < 145 {
-                    return; // COPYRIGHT ATARI
-                }
-
                 //let dist = (x - self.x) * (x - self.x) + (y - self.y) * (y - self.y);
 
                 //let mut c = z;
@@ -129,80 +383,45 @@ impl Dvg {
                 //    println!("Long");
                 //}
 
-                if z == 15 {
-                    // skip drawing bullets
-                    return;
-                }
-
                 let a = x as u16;
                 let b = y as u16;
                 let out = [z as u8, (a >> 8) as u8, a as u8, (b >> 8) as u8, b as u8];
-                port.write(&out).ok();
-
-                sleep(Duration::from_micros(50));
+                self.packet.extend_from_slice(&out);
 
                 //println!(
                 //    "Sending: {},{},{},{},{}",
                 //    out[0], out[1], out[2], out[3], out[4]
                 //);
 
-                // dont packetize. USB bandwith is not the limit, i2c execution is
-
-                /*let i = self.packetidx as usize;
-                self.packet[i] = z as u8;
-                self.packet[i + 1] = (a >> 8) as u8;
-                self.packet[i + 2] = a as u8;
-                self.packet[i + 3] = (b >> 8) as u8;
-                self.packet[i + 4] = b as u8;
-                self.packetidx += 5;
-                if (self.packetidx >= 60) {
-                    port.write(&self.packet).ok();
-                    self.packetidx = 0;
-                }*/
+                // by default each command is written on its own, as the
+                // receiver's i2c execution rather than USB bandwidth is the
+                // limit. receivers that keep up can take packets
+                if self.packet.len() >= self.packet_vectors * out.len() {
+                    self.write_packet(port);
+                }
             }
         }
     }
 
-    fn line(&mut self, x: i16, y: i16, z: u16, canvas: &mut Canvas<Window>) {
+    fn line<R: RenderTarget>(&self, segment: &Segment, canvas: &mut R) {
+        let (x, y, z) = (segment.x1, segment.y1, segment.z);
         if z != 0 {
-            let mut color = pixels::Color::RGBA(255, 255, 255, z as u8 * 17);
+            let color = self.apply_gamma(self.colors.color(z));
 
-            match z {
-                7 => {
-                    color = pixels::Color::RGBA(255, 0, 0, 255);
-                }
-                8 => {
-                    color = pixels::Color::RGBA(0, 255, 0, 255);
-                }
-                9 => {
-                    color = pixels::Color::RGBA(0, 0, 255, 255);
-                }
-                10 => {
-                    color = pixels::Color::RGBA(255, 255, 0, 255);
-                }
-                11 => {
-                    color = pixels::Color::RGBA(255, 0, 255, 255);
-                }
-                12 => {
-                    color = pixels::Color::RGBA(0, 255, 255, 255);
-                }
-                _ => {}
-            }
-
-            let (w, h) = canvas.output_size().unwrap();
+            let (w, h) = canvas.size();
 
-            if x == self.x && y == self.y {
+            if x == segment.x0 && y == segment.y0 {
                 // on the vector display, a single point can be extremely
                 // bright. we can't do that so we just go bigger.
-                let _ = canvas.filled_circle(Dvg::screen_x(x, w), Dvg::screen_y(y, h), 2, color);
+                canvas.draw_point(Dvg::screen_x(x, w), Dvg::screen_y(y, h), 2, color);
             } else {
-                let _ = canvas.line(
-                    Dvg::screen_x(x, w),
-                    Dvg::screen_y(y, h),
-                    Dvg::screen_x(self.x, w),
-                    Dvg::screen_y(self.y, h),
-                    color,
-                );
+                let (x0, y0) = (Dvg::screen_x(x, w), Dvg::screen_y(y, h));
+                let x1 = Dvg::screen_x(segment.x0, w);
+                let y1 = Dvg::screen_y(segment.y0, h);
+                match self.colors.width(z) {
+                    1 => canvas.draw_line(x0, y0, x1, y1, color),
+                    width => canvas.draw_thick_line(x0, y0, x1, y1, width, color),
+                }
 
                 /*let _ = canvas.rectangle(
                     Dvg::screen_x(150, w),
@@ -221,55 +440,306 @@ impl Dvg {
                 );*/
             }
         }
-        self.x = x;
-        self.y = y;
     }
 
-    fn shift(a: u16, s: i16) -> u16 {
-        if s >= 0 {
-            a >> s
+    // the DVG adds the global scale to the instruction's own in 4 bits,
+    // wrapping, and divides the 10 bit magnitude by 2 to the power of 9 less
+    // the total. nothing is ever multiplied: a total of 10 to 15 doesn't
+    // move the beam at all. the wrapping is how a global scale of 8 to 15
+    // makes vectors smaller rather than bigger
+    fn scale_delta(magnitude: u16, negative: bool, scale: u16) -> i16 {
+        let scale = scale & 0xF;
+        if scale > 9 {
+            return 0;
+        }
+        let delta = (magnitude & 0x3FF) >> (9 - scale);
+        if negative {
+            -(delta as i16)
         } else {
-            a << (-s)
+            delta as i16
         }
     }
 
-    pub fn render(
-        &mut self,
-        memory: &mut Memory,
-        canvas: &mut Canvas<Window>,
-        port: &mut Option<Box<dyn SerialPort>>,
-    ) {
+    // the beam movement and intensity of a VCTR, at the global scale sf
+    fn vctr_delta(op_word1: u16, op_word2: u16, sf: u16) -> (i16, i16, u16) {
+        let ys = (0x400 & op_word1) != 0;
+        let delta_y = 0x3FF & op_word1;
+        let z = (0xF000 & op_word2) >> 12;
+        let xs = (0x400 & op_word2) != 0;
+        let delta_x = 0x3FF & op_word2;
+        let scale = sf + ((op_word1 & 0xF000) >> 12);
+        let dx = Dvg::scale_delta(delta_x, xs, scale);
+        let dy = Dvg::scale_delta(delta_y, ys, scale);
+        (dx, dy, z)
+    }
+
+    // the beam movement and intensity of an SVEC, at the global scale sf
+    fn svec_delta(op_word1: u16, sf: u16) -> (i16, i16, u16) {
+        let svec_sf = ((op_word1 & 0x800) >> 11) + ((op_word1 & 0x8) >> 2);
+        let ys = (op_word1 & 0x400) != 0;
+        let delta_y = op_word1 & 0x300;
+        let xs = (op_word1 & 0x4) != 0;
+        let delta_x = (op_word1 & 0x3) << 8;
+        let z = (op_word1 & 0xF0) >> 4;
+        let scale = sf + 2 + svec_sf;
+        let dx = Dvg::scale_delta(delta_x, xs, scale);
+        let dy = Dvg::scale_delta(delta_y, ys, scale);
+        (dx, dy, z)
+    }
+
+    // the position a LABS moves the beam to and the global scale it sets
+    fn labs_position(op_word1: u16, op_word2: u16) -> (i16, i16, u16) {
+        let ys = (0x400 & op_word1) != 0;
+        let y = 0x3FF & op_word1;
+        let xs = (0x400 & op_word2) != 0;
+        let x = 0x3FF & op_word2;
+        let sf = (op_word2 & 0xF000) >> 12;
+        let y = if ys {
+            0 - ((y ^ 0x3FF) + 1) as i16
+        } else {
+            y as i16
+        };
+        let x = if xs {
+            0 - ((x ^ 0x3FF) + 1) as i16
+        } else {
+            x as i16
+        };
+        (x, y, sf)
+    }
+
+    // a line per instruction the DVG would execute from the start of the
+    // display list, following jumps and subroutines through to HALT. the
+    // vector deltas are given after scaling, as the beam moves. nothing is
+    // drawn and the DVG's own state is left alone
+    pub fn disassemble(memory: &Memory) -> Vec<String> {
+        let mut dvg = Dvg::new(false, false, ColorScheme::monochrome());
+        dvg.reset();
+        let mut lines = Vec::new();
+        while lines.len() < MAX_DISPLAY_LIST_INSTRUCTIONS {
+            let pc = dvg.pc;
+            let op_word1 = dvg.load_from_pc(memory);
+            let op = Dvg::instruction_from_word(op_word1);
+            // set where the DVG would stop, one way or another
+            let mut stopped = false;
+            let (words, text) = match op {
+                Instruction::VCTR => {
+                    let op_word2 = dvg.load_from_pc(memory);
+                    let (dx, dy, z) = Dvg::vctr_delta(op_word1, op_word2, dvg.sf);
+                    (
+                        format!("{:04X} {:04X}", op_word1, op_word2),
+                        format!("VCTR scale {} dx {} dy {} z {}", op_word1 >> 12, dx, dy, z),
+                    )
+                }
+                Instruction::LABS => {
+                    let op_word2 = dvg.load_from_pc(memory);
+                    let (x, y, sf) = Dvg::labs_position(op_word1, op_word2);
+                    dvg.sf = sf;
+                    (
+                        format!("{:04X} {:04X}", op_word1, op_word2),
+                        format!("LABS x {} y {} global scale {}", x, y, sf),
+                    )
+                }
+                Instruction::SVEC => {
+                    let (dx, dy, z) = Dvg::svec_delta(op_word1, dvg.sf);
+                    let scale = ((op_word1 & 0x800) >> 11) + ((op_word1 & 0x8) >> 2);
+                    (
+                        format!("{:04X}", op_word1),
+                        format!("SVEC scale {} dx {} dy {} z {}", scale, dx, dy, z),
+                    )
+                }
+                Instruction::HALT => {
+                    stopped = true;
+                    (format!("{:04X}", op_word1), "HALT".to_string())
+                }
+                Instruction::JSRL if dvg.sp > 3 => {
+                    stopped = true;
+                    (format!("{:04X}", op_word1), "JSRL stack overflow".to_string())
+                }
+                Instruction::JSRL => {
+                    dvg.stack[dvg.sp] = dvg.pc;
+                    dvg.sp += 1;
+                    dvg.pc = op_word1 & 0xFFF;
+                    (format!("{:04X}", op_word1), format!("JSRL {:03X}", dvg.pc))
+                }
+                Instruction::RTSL if dvg.sp == 0 => {
+                    stopped = true;
+                    (format!("{:04X}", op_word1), "RTSL stack underflow".to_string())
+                }
+                Instruction::RTSL => {
+                    dvg.sp -= 1;
+                    dvg.pc = dvg.stack[dvg.sp];
+                    (format!("{:04X}", op_word1), format!("RTSL to {:03X}", dvg.pc))
+                }
+                Instruction::JMPL => {
+                    dvg.pc = op_word1 & 0xFFF;
+                    (format!("{:04X}", op_word1), format!("JMPL {:03X}", dvg.pc))
+                }
+            };
+            lines.push(format!("{:03X}  {:<9}  {}", pc, words, text));
+            if stopped {
+                return lines;
+            }
+        }
+        lines.push(format!("no HALT in {} instructions", MAX_DISPLAY_LIST_INSTRUCTIONS));
+        lines
+    }
+
+    // run the display list through to HALT, collecting every beam movement,
+    // blanked moves included, with the geometry scale and cocktail flip
+    // applied. this is the frame everything else works from: the window,
+    // the serial display, the WebSocket clients and the SVG export. it needs
+    // nothing but the memory, so it's also the way in for other tools
+    pub fn collect_frame(&mut self, memory: &mut Memory) -> Vec<Segment> {
         memory.mapped_io.halt = 0xFF;
         memory.mapped_io.godvg = 0;
         self.reset();
-        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
-        canvas.clear();
+        let flip = self.cocktail && memory.peek(CURRENT_PLAYER) != 0;
+        let mut segments = Vec::new();
+        let mut instructions = 0;
         while memory.mapped_io.halt != 0 {
-            self.execute_instruction(memory, canvas, port);
+            if instructions == MAX_DISPLAY_LIST_INSTRUCTIONS {
+                self.frame_error(
+                    &format!("DVG display list without HALT in {} instructions", instructions),
+                    memory,
+                );
+                break;
+            }
+            instructions += 1;
+            if let Some(segment) = self.execute_instruction(memory) {
+                let segment = self.scale_segment(segment);
+                segments.push(if flip { Dvg::flip_segment(segment) } else { segment });
+            }
         }
-        canvas.present();
-        /*self.send_command(0, 95, 0, canvas, port);
-        self.send_command(0, 95, 12, canvas, port);
-        self.send_command(1023, 95, 12, canvas, port);
-        self.send_command(1023, 928, 12, canvas, port);
-        self.send_command(0, 928, 12, canvas, port);
-        self.send_command(0, 95, 12, canvas, port);
-        self.send_command(512, 512, 0, canvas, port);*/
-        self.send_command(0, 0, 0, canvas, port);
-        self.send_command(0, 0, 11, canvas, port);
-        self.send_command(1023, 0, 11, canvas, port);
-        self.send_command(1023, 1023, 11, canvas, port);
-        self.send_command(0, 1023, 11, canvas, port);
-        self.send_command(0, 0, 11, canvas, port);
-        self.send_command(512, 512, 0, canvas, port);
-    }
-
-    fn execute_instruction(
+        segments
+    }
+
+    // turn a segment through 180 degrees about the centre of the screen
+    fn flip_segment(segment: Segment) -> Segment {
+        Segment {
+            x0: 1023i16.saturating_sub(segment.x0),
+            y0: 1023i16.saturating_sub(segment.y0),
+            x1: 1023i16.saturating_sub(segment.x1),
+            y1: 1023i16.saturating_sub(segment.y1),
+            ..segment
+        }
+    }
+
+    // apply the overall geometry scale, about the centre of the screen
+    fn scale_segment(&self, segment: Segment) -> Segment {
+        if self.geometry_scale == 1.0 {
+            return segment;
+        }
+        let scale = |value: i16| {
+            let scaled = (value as f64 - 512.0) * self.geometry_scale + 512.0;
+            scaled
+                .round()
+                .max(i16::min_value() as f64)
+                .min(i16::max_value() as f64) as i16
+        };
+        Segment {
+            x0: scale(segment.x0),
+            y0: scale(segment.y0),
+            x1: scale(segment.x1),
+            y1: scale(segment.y1),
+            ..segment
+        }
+    }
+
+    pub fn render<R: RenderTarget>(
         &mut self,
         memory: &mut Memory,
-        canvas: &mut Canvas<Window>,
+        canvas: &mut R,
         port: &mut Option<Box<dyn SerialPort>>,
+        sinks: &mut [Box<dyn VectorSink>],
     ) {
+        let segments = self.collect_frame(memory);
+        for sink in sinks.iter_mut() {
+            sink.send_frame(&segments);
+        }
+        for (i, segment) in segments.iter().enumerate() {
+            self.send_command(segment.x1, segment.y1, segment.z, port);
+            if let Some(step) = self.beam_trace {
+                if (i + 1) % step == 0 {
+                    self.trace_beam(&segments[..i + 1], canvas);
+                }
+            }
+        }
+        self.draw(&segments, canvas);
+        if self.debug_mode {
+            self.last_frame = segments;
+        }
+        /*self.send_command(0, 95, 0, port);
+        self.send_command(0, 95, 12, port);
+        self.send_command(1023, 95, 12, port);
+        self.send_command(1023, 928, 12, port);
+        self.send_command(0, 928, 12, port);
+        self.send_command(0, 95, 12, port);
+        self.send_command(512, 512, 0, port);*/
+        self.send_command(0, 0, 0, port);
+        self.send_command(0, 0, 11, port);
+        self.send_command(1023, 0, 11, port);
+        self.send_command(1023, 1023, 11, port);
+        self.send_command(0, 1023, 11, port);
+        self.send_command(0, 0, 11, port);
+        self.send_command(512, 512, 0, port);
+        self.flush_packet(port);
+        self.send_frame_sync(port);
+    }
+
+    // the picture the display list in memory draws, as an SVG document 1024
+    // units square. nothing is sent to the serial display
+    pub fn render_to_svg(&mut self, memory: &mut Memory) -> String {
+        let mut target = SvgTarget::new(1024, 1024);
+        self.render_offscreen(memory, &mut target);
+        target.finish()
+    }
+
+    // draw the display list in memory again on another target, without
+    // sending it anywhere
+    pub fn render_offscreen<R: RenderTarget>(&mut self, memory: &mut Memory, target: &mut R) {
+        let segments = self.collect_frame(memory);
+        self.draw(&segments, target);
+    }
+
+    fn draw<R: RenderTarget>(&self, segments: &[Segment], canvas: &mut R) {
+        canvas.clear();
+        for segment in segments.iter().filter(|segment| self.on_screen(segment)) {
+            self.line(segment, canvas);
+        }
+    }
+
+    // show the frame drawn so far with the beam as a bright dot
+    fn trace_beam<R: RenderTarget>(&self, segments: &[Segment], canvas: &mut R) {
+        self.draw(segments, canvas);
+        if let Some(segment) = segments.last() {
+            let (w, h) = canvas.size();
+            canvas.draw_point(
+                Dvg::screen_x(segment.x1, w),
+                Dvg::screen_y(segment.y1, h),
+                4,
+                pixels::Color::RGB(255, 255, 255),
+            );
+        }
+        canvas.present();
+        sleep(BEAM_TRACE_DELAY);
+    }
+
+    // move the beam to x, y returning the resulting segment
+    fn move_beam(&mut self, x: i16, y: i16, z: u16, pc: u16) -> Segment {
+        let segment = Segment {
+            x0: self.x,
+            y0: self.y,
+            x1: x,
+            y1: y,
+            z,
+            pc,
+        };
+        self.x = x;
+        self.y = y;
+        segment
+    }
+
+    fn execute_instruction(&mut self, memory: &mut Memory) -> Option<Segment> {
         let instr_addr = self.pc;
         let op_word1 = self.load_from_pc(memory);
         let op = Dvg::instruction_from_word(op_word1);
@@ -294,79 +764,122 @@ impl Dvg {
         }
         match op {
             Instruction::VCTR => {
-                let ys = (0x400 & op_word1) != 0;
-                let delta_y = 0x3FF & op_word1;
-                let z = (0xF000 & op_word2) >> 12;
-                let xs = (0x400 & op_word2) != 0;
-                let delta_x = 0x3FF & op_word2;
-                let shift_bits = 9 - ((op_word1 & 0xF000) >> 12) as i16 + self.sf;
-                let x = self.x + Dvg::shift(delta_x, shift_bits) as i16 * if xs { -1 } else { 1 };
-                let y = self.y + Dvg::shift(delta_y, shift_bits) as i16 * if ys { -1 } else { 1 };
-                self.send_command(x, y, z, canvas, port);
-                self.line(x, y, z, canvas);
+                // word 1: OOOO -YYY YYYY YYYY  word 2: ZZZZ -XXX XXXX XXXX
+                // O is the opcode, which doubles as the vector's scale
+                // (0 - 9), Z the intensity and X/Y sign/magnitude deltas
+                let (dx, dy, z) = Dvg::vctr_delta(op_word1, op_word2, self.sf);
+                Some(self.move_beam(self.x + dx, self.y + dy, z, instr_addr))
             }
             Instruction::LABS => {
                 // CUR
-                let ys = (0x400 & op_word1) != 0;
-                let y = 0x3FF & op_word1;
-                let xs = (0x400 & op_word2) != 0;
-                let x = 0x3FF & op_word2;
-                let sf = (op_word2 & 0xF000) >> 12;
-                self.sf = if sf & 0x8 == 0 {
-                    -(sf as i16)
-                } else {
-                    16 - sf as i16
-                };
-                self.y = if ys {
-                    0 - ((y ^ 0x3FF) + 1) as i16
-                } else {
-                    y as i16
-                };
-                self.x = if xs {
-                    0 - ((x ^ 0x3FF) + 1) as i16
-                } else {
-                    x as i16
-                };
-
-                self.send_command(self.x, self.y, 0, canvas, port);
+                let (x, y, sf) = Dvg::labs_position(op_word1, op_word2);
+                self.sf = sf;
+                Some(self.move_beam(x, y, 0, instr_addr))
             }
             Instruction::HALT => {
                 memory.mapped_io.halt = 0;
+                None
             }
             Instruction::JSRL => {
                 if self.sp > 3 {
-                    panic!("DVG stack overflow");
+                    self.frame_error("DVG stack overflow", memory);
+                    return None;
                 }
                 let addr = op_word1 & 0xFFF;
                 self.stack[self.sp] = self.pc;
                 self.sp += 1;
                 self.pc = addr;
+                None
             }
             Instruction::RTSL => {
                 if self.sp == 0 {
-                    panic!("DVG stack underflow");
+                    self.frame_error("DVG stack underflow", memory);
+                    return None;
                 }
                 self.sp -= 1;
                 self.pc = self.stack[self.sp];
+                None
             }
             Instruction::JMPL => {
                 let a = op_word1 & 0xFFF;
                 self.pc = a;
+                None
             }
             Instruction::SVEC => {
-                let sf = ((op_word1 & 0x800) >> 11) + ((op_word1 & 0x8) >> 2);
-                let ys = (op_word1 & 0x400) != 0;
-                let delta_y = op_word1 & 0x300;
-                let xs = (op_word1 & 0x4) != 0;
-                let delta_x = (op_word1 & 0x3) << 8;
-                let z = (op_word1 & 0xF0) >> 4;
-
-                let shift_bits = (7 - sf as i16) + self.sf;
-                let x = self.x + Dvg::shift(delta_x, shift_bits) as i16 * if xs { -1 } else { 1 };
-                let y = self.y + Dvg::shift(delta_y, shift_bits) as i16 * if ys { -1 } else { 1 };
-                self.send_command(x, y, z, canvas, port);
-                self.line(x, y, z, canvas);
+                // 1111 SYyy ZZZZ TXxx
+                // S and T are bits 0 and 1 of the scale, Y and X the signs,
+                // yy and xx the top two bits of otherwise 10 bit magnitudes
+                // and Z the intensity. a short vector scale of 0 - 3 is the
+                // same as a VCTR opcode of 2 - 5
+                let (dx, dy, z) = Dvg::svec_delta(op_word1, self.sf);
+                Some(self.move_beam(self.x + dx, self.y + dy, z, instr_addr))
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vctr_scaling() {
+        // global scale, opcode, the deltas' magnitudes with Y negative, and
+        // the beam movement that comes out
+        let cases = [
+            (0, 9, 0x3FF, 100, (1023, -100)),
+            (0, 8, 100, 100, (50, -50)),
+            (0, 0, 0x200, 0x3FF, (1, -1)),
+            (1, 8, 100, 100, (100, -100)),
+            // the scale is 4 bits, so 10 and up don't move the beam and a
+            // global scale of 15 takes one off
+            (1, 9, 100, 100, (0, 0)),
+            (15, 9, 100, 100, (50, -50)),
+            (7, 9, 0x200, 0x200, (1, -1)),
+        ];
+        for &(sf, op, x, y, (dx, dy)) in &cases {
+            let word1 = (op << 12) | 0x400 | y;
+            let word2 = 0xC000 | x;
+            assert_eq!(Dvg::vctr_delta(word1, word2, sf), (dx, dy, 12), "sf {} op {}", sf, op);
+        }
+    }
+
+    #[test]
+    fn svec_scaling() {
+        // the largest short vector scale, 1 right at intensity 12, which is
+        // a VCTR scale of 5
+        assert_eq!(Dvg::svec_delta(0xF8C9, 0), (16, 0, 12));
+        assert_eq!(Dvg::svec_delta(0xF8C9, 4), (256, 0, 12));
+        assert_eq!(Dvg::svec_delta(0xF8C9, 5), (0, 0, 12));
+        // the smallest scale, a step down
+        assert_eq!(Dvg::svec_delta(0xF5C0, 0), (0, -2, 12));
+    }
+
+    #[test]
+    fn collect_frame_stops_at_halt() {
+        let mut memory = Memory::blank();
+        // LABS to 200, 100, a VCTR up 50 and right 100 at intensity 12 and
+        // full scale, HALT, then a VCTR that's never reached
+        let program = Dvg::parse_program("A064 00C8  9032 C064  B000  9032 C064").unwrap();
+        Dvg::load_program(&mut memory, &program);
+        let mut dvg = Dvg::new(false, false, ColorScheme::classic());
+        let segments = dvg.collect_frame(&mut memory);
+        let lines: Vec<(i16, i16, i16, i16, u16)> = segments
+            .iter()
+            .map(|segment| (segment.x0, segment.y0, segment.x1, segment.y1, segment.z))
+            .collect();
+        assert_eq!(lines, vec![(0, 0, 200, 100, 0), (200, 100, 300, 150, 12)]);
+        assert_eq!(memory.mapped_io.halt, 0);
+    }
+
+    #[test]
+    fn describe_instruction_decodes_vectors() {
+        let mut memory = Memory::blank();
+        let program = Dvg::parse_program("A064 00C8  9032 C064  B000").unwrap();
+        Dvg::load_program(&mut memory, &program);
+        assert_eq!(
+            Dvg::describe_instruction(&memory, 3),
+            "003  9032 C064  VCTR scale 9 dx 100 dy 50 z 12"
+        );
     }
 }
@@ -0,0 +1,140 @@
+// on-screen hex editor for poking at memory while the game runs
+
+use memory::Memory;
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const BYTES_PER_ROW: u16 = 16;
+const ROWS: u16 = 16;
+const CHAR_SIZE: i16 = 8; // the gfx built in font is 8x8
+const MARGIN: i16 = 8;
+
+pub struct MemoryEditor {
+    pub active: bool,
+    cursor: u16,
+    top: u16,
+    high_nibble: Option<u8>, // first digit typed for the byte under the cursor
+}
+
+impl MemoryEditor {
+    pub fn new() -> MemoryEditor {
+        MemoryEditor {
+            active: false,
+            cursor: 0,
+            top: 0,
+            high_nibble: None,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+        self.high_nibble = None;
+    }
+
+    fn hex_digit(keycode: Keycode) -> Option<u8> {
+        match keycode {
+            Keycode::Num0 | Keycode::Kp0 => Some(0x0),
+            Keycode::Num1 | Keycode::Kp1 => Some(0x1),
+            Keycode::Num2 | Keycode::Kp2 => Some(0x2),
+            Keycode::Num3 | Keycode::Kp3 => Some(0x3),
+            Keycode::Num4 | Keycode::Kp4 => Some(0x4),
+            Keycode::Num5 | Keycode::Kp5 => Some(0x5),
+            Keycode::Num6 | Keycode::Kp6 => Some(0x6),
+            Keycode::Num7 | Keycode::Kp7 => Some(0x7),
+            Keycode::Num8 | Keycode::Kp8 => Some(0x8),
+            Keycode::Num9 | Keycode::Kp9 => Some(0x9),
+            Keycode::A => Some(0xA),
+            Keycode::B => Some(0xB),
+            Keycode::C => Some(0xC),
+            Keycode::D => Some(0xD),
+            Keycode::E => Some(0xE),
+            Keycode::F => Some(0xF),
+            _ => None,
+        }
+    }
+
+    fn move_cursor(&mut self, delta: i32) {
+        self.cursor = (self.cursor as i32 + delta) as u16;
+        self.high_nibble = None;
+        // keep the cursor's row in view
+        let row = self.cursor & !(BYTES_PER_ROW - 1);
+        let visible = BYTES_PER_ROW * ROWS;
+        if row.wrapping_sub(self.top) >= visible {
+            self.top = if self.cursor < self.top {
+                row
+            } else {
+                row.wrapping_sub(visible - BYTES_PER_ROW)
+            };
+        }
+    }
+
+    // while the editor is open it takes all the key presses. edits go
+    // through set_byte so only writable locations actually change
+    pub fn handle_key(&mut self, keycode: Keycode, memory: &mut Memory) {
+        match keycode {
+            Keycode::Left => self.move_cursor(-1),
+            Keycode::Right => self.move_cursor(1),
+            Keycode::Up => self.move_cursor(-(BYTES_PER_ROW as i32)),
+            Keycode::Down => self.move_cursor(BYTES_PER_ROW as i32),
+            Keycode::PageUp => self.move_cursor(-((BYTES_PER_ROW * ROWS) as i32)),
+            Keycode::PageDown => self.move_cursor((BYTES_PER_ROW * ROWS) as i32),
+            Keycode::Backspace => self.high_nibble = None,
+            _ => {
+                if let Some(digit) = MemoryEditor::hex_digit(keycode) {
+                    match self.high_nibble {
+                        None => self.high_nibble = Some(digit),
+                        Some(high) => {
+                            memory.set_byte(self.cursor, (high << 4) | digit);
+                            self.move_cursor(1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas<Window>, memory: &Memory) {
+        if !self.active {
+            return;
+        }
+        let text = pixels::Color::RGBA(0, 255, 0, 255);
+        let highlight = pixels::Color::RGBA(255, 255, 0, 255);
+        let line_chars = 6 + 3 * BYTES_PER_ROW as i16;
+        let _ = canvas.box_(
+            0,
+            0,
+            MARGIN * 2 + line_chars * CHAR_SIZE,
+            MARGIN * 2 + (ROWS as i16 + 1) * CHAR_SIZE * 2,
+            pixels::Color::RGBA(0, 0, 0, 200),
+        );
+        for row in 0..ROWS {
+            let addr = self.top.wrapping_add(row * BYTES_PER_ROW);
+            let y = MARGIN + row as i16 * CHAR_SIZE * 2;
+            let bytes = memory.dump(addr, BYTES_PER_ROW as usize);
+            let _ = canvas.string(MARGIN, y, &format!("{:04X}:", addr), text);
+            for (col, &byte) in (0..BYTES_PER_ROW).zip(bytes.iter()) {
+                let byte_addr = addr.wrapping_add(col);
+                let x = MARGIN + (6 + 3 * col as i16) * CHAR_SIZE;
+                let (value, color) = if byte_addr == self.cursor {
+                    let value = match self.high_nibble {
+                        Some(high) => format!("{:X}_", high),
+                        None => format!("{:02X}", byte),
+                    };
+                    (value, highlight)
+                } else {
+                    (format!("{:02X}", byte), text)
+                };
+                let _ = canvas.string(x, y, &value, color);
+            }
+        }
+        let _ = canvas.string(
+            MARGIN,
+            MARGIN + ROWS as i16 * CHAR_SIZE * 2,
+            "arrows/pgup/pgdn move, hex digits edit, F2 close",
+            text,
+        );
+    }
+}
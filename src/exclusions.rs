@@ -0,0 +1,285 @@
+// what isn't sent to the serial display: areas of the screen, such as the
+// score text, and intensities, such as the bullets. also a tuner for
+// adjusting the areas by eye
+
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use display::Dvg;
+
+const NUDGE: i16 = 5; // DVG units moved per key press
+
+// the game draws the ship's and saucer's shots as dots at full intensity (15).
+// nothing else uses that level, so by default we don't send them to the
+// physical display, which struggles to draw them cleanly
+pub const BULLET_INTENSITY: u16 = 15;
+
+// a rectangle in DVG coordinates (0 to 1023, y upwards). beam positions
+// strictly inside it are excluded
+#[derive(Debug, Clone)]
+pub struct ExclusionRect {
+    pub name: String,
+    pub x0: i16,
+    pub y0: i16,
+    pub x1: i16,
+    pub y1: i16,
+}
+
+impl ExclusionRect {
+    fn new(name: &str, x0: i16, y0: i16, x1: i16, y1: i16) -> ExclusionRect {
+        ExclusionRect {
+            name: name.to_string(),
+            x0,
+            y0,
+            x1,
+            y1,
+        }
+    }
+
+    pub fn contains(&self, x: i16, y: i16) -> bool {
+        x > self.x0 && y > self.y0 && x < self.x1 && y < self.y1
+    }
+
+    // what suits the display this was first written for
+    pub fn defaults() -> Vec<ExclusionRect> {
+        vec![
+            ExclusionRect::new("TEXT", -1, 850, 1024, 1000),
+            ExclusionRect::new("LIVES", 150, 835, 220, 870),
+            ExclusionRect::new("PRESS START", 395, 785, 640, 825),
+            ExclusionRect::new("COPYRIGHT ATARI", 395, 120, 585, 145),
+        ]
+    }
+
+    // lines of "x0 y0 x1 y1 [name]", with # starting a comment
+    pub fn parse(text: &str) -> Result<Vec<ExclusionRect>, String> {
+        let mut rects = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = code.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let values: Result<Vec<i16>, _> =
+                fields.iter().take(4).map(|f| f.parse::<i16>()).collect();
+            match values {
+                Ok(ref v) if v.len() == 4 => {
+                    let name = fields[4..].join(" ");
+                    rects.push(ExclusionRect::new(&name, v[0], v[1], v[2], v[3]));
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid exclusion rectangle on line {}: '{}'",
+                        number + 1,
+                        line
+                    ))
+                }
+            }
+        }
+        Ok(rects)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Vec<ExclusionRect>, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        ExclusionRect::parse(&text)
+    }
+}
+
+// in the same format parse reads
+impl fmt::Display for ExclusionRect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} {}", self.x0, self.y0, self.x1, self.y1, self.name)
+    }
+}
+
+// decides which beam positions go to the serial display. the defaults suit
+// the display this was first written for
+#[derive(Debug, Clone)]
+pub struct SerialFilter {
+    pub exclusions: Vec<ExclusionRect>,
+    skip_z: u16, // bit n set means skip intensity n
+}
+
+impl SerialFilter {
+    pub fn new() -> SerialFilter {
+        SerialFilter {
+            exclusions: ExclusionRect::defaults(),
+            skip_z: 1 << BULLET_INTENSITY,
+        }
+    }
+
+    // choose whether vectors of intensity z are sent
+    pub fn set_z_skipped(&mut self, z: u16, skipped: bool) {
+        let mask = 1 << (z & 0xF);
+        if skipped {
+            self.skip_z |= mask;
+        } else {
+            self.skip_z &= !mask;
+        }
+    }
+
+    pub fn z_skipped(&self, z: u16) -> bool {
+        self.skip_z & (1 << (z & 0xF)) != 0
+    }
+
+    // returns whether bullets are now skipped
+    pub fn toggle_bullets(&mut self) -> bool {
+        let skipped = !self.z_skipped(BULLET_INTENSITY);
+        self.set_z_skipped(BULLET_INTENSITY, skipped);
+        skipped
+    }
+
+    // whether the beam moving to x, y at intensity z is sent
+    pub fn passes(&self, x: i16, y: i16, z: u16) -> bool {
+        !self.z_skipped(z) && !self.exclusions.iter().any(|rect| rect.contains(x, y))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edge {
+    Left,
+    Bottom,
+    Right,
+    Top,
+}
+
+impl Edge {
+    fn next(self) -> Edge {
+        match self {
+            Edge::Left => Edge::Bottom,
+            Edge::Bottom => Edge::Right,
+            Edge::Right => Edge::Top,
+            Edge::Top => Edge::Left,
+        }
+    }
+}
+
+pub struct ExclusionTuner {
+    pub active: bool,
+    selected: usize,
+    edge: Edge,
+}
+
+impl ExclusionTuner {
+    pub fn new() -> ExclusionTuner {
+        ExclusionTuner {
+            active: false,
+            selected: 0,
+            edge: Edge::Left,
+        }
+    }
+
+    // leaving the tuner prints the result, ready to save for --serial-exclusions
+    pub fn toggle(&mut self, rects: &[ExclusionRect]) {
+        self.active = !self.active;
+        if !self.active {
+            ExclusionTuner::print(rects);
+        }
+    }
+
+    fn print(rects: &[ExclusionRect]) {
+        println!("# serial exclusion rectangles: x0 y0 x1 y1 name");
+        for rect in rects {
+            println!("{}", rect);
+        }
+    }
+
+    // tab picks the rectangle, space the edge and the arrows move it
+    pub fn handle_key(&mut self, keycode: Keycode, rects: &mut [ExclusionRect]) {
+        if rects.is_empty() {
+            return;
+        }
+        self.selected %= rects.len();
+        let delta = match keycode {
+            Keycode::Tab => {
+                self.selected = (self.selected + 1) % rects.len();
+                0
+            }
+            Keycode::Space => {
+                self.edge = self.edge.next();
+                0
+            }
+            Keycode::Return => {
+                ExclusionTuner::print(rects);
+                0
+            }
+            Keycode::Left | Keycode::Down => -NUDGE,
+            Keycode::Right | Keycode::Up => NUDGE,
+            _ => 0,
+        };
+        if delta != 0 {
+            let rect = &mut rects[self.selected];
+            match self.edge {
+                Edge::Left => rect.x0 += delta,
+                Edge::Bottom => rect.y0 += delta,
+                Edge::Right => rect.x1 += delta,
+                Edge::Top => rect.y1 += delta,
+            }
+            println!("{}", rect);
+        }
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas<Window>, rects: &[ExclusionRect]) {
+        if !self.active {
+            return;
+        }
+        let normal = pixels::Color::RGBA(0, 128, 255, 255);
+        let selected = pixels::Color::RGBA(255, 255, 0, 255);
+        let edge = pixels::Color::RGBA(255, 0, 0, 255);
+        let viewport = canvas.viewport();
+        let (w, h) = (viewport.width(), viewport.height());
+        for (i, rect) in rects.iter().enumerate() {
+            let left = Dvg::screen_x(rect.x0, w);
+            let right = Dvg::screen_x(rect.x1, w);
+            let bottom = Dvg::screen_y(rect.y0, h);
+            let top = Dvg::screen_y(rect.y1, h);
+            if i != self.selected {
+                let _ = canvas.rectangle(left, top, right, bottom, normal);
+                continue;
+            }
+            let _ = canvas.rectangle(left, top, right, bottom, selected);
+            let _ = match self.edge {
+                Edge::Left => canvas.line(left, top, left, bottom, edge),
+                Edge::Bottom => canvas.line(left, bottom, right, bottom, edge),
+                Edge::Right => canvas.line(right, top, right, bottom, edge),
+                Edge::Top => canvas.line(left, top, right, top, edge),
+            };
+            let _ = canvas.string(left + 2, top + 2, &rect.name, selected);
+        }
+        let _ = canvas.string(
+            8,
+            h as i16 - 16,
+            "tab rectangle, space edge, arrows move, enter print, F4 close",
+            selected,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_outside_rectangles_and_skipped_intensities() {
+        let mut filter = SerialFilter::new();
+        filter.exclusions = ExclusionRect::parse("100 100 200 200 BOX").unwrap();
+        assert!(filter.passes(150, 300, 7));
+        assert!(!filter.passes(150, 150, 7));
+        // the edges aren't inside
+        assert!(filter.passes(100, 150, 7));
+        assert!(filter.passes(150, 200, 7));
+        // bullets are skipped by default, wherever they are
+        assert!(!filter.passes(150, 300, BULLET_INTENSITY));
+        assert!(!filter.toggle_bullets());
+        assert!(filter.passes(150, 300, BULLET_INTENSITY));
+        filter.set_z_skipped(7, true);
+        assert!(!filter.passes(150, 300, 7));
+        assert!(filter.passes(150, 300, 6));
+    }
+}
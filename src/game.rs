@@ -0,0 +1,67 @@
+// knowledge of where the Asteroids ROM keeps its game state in RAM, see
+// http://www.computerarcheology.com/Arcade/Asteroids/RAMUse.html
+
+use memory::Memory;
+
+pub const SHIPS_PLAYER_1: u16 = 0x0057;
+pub const SHIPS_PLAYER_2: u16 = 0x0058;
+pub const CURRENT_PLAYER: u16 = 0x0018;
+
+// each player's objects live in their own page, the ship's four shots in
+// slots 1F-22 of it. a slot is free when zero
+const PLAYER_1_SHOTS: u16 = 0x021F;
+const SHOT_SLOTS: u16 = 4;
+
+// practice cheat: whenever a player's remaining ships go down, put them back
+pub struct GodMode {
+    ships: [u8; 2],
+}
+
+impl GodMode {
+    pub fn new() -> GodMode {
+        GodMode { ships: [0; 2] }
+    }
+
+    // called once a frame
+    pub fn apply(&mut self, memory: &mut Memory) {
+        for (i, &addr) in [SHIPS_PLAYER_1, SHIPS_PLAYER_2].iter().enumerate() {
+            let ships = memory.peek(addr);
+            if ships < self.ships[i] {
+                memory.set_byte(addr, self.ships[i]);
+            } else {
+                self.ships[i] = ships;
+            }
+        }
+    }
+}
+
+// autofire that only presses fire when the game will accept a shot. the
+// ROM fires on the button going down and checks it once a frame, so the
+// fastest useful rate is pressed one frame and released the next, i.e. a
+// shot every two frames (about 30 a second). on top of that a press is only
+// made while one of the four shot slots is free, as otherwise the ROM
+// ignores it
+pub struct TurboFire {
+    pub held: bool,
+    pressed: bool,
+}
+
+impl TurboFire {
+    pub fn new() -> TurboFire {
+        TurboFire {
+            held: false,
+            pressed: false,
+        }
+    }
+
+    fn shot_slot_free(memory: &Memory) -> bool {
+        let page = if memory.peek(CURRENT_PLAYER) == 0 { 0 } else { 0x100 };
+        (0..SHOT_SLOTS).any(|i| memory.peek(PLAYER_1_SHOTS + page + i) == 0)
+    }
+
+    // called once a frame, in place of the fire key updating swfire
+    pub fn apply(&mut self, memory: &mut Memory) {
+        self.pressed = self.held && !self.pressed && TurboFire::shot_slot_free(memory);
+        memory.mapped_io.swfire = if self.pressed { 0xFF } else { 0 };
+    }
+}
@@ -0,0 +1,349 @@
+// record the picture as an animated GIF, for sharing a game without screen
+// capture software. frames are drawn again off screen at half size, a fixed
+// number of times a second of emulated time, and written out as they come,
+// with the file finished on quit
+//
+// each frame has a palette of its own holding just the colours drawn on it,
+// which for the vector display is black and a handful of intensities, so
+// nothing is lost to dithering and the LZW compression does well on the
+// mostly black picture
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use sdl2::pixels::Color;
+
+use machine::CPU_CLOCK_HZ;
+use render::{RenderTarget, PICTURE_HEIGHT, PICTURE_WIDTH};
+
+// half the size of the picture is plenty to see what's going on
+pub const GIF_WIDTH: u32 = PICTURE_WIDTH / 2;
+pub const GIF_HEIGHT: u32 = PICTURE_HEIGHT / 2;
+
+// frames a second. the game draws about 60, but a GIF that fast is huge and
+// many viewers slow it down anyway
+pub const FRAME_RATE: u64 = 20;
+
+const PALETTE_SIZE: usize = 256;
+const MIN_CODE_SIZE: u8 = 8; // bits in a palette index
+const MAX_CODE_SIZE: u8 = 12;
+const CLEAR_CODE: u16 = 1 << MIN_CODE_SIZE;
+const END_CODE: u16 = CLEAR_CODE + 1;
+const MAX_CODES: u16 = 1 << MAX_CODE_SIZE;
+
+// a picture of palette indices, with 0 always black. where lines cross the
+// last one drawn wins, so only colours that were drawn end up in the palette
+pub struct RasterTarget {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    palette: Vec<[u8; 3]>,
+}
+
+impl RasterTarget {
+    pub fn new(width: u32, height: u32) -> RasterTarget {
+        RasterTarget {
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+            palette: vec![[0, 0, 0]],
+        }
+    }
+
+    // the colour's index, adding it to the palette if there's room or
+    // settling for the closest one there is if not. the colour is blended
+    // over black
+    fn index(&mut self, color: Color) -> u8 {
+        let over_black = |value: u8| (value as u32 * color.a as u32 / 255) as u8;
+        let rgb = [over_black(color.r), over_black(color.g), over_black(color.b)];
+        if let Some(index) = self.palette.iter().position(|&entry| entry == rgb) {
+            return index as u8;
+        }
+        if self.palette.len() < PALETTE_SIZE {
+            self.palette.push(rgb);
+            return (self.palette.len() - 1) as u8;
+        }
+        let distance = |entry: &[u8; 3]| -> i32 {
+            (0..3)
+                .map(|i| (entry[i] as i32 - rgb[i] as i32).pow(2))
+                .sum()
+        };
+        (0..self.palette.len())
+            .min_by_key(|&i| distance(&self.palette[i]))
+            .unwrap_or(0) as u8
+    }
+
+    // a square of the given half width, clipped to the picture
+    fn plot(&mut self, x: i32, y: i32, half_width: i32, index: u8) {
+        for py in y - half_width..=y + half_width {
+            for px in x - half_width..=x + half_width {
+                if px >= 0 && py >= 0 && (px as u32) < self.width && (py as u32) < self.height {
+                    self.pixels[py as usize * self.width as usize + px as usize] = index;
+                }
+            }
+        }
+    }
+
+    // Bresenham's line
+    fn line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, half_width: i32, color: Color) {
+        let index = self.index(color);
+        let (mut x, mut y) = (x0 as i32, y0 as i32);
+        let (x1, y1) = (x1 as i32, y1 as i32);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut error = dx + dy;
+        loop {
+            self.plot(x, y, half_width, index);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += sx;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+}
+
+impl RenderTarget for RasterTarget {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn clear(&mut self) {
+        for pixel in self.pixels.iter_mut() {
+            *pixel = 0;
+        }
+        self.palette.truncate(1);
+    }
+
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color) {
+        self.line(x0, y0, x1, y1, 0, color);
+    }
+
+    // the picture is half size, so lines are half as thick
+    fn draw_thick_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, width: u8, color: Color) {
+        self.line(x0, y0, x1, y1, (width as i32 - 1) / 2, color);
+    }
+
+    fn draw_point(&mut self, x: i16, y: i16, radius: i16, color: Color) {
+        let index = self.index(color);
+        let radius = radius as i32;
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= radius * radius {
+                    self.plot(x as i32 + dx, y as i32 + dy, 0, index);
+                }
+            }
+        }
+    }
+
+    fn present(&mut self) {}
+}
+
+// packs variable width codes into bytes, lowest bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+// GIF's variant of LZW. the code size grows a step behind the dictionary,
+// as the decoder only learns each string one code later than the encoder,
+// and a full dictionary starts again with a clear code
+fn lzw_compress(indices: &[u8]) -> Vec<u8> {
+    let mut output = BitWriter {
+        bytes: Vec::new(),
+        buffer: 0,
+        bits: 0,
+    };
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = MIN_CODE_SIZE + 1;
+    let mut next = END_CODE + 1;
+    output.write(CLEAR_CODE, size);
+    let mut pixels = indices.iter();
+    let mut prefix = match pixels.next() {
+        Some(&index) => index as u16,
+        None => {
+            output.write(END_CODE, size);
+            return output.finish();
+        }
+    };
+    for &index in pixels {
+        if let Some(&code) = dictionary.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        output.write(prefix, size);
+        if next < MAX_CODES {
+            dictionary.insert((prefix, index), next);
+            next += 1;
+            if next == (1 << size) + 1 && size < MAX_CODE_SIZE {
+                size += 1;
+            }
+        } else {
+            output.write(CLEAR_CODE, size);
+            dictionary.clear();
+            size = MIN_CODE_SIZE + 1;
+            next = END_CODE + 1;
+        }
+        prefix = index as u16;
+    }
+    output.write(prefix, size);
+    if next == 1 << size && size < MAX_CODE_SIZE {
+        size += 1;
+    }
+    output.write(END_CODE, size);
+    output.finish()
+}
+
+// an image descriptor with its palette and pixels, in sub-blocks of at most
+// 255 bytes
+fn encode_image(raster: &RasterTarget) -> Vec<u8> {
+    let mut image = vec![0x2C, 0, 0, 0, 0];
+    image.extend_from_slice(&(raster.width as u16).to_le_bytes());
+    image.extend_from_slice(&(raster.height as u16).to_le_bytes());
+    image.push(0x80 | (MIN_CODE_SIZE - 1)); // a local palette of 256 colours
+    for i in 0..PALETTE_SIZE {
+        image.extend_from_slice(raster.palette.get(i).unwrap_or(&[0, 0, 0]));
+    }
+    image.push(MIN_CODE_SIZE);
+    for block in lzw_compress(&raster.pixels).chunks(255) {
+        image.push(block.len() as u8);
+        image.extend_from_slice(block);
+    }
+    image.push(0);
+    image
+}
+
+pub struct GifRecorder {
+    path: PathBuf,
+    file: Option<BufWriter<File>>, // None after a write error
+    raster: RasterTarget,
+    interval: u64, // cycles between frames
+    // cycles run since the first frame. loading a state or rewinding moves
+    // the CPU's count back and forth, but the recording keeps going forward
+    clock: u64,
+    last_cycle: Option<u64>,
+    next_capture: u64,
+    // the last frame taken and when, which is written when the next one is
+    // and its delay is known
+    pending: Option<(u64, Vec<u8>)>,
+    frames: u64,
+}
+
+impl GifRecorder {
+    pub fn create(path: &Path) -> Result<GifRecorder, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Error creating {}: {}", path.display(), e))?;
+        let mut file = BufWriter::new(file);
+        let mut header = b"GIF89a".to_vec();
+        header.extend_from_slice(&(GIF_WIDTH as u16).to_le_bytes());
+        header.extend_from_slice(&(GIF_HEIGHT as u16).to_le_bytes());
+        header.extend_from_slice(&[0, 0, 0]); // no global palette
+        // loop forever
+        header.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+        file.write_all(&header)
+            .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+        Ok(GifRecorder {
+            path: path.to_path_buf(),
+            file: Some(file),
+            raster: RasterTarget::new(GIF_WIDTH, GIF_HEIGHT),
+            interval: CPU_CLOCK_HZ / FRAME_RATE,
+            clock: 0,
+            last_cycle: None,
+            next_capture: 0,
+            pending: None,
+            frames: 0,
+        })
+    }
+
+    // write the pending frame, shown until the given time. GIF delays are
+    // in hundredths of a second, and working them out from the start keeps
+    // the rounding from adding up
+    fn write_pending(&mut self, until: u64) {
+        let (shown, image) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let centiseconds = |clock: u64| clock * 100 / CPU_CLOCK_HZ;
+        let delay = centiseconds(until) - centiseconds(shown);
+        let delay = delay.max(1).min(u16::max_value() as u64) as u16;
+        // a graphic control extension with the delay, then the image
+        let mut frame = vec![0x21, 0xF9, 0x04, 0x00];
+        frame.extend_from_slice(&delay.to_le_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(&image);
+        let written = match self.file {
+            Some(ref mut file) => file.write_all(&frame),
+            None => return,
+        };
+        match written {
+            Ok(()) => self.frames += 1,
+            Err(e) => {
+                println!("Error writing {}, stopping the recording: {}", self.path.display(), e);
+                self.file = None;
+            }
+        }
+    }
+
+    // called as each frame is drawn. once a frame's worth of time has gone
+    // by, draw is given the target to draw the frame on again
+    pub fn capture<F: FnOnce(&mut RasterTarget)>(&mut self, cycle: u64, draw: F) {
+        if let Some(last_cycle) = self.last_cycle {
+            self.clock += cycle.saturating_sub(last_cycle);
+        }
+        self.last_cycle = Some(cycle);
+        if self.file.is_none() || self.clock < self.next_capture {
+            return;
+        }
+        draw(&mut self.raster);
+        let clock = self.clock;
+        self.write_pending(clock);
+        self.pending = Some((clock, encode_image(&self.raster)));
+        self.next_capture = clock + self.interval;
+    }
+
+    // write the last frame, shown for as long as the others, and the
+    // trailer. returns the number of frames written
+    pub fn finish(mut self) -> Result<u64, String> {
+        if let Some((shown, _)) = self.pending {
+            self.write_pending(shown + self.interval);
+        }
+        let path = self.path.clone();
+        match self.file {
+            Some(ref mut file) => file
+                .write_all(&[0x3B])
+                .and_then(|_| file.flush())
+                .map_err(|e| format!("Error writing {}: {}", path.display(), e))?,
+            None => return Err(format!("The recording in {} is incomplete", path.display())),
+        }
+        Ok(self.frames)
+    }
+}
@@ -0,0 +1,64 @@
+// run the game without a window, sound or serial display, for automated
+// checks. the CPU, memory and DVG run as they do in the main loop, but each
+// frame is drawn on a target that just keeps the lines, and nothing waits
+// on the clock
+
+use display::Dvg;
+use machine::{Machine, NMI_CYCLES};
+use recording::InputPlayer;
+use render::{DrawnLine, RecordingTarget, PICTURE_HEIGHT, PICTURE_WIDTH};
+
+const TICK_CYCLES: u64 = 500; // as often as the main loop looks at the controls
+
+pub struct HeadlessRun {
+    pub frames: u64,
+    pub cycles: u64,
+    pub last_frame: Vec<DrawnLine>,
+}
+
+// run until the DVG has drawn the given number of frames, taking the
+// controls from a recording if there is one
+pub fn run(
+    machine: &mut Machine,
+    dvg: &mut Dvg,
+    frames: u64,
+    mut player: Option<InputPlayer>,
+) -> HeadlessRun {
+    let start_cycle = machine.cpu.cycle;
+    let mut next_nmi = (machine.cpu.cycle / NMI_CYCLES + 1) * NMI_CYCLES;
+    let mut next_tick = machine.cpu.cycle;
+    let mut target = RecordingTarget::new(PICTURE_WIDTH, PICTURE_HEIGHT);
+    let mut frames_drawn = 0;
+    while frames_drawn < frames {
+        // the controls change at the same points as in the main loop, so a
+        // recording plays back the same way
+        if machine.cpu.cycle >= next_tick {
+            next_tick = (machine.cpu.cycle / TICK_CYCLES + 1) * TICK_CYCLES;
+            if let Some(ref mut player) = player {
+                player.apply(machine.cpu.cycle, &mut machine.memory);
+            }
+            // nothing plays the sounds
+            machine.memory.take_sound_events();
+        }
+        machine.cpu.run_for_cycles(&mut machine.memory, 1);
+        if machine.memory.mapped_io.godvg != 0 {
+            if let Some(ref mut player) = player {
+                player.apply(machine.cpu.cycle, &mut machine.memory);
+            }
+            dvg.render(&mut machine.memory, &mut target, &mut None, &mut []);
+            frames_drawn += 1;
+        }
+        if machine.cpu.cycle >= next_nmi {
+            machine.nmi();
+            next_nmi += NMI_CYCLES;
+        }
+        if machine.memory.irq {
+            machine.cpu.initiate_irq(&mut machine.memory);
+        }
+    }
+    HeadlessRun {
+        frames: frames_drawn,
+        cycles: machine.cpu.cycle - start_cycle,
+        last_frame: target.lines,
+    }
+}
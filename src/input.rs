@@ -1,29 +1,153 @@
 // deal with *some* of the Asteroids game inputs
 
-use memory::Memory;
+use memory::{CoinSlot, Memory};
 use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 
-pub fn update_from_input(keycode: Keycode, active: bool, memory: &mut Memory) {
+// the switches the keyboard can work, named as in a bindings file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    Start,
+    Fire,
+    Thrust,
+    RotateLeft,
+    RotateRight,
+    Hyperspace,
+    SelfTest,
+    DiagStep,
+    Coin(CoinSlot),
+}
+
+impl Control {
+    pub fn from_name(name: &str) -> Option<Control> {
+        match name {
+            "start" => Some(Control::Start),
+            "fire" => Some(Control::Fire),
+            "thrust" => Some(Control::Thrust),
+            "left" => Some(Control::RotateLeft),
+            "right" => Some(Control::RotateRight),
+            "hyperspace" => Some(Control::Hyperspace),
+            "test" => Some(Control::SelfTest),
+            "diag" => Some(Control::DiagStep),
+            "coin_left" => Some(Control::Coin(CoinSlot::Left)),
+            "coin_center" => Some(Control::Coin(CoinSlot::Center)),
+            "coin_right" => Some(Control::Coin(CoinSlot::Right)),
+            _ => None,
+        }
+    }
+}
+
+pub struct KeyBindings {
+    keys: HashMap<Keycode, Control>,
+}
+
+impl KeyBindings {
+    pub fn new() -> KeyBindings {
+        let mut bindings = KeyBindings {
+            keys: HashMap::new(),
+        };
+        bindings.set_binding(Control::Start, Keycode::S);
+        bindings.set_binding(Control::Fire, Keycode::Space);
+        bindings.set_binding(Control::RotateLeft, Keycode::Left);
+        bindings.set_binding(Control::RotateRight, Keycode::Right);
+        bindings.set_binding(Control::Thrust, Keycode::Up);
+        bindings.set_binding(Control::Hyperspace, Keycode::LShift);
+        bindings.set_binding(Control::SelfTest, Keycode::T);
+        bindings.set_binding(Control::DiagStep, Keycode::D);
+        bindings.set_binding(Control::Coin(CoinSlot::Left), Keycode::Num5);
+        bindings.set_binding(Control::Coin(CoinSlot::Center), Keycode::Num6);
+        bindings.set_binding(Control::Coin(CoinSlot::Right), Keycode::Num7);
+        bindings
+    }
+
+    // moves the control to the key, so the key it was on no longer works it
+    pub fn set_binding(&mut self, control: Control, keycode: Keycode) {
+        self.keys.retain(|_, bound| *bound != control);
+        self.keys.insert(keycode, control);
+    }
+
+    pub fn control(&self, keycode: Keycode) -> Option<Control> {
+        self.keys.get(&keycode).cloned()
+    }
+
+    // lines of "control key", with # starting a comment, e.g. "fire F".
+    // keys are SDL key names, and controls not mentioned keep their keys
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("").trim();
+            if code.is_empty() {
+                continue;
+            }
+            let mut fields = code.splitn(2, char::is_whitespace);
+            let name = fields.next().unwrap_or("");
+            let key = fields.next().unwrap_or("").trim();
+            let control = Control::from_name(name)
+                .ok_or_else(|| format!("Unknown control '{}' on line {}", name, number + 1))?;
+            let keycode = Keycode::from_name(key)
+                .ok_or_else(|| format!("Unknown key '{}' on line {}", key, number + 1))?;
+            self.set_binding(control, keycode);
+        }
+        Ok(())
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        self.parse(&text)
+    }
+}
+
+pub fn update_from_input(
+    keycode: Keycode,
+    active: bool,
+    bindings: &KeyBindings,
+    memory: &mut Memory,
+) {
+    if let Some(control) = bindings.control(keycode) {
+        apply_control(control, active, memory);
+    }
+}
+
+// sets the switch for a control, whatever it's worked by
+pub fn apply_control(control: Control, active: bool, memory: &mut Memory) {
     let mem_val = if active { 0xFF } else { 0 };
-    match keycode {
-        Keycode::S => {
+    match control {
+        Control::Start => {
             memory.mapped_io.sw1start = mem_val;
         }
-        Keycode::Space => {
+        Control::Fire => {
             memory.mapped_io.swfire = mem_val;
         }
-        Keycode::Left => {
+        Control::RotateLeft => {
             memory.mapped_io.swrotleft = mem_val;
         }
-        Keycode::Right => {
+        Control::RotateRight => {
             memory.mapped_io.swrotrght = mem_val;
         }
-        Keycode::Up => {
+        Control::Thrust => {
             memory.mapped_io.swthrust = mem_val;
         }
-        Keycode::LShift => {
+        Control::Hyperspace => {
             memory.mapped_io.swhyper = mem_val;
         }
-        _ => {}
+        // the self test switch is a toggle on the board, which the game
+        // looks at as it starts up
+        Control::SelfTest => {
+            if active {
+                memory.mapped_io.swtest ^= 0xFF;
+            }
+        }
+        // steps through the test screens
+        Control::DiagStep => {
+            memory.mapped_io.swdiagst = mem_val;
+        }
+        // the coin drops through by itself, so only the press counts
+        Control::Coin(slot) => {
+            if active {
+                memory.insert_coin(slot);
+            }
+        }
     }
 }
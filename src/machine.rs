@@ -0,0 +1,165 @@
+// the CPU together with the memory it runs against
+
+use cpu::Cpu;
+use memory::Memory;
+
+// the watchdog resets the machine if the game goes this many NMIs without
+// writing to it. the Asteroids NMI handler clears it every time, so the
+// exact figure only matters for code that's gone astray
+pub const DEFAULT_WATCHDOG_NMIS: u32 = 8;
+
+pub const CPU_CLOCK_HZ: u64 = 1_500_000;
+
+// the NMI comes every 4ms, from the 3KHz clock
+pub const NMI_CYCLES: u64 = 6000;
+
+const PC_WINDOW_BEFORE: u16 = 16;
+const PC_WINDOW_AFTER: u16 = 32;
+
+// status register bits, highest first, as named in machine state JSON
+const FLAG_NAMES: [&str; 8] = ["n", "v", "u", "b", "d", "i", "z", "c"];
+
+pub struct Machine {
+    pub cpu: Cpu,
+    pub memory: Memory,
+    watchdog_nmis: Option<u32>, // None disables the watchdog
+    nmis_since_watchdog: u32,
+    watchdog_resets: u32,
+}
+
+impl Machine {
+    pub fn new(debug_mode: bool, illegal_as_nop: bool, memory: Memory) -> Machine {
+        Machine {
+            cpu: Cpu::new(debug_mode, illegal_as_nop),
+            memory,
+            watchdog_nmis: Some(DEFAULT_WATCHDOG_NMIS),
+            nmis_since_watchdog: 0,
+            watchdog_resets: 0,
+        }
+    }
+
+    pub fn set_watchdog(&mut self, nmis: Option<u32>) {
+        self.watchdog_nmis = nmis;
+    }
+
+    // how many times the watchdog has had to reset the machine, which
+    // points at an emulation bug or a bad ROM
+    pub fn watchdog_resets(&self) -> u32 {
+        self.watchdog_resets
+    }
+
+    // NMIs since the game last cleared the watchdog, for save states
+    pub fn nmis_since_watchdog(&self) -> u32 {
+        self.nmis_since_watchdog
+    }
+
+    pub fn set_nmis_since_watchdog(&mut self, nmis: u32) {
+        self.nmis_since_watchdog = nmis;
+    }
+
+    // the periodic NMI, which also clocks the watchdog
+    pub fn nmi(&mut self) {
+        if self.memory.mapped_io.watchdog_cleared {
+            self.memory.mapped_io.watchdog_cleared = false;
+            self.nmis_since_watchdog = 0;
+        } else {
+            self.nmis_since_watchdog += 1;
+        }
+        match self.watchdog_nmis {
+            Some(limit) if self.nmis_since_watchdog >= limit => {
+                self.watchdog_resets += 1;
+                println!(
+                    "Watchdog reset #{} at PC {:04X}, cycle {}",
+                    self.watchdog_resets,
+                    self.cpu.pc(),
+                    self.cpu.cycle
+                );
+                self.nmis_since_watchdog = 0;
+                self.reset();
+            }
+            _ => self.cpu.initiate_nmi(&mut self.memory),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset(&self.memory);
+    }
+
+    // as if the cabinet had been switched off and on again, without having
+    // to load the ROMs again
+    pub fn power_cycle(&mut self) {
+        self.memory.reset_io();
+        self.nmis_since_watchdog = 0;
+        self.reset();
+    }
+
+    fn bytes_json(&self, start: u16, length: u16) -> String {
+        let bytes: Vec<String> = self
+            .memory
+            .dump(start, length as usize)
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect();
+        format!("{{\"start\":{},\"bytes\":[{}]}}", start, bytes.join(","))
+    }
+
+    // a snapshot for external debuggers to poll. the schema is kept stable:
+    //
+    // {"cpu":{"a":0,"x":0,"y":0,"s":253,"pc":31749,"p":36,
+    //         "flags":{"n":false,"v":false,"u":true,"b":false,
+    //                  "d":false,"i":true,"z":false,"c":false},
+    //         "cycle":6},
+    //  "watchdog_resets":0,
+    //  "memory":{"pc":{"start":31733,"bytes":[...]},
+    //            "stack":{"start":510,"bytes":[...]}}}
+    //
+    // all numbers are decimal. "pc" covers the 16 bytes before PC and the
+    // 32 from it and "stack" the bytes in use, from S + 1 to $01FF (so
+    // it's empty when S is $FF)
+    pub fn state_json(&self) -> String {
+        let registers = self.cpu.snapshot();
+        let flags: Vec<String> = FLAG_NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("\"{}\":{}", name, registers.p & (0x80 >> i) != 0))
+            .collect();
+        let stack_start = 0x100 + registers.s as u16 + 1;
+        format!(
+            "{{\"cpu\":{{\"a\":{},\"x\":{},\"y\":{},\"s\":{},\"pc\":{},\"p\":{},\"flags\":{{{}}},\"cycle\":{}}},\
+             \"watchdog_resets\":{},\"memory\":{{\"pc\":{},\"stack\":{}}}}}",
+            registers.a,
+            registers.x,
+            registers.y,
+            registers.s,
+            registers.pc,
+            registers.p,
+            flags.join(","),
+            registers.cycle,
+            self.watchdog_resets(),
+            self.bytes_json(
+                registers.pc.wrapping_sub(PC_WINDOW_BEFORE),
+                PC_WINDOW_BEFORE + PC_WINDOW_AFTER
+            ),
+            self.bytes_json(stack_start, 0x200 - stack_start)
+        )
+    }
+
+    // state_json plus the address of the instruction that was executing and,
+    // in debug mode, those of the instructions before it:
+    //
+    // {"instruction":31749,"recent":[31740,31743,...],"state":{...}}
+    pub fn crash_report(&self) -> String {
+        let recent: Vec<String> = self
+            .cpu
+            .recent_addresses()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        format!(
+            "{{\"instruction\":{},\"recent\":[{}],\"state\":{}}}",
+            self.cpu.instruction_address(),
+            recent.join(","),
+            self.state_json()
+        )
+    }
+}
@@ -1,97 +1,412 @@
 // combine roms cat 035127-02.np3 035145-02.ef2 035144-02.h2 035143-02.j2 > asteroids.rom
 
 // Enulator to run Asteroids game
+#[macro_use]
+extern crate clap;
 extern crate find_folder;
 extern crate sdl2;
+#[cfg(feature = "serde")]
+extern crate serde;
+extern crate serde_json;
 extern crate serialport;
+extern crate tungstenite;
 
 use serialport::prelude::*;
 
+mod colors;
+mod config;
+mod conformance;
+mod controller;
+mod coverage;
 mod cpu;
+mod debugger;
 mod display;
+mod editor;
+mod exclusions;
+mod game;
+mod gif;
+mod headless;
 mod input;
+mod machine;
 mod memory;
+mod panel;
+mod perf;
+mod profile;
+mod recording;
+mod render;
+mod rewind;
+mod savestate;
+mod screenshot;
 mod sound;
+mod vsync;
+mod websocket;
 
 use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Keycode;
-use sdl2::mixer::{Channel, AUDIO_S16LSB, DEFAULT_CHANNELS, INIT_OGG, MAX_VOLUME};
-use sdl2::video::WindowPos;
+use sdl2::mouse::MouseButton;
+use sdl2::mixer::{AUDIO_S16LSB, DEFAULT_CHANNELS, INIT_OGG, MAX_VOLUME};
+use sdl2::render::Canvas;
+use sdl2::video::{Window, WindowPos};
 use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 const SCREEN_WIDTH: u32 = 10240; // i.e. bigger than maximised dimensions
-const NMI_CYCLES: u64 = 6000;
 const TICKS_PER_SLEEP: u32 = 20;
 const SLOWDOWN_FACTOR: f64 = 1.0;
+const MIN_GEOMETRY_SCALE: f64 = 0.1;
+const MAX_GEOMETRY_SCALE: f64 = 8.0;
+const MAX_SERIAL_PACKET_VECTORS: usize = 64;
+const DEFAULT_STATE_FILE: &str = "asteroids.state";
+const MAX_REWIND_DEPTH: usize = 1_000;
+// how often holding the rewind key steps back a state
+const REWIND_STEP: Duration = Duration::from_millis(100);
+const MIN_GAMMA: f64 = 0.1;
+const MAX_GAMMA: f64 = 5.0;
 
-use cpu::Cpu;
-use display::Dvg;
-use memory::Memory;
+// a larger mixer buffer trades latency for fewer underruns. windows audio
+// drivers seem to need the extra headroom to avoid crackling
+#[cfg(target_os = "windows")]
+const DEFAULT_AUDIO_BUFFER: i32 = 2_048;
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_AUDIO_BUFFER: i32 = 1_024;
+const MIN_AUDIO_BUFFER: i32 = 256;
+const MAX_AUDIO_BUFFER: i32 = 8_192;
+const DEFAULT_AUDIO_RATE: i32 = 44_100;
+const MIN_AUDIO_RATE: i32 = 8_000;
+const MAX_AUDIO_RATE: i32 = 96_000;
+const DEFAULT_VOLUME: i32 = MAX_VOLUME / 2;
+const MAX_SOUND_FADE_MS: u64 = 1_000;
+
+use colors::ColorScheme;
+use config::{Config, SpeedMode};
+use controller::Controllers;
+use debugger::Debugger;
+use display::{Dvg, VectorSink};
+use editor::MemoryEditor;
+use exclusions::{ExclusionRect, ExclusionTuner};
+use game::{GodMode, TurboFire};
+use gif::GifRecorder;
+use input::{Control, KeyBindings};
+use machine::{Machine, CPU_CLOCK_HZ, NMI_CYCLES};
+use memory::{Coinage, DipSettings, Language, Memory, ROM_CHIP_NAMES};
+use panel::ControlPanel;
+use perf::PerfOverlay;
+use recording::{InputPlayer, InputRecorder};
+use render::Phosphor;
+use rewind::Rewind;
+use savestate::SaveState;
 use sound::Sounds;
+use vsync::FramePacer;
+use websocket::DisplayListServer;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let debug = args.len() > 1 && args[1] == "debug";
+    let config = Config::from_args(env::args_os()).unwrap_or_else(|e| exit_with_error(&e));
+    let debug = config.debug;
+    let sound_test = config.flag("sound-test");
+    let audio_buffer = match config.value("audio-buffer") {
+        Some(value) => parse_audio_buffer(value).unwrap_or_else(|e| exit_with_error(&e)),
+        None => DEFAULT_AUDIO_BUFFER,
+    };
+    let audio_rate = match config.value("audio-rate") {
+        Some(value) => parse_audio_rate(value).unwrap_or_else(|e| exit_with_error(&e)),
+        None => DEFAULT_AUDIO_RATE,
+    };
+
+    // print a listing of some of the game code and stop
+    if let Some(range) = config.value("disassemble") {
+        let (start, end) = parse_address_range(range).unwrap_or_else(|e| exit_with_error(&e));
+        let memory = load_memory(&config);
+        let mut addr = start as u32;
+        while addr <= end as u32 {
+            let (text, length) = cpu::disassemble(&memory, addr as u16);
+            let bytes: Vec<String> = memory
+                .dump(addr as u16, length as usize)
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            println!("{:04X}  {:<9} {}", addr, bytes.join(" "), text);
+            addr += length as u32;
+        }
+        return;
+    }
+
+    // run Klaus Dormann's 6502 functional test and stop
+    if let Some(path) = config.value("functional-test") {
+        match conformance::run_functional_test(Path::new(path)) {
+            Ok(cycles) => println!("Functional test passed in {} cycles", cycles),
+            Err(e) => exit_with_error(&e),
+        }
+        return;
+    }
+
+    // run Tom Harte's single step tests, a file for one op code or a
+    // directory for them all, and stop
+    if let Some(path) = config.value("single-step-tests") {
+        let results = conformance::run_single_step_tests(Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(&e));
+        println!(
+            "{} passed, {} failed, {} skipped",
+            results.passed, results.failed, results.skipped
+        );
+        if results.failed > 0 {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // run without a window for a number of frames and stop
+    if let Some(value) = config.value("headless") {
+        let frames = value
+            .parse::<u64>()
+            .unwrap_or_else(|_| exit_with_error(&format!("Invalid number of frames '{}'", value)));
+        let mut machine = build_machine(&config);
+        let mut dvg = build_dvg(&config, false);
+        let player = config.value("play").map(|path| {
+            InputPlayer::load(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e))
+        });
+        machine.reset();
+        let run = headless::run(&mut machine, &mut dvg, frames, player);
+        println!(
+            "{} frames in {} cycles, {} lines drawn in the last frame",
+            run.frames,
+            run.cycles,
+            run.last_frame.len()
+        );
+        if let Some(path) = config.value("coverage") {
+            if let Err(e) = machine.cpu.dump_coverage(path) {
+                exit_with_error(&format!("Error writing coverage to {}: {}", path, e));
+            }
+        }
+        print!("{}", machine.cpu.profile_report());
+        return;
+    }
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsys = sdl_context.video().unwrap();
-    let window = video_subsys
-        .window("Asteroids Emu", SCREEN_WIDTH, SCREEN_WIDTH)
-        .resizable()
-        .maximized()
-        .opengl()
-        .build()
-        .unwrap();
+    // without a scale the window starts maximised, and is fitted to the
+    // screen once it's shown. with one it starts just big enough
+    let window = match config.scale {
+        Some(scale) => video_subsys
+            .window("Asteroids Emu", 1024 * scale, 832 * scale)
+            .resizable()
+            .position_centered()
+            .opengl()
+            .build(),
+        None => video_subsys
+            .window("Asteroids Emu", SCREEN_WIDTH, SCREEN_WIDTH)
+            .resizable()
+            .maximized()
+            .opengl()
+            .build(),
+    }
+    .unwrap();
 
     let _audio = sdl_context.audio().unwrap();
     sdl2::mixer::open_audio(
-        44_100,       // frequency
+        audio_rate,   // frequency
         AUDIO_S16LSB, //format
         DEFAULT_CHANNELS,
-        1_024, // chunk size
+        audio_buffer, // chunk size
     )
     .unwrap();
+    if debug {
+        println!("Audio {} Hz, {} sample buffer", audio_rate, audio_buffer);
+    }
     let _mixer_context = sdl2::mixer::init(INIT_OGG).unwrap();
-    Channel::all().set_volume(MAX_VOLUME / 2);
+    sdl2::mixer::allocate_channels(sound::MIXER_CHANNELS);
 
-    let tick_time = Duration::new(
+    let batch_time = Duration::new(
         0,
         (1000000000f64 / 3000f64 * SLOWDOWN_FACTOR * TICKS_PER_SLEEP as f64) as u32,
     );
+    let tick_time = config.speed.wall_time(batch_time);
+    // while the fast forward key is held
+    let turbo_speed = match config.value("turbo") {
+        Some(name) => SpeedMode::from_name(name).unwrap_or_else(|| {
+            exit_with_error(&format!(
+                "Invalid turbo speed '{}', expected max or a multiple of real time",
+                name
+            ))
+        }),
+        None => SpeedMode::Unlimited,
+    };
+    let turbo_tick_time = turbo_speed.wall_time(batch_time);
+    let turbo_sound = config.flag("turbo-sound");
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    // with vsync, presenting waits for the monitor to refresh
+    let mut canvas = if config.flag("vsync") {
+        window.into_canvas().present_vsync().build().unwrap()
+    } else {
+        window.into_canvas().build().unwrap()
+    };
+    let mut pacer = if config.flag("vsync") {
+        let refresh_rate = canvas
+            .window()
+            .display_index()
+            .and_then(|index| video_subsys.current_display_mode(index))
+            .map(|mode| mode.refresh_rate.max(0) as u32)
+            .unwrap_or(0);
+        if debug {
+            println!("Display refreshes at {} Hz", refresh_rate);
+        }
+        Some(FramePacer::new(refresh_rate))
+    } else {
+        None
+    };
+    fit_picture(&mut canvas, config.scale);
+    let texture_creator = canvas.texture_creator();
+    let mut phosphor = config.value("phosphor").map(|value| match value.parse::<f64>() {
+        Ok(decay) if decay > 0.0 && decay <= 1.0 => Phosphor::new(&texture_creator, decay),
+        _ => exit_with_error(&format!(
+            "Phosphor decay must be above 0 and at most 1, not '{}'",
+            value
+        )),
+    });
 
     let mut events = sdl_context.event_pump().unwrap();
+    let mut controllers = Controllers::new(sdl_context.game_controller().unwrap());
 
-    let mut settings: SerialPortSettings = Default::default();
-    settings.timeout = Duration::from_millis(10);
-    settings.baud_rate = 921600;
-
-    let mut serialoutput = true;
+    let settings = serial_settings(config.baud);
 
     // Open the serial port
-    let mut port: Option<Box<dyn SerialPort>> =
-        match serialport::open_with_settings("/dev/ttyACM2", &settings) {
-            Ok(port) => Some(port),
+    let mut port: Option<Box<dyn SerialPort>> = config.serial.as_ref().and_then(|name| {
+        match serialport::open_with_settings(name, &settings) {
+            Ok(port) => {
+                println!("Serial display on {} at {} baud", name, settings.baud_rate);
+                Some(port)
+            }
             Err(e) => {
-                println!("Error opening serial port: {}", e);
-                serialoutput = false;
+                println!(
+                    "Error opening serial port {}, running without the serial display: {}",
+                    name, e
+                );
                 None
             }
-        };
+        }
+    });
+    let serialoutput = port.is_some();
+
+    // physical controls, alongside the keyboard
+    let mut panel = config.value("panel").map(|name| {
+        match serialport::open_with_settings(name, &settings) {
+            Ok(port) => ControlPanel::new(port),
+            Err(e) => exit_with_error(&format!("Error opening control panel {}: {}", name, e)),
+        }
+    });
 
-    let mut cpu = Cpu::new(debug);
-    let mut dvg = Dvg::new(debug, serialoutput);
-    let mut memory = Memory::new();
-    let mut sounds = Sounds::new();
-    cpu.reset(&memory);
+    let mut sinks: Vec<Box<dyn VectorSink>> = Vec::new();
+    if let Some(addr) = config.value("websocket") {
+        match DisplayListServer::bind(addr) {
+            Ok(server) => {
+                println!("Serving display lists on ws://{}", addr);
+                sinks.push(Box::new(server));
+            }
+            Err(e) => exit_with_error(&format!("Error listening on {}: {}", addr, e)),
+        }
+    }
+
+    let mut machine = build_machine(&config);
+    let coverage_path = config.value("coverage");
+    let mut dvg = build_dvg(&config, serialoutput);
+    let mut sounds = if let Some(dir) = config.value("sound-dir") {
+        Sounds::new_with_dir(Path::new(dir))
+    } else {
+        Sounds::new()
+    };
+    match config.value("volume") {
+        Some(value) => match value.parse::<i32>() {
+            Ok(level) => sounds.set_master_volume(level),
+            Err(_) => exit_with_error(&format!("Invalid volume '{}'", value)),
+        },
+        None => sounds.set_master_volume(DEFAULT_VOLUME),
+    }
+    sounds.set_enabled(!config.mute);
+    if let Some(value) = config.value("sound-fade") {
+        match value.parse::<u64>() {
+            Ok(ms) if ms <= MAX_SOUND_FADE_MS => sounds.set_fade(Duration::from_millis(ms)),
+            _ => exit_with_error(&format!(
+                "Sound fade must be between 0 and {} ms",
+                MAX_SOUND_FADE_MS
+            )),
+        }
+    }
+    if let Some(list) = config.value("effect-volume") {
+        for item in list.split(',') {
+            let mut parts = item.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let level = parts.next().and_then(|level| level.parse::<i32>().ok());
+            let result = match level {
+                Some(level) => sounds.set_volume(name, level),
+                None => Err(format!("Invalid effect volume '{}', expected e.g. thump_low=32", item)),
+            };
+            if let Err(e) = result {
+                exit_with_error(&e);
+            }
+        }
+    }
+    let mut bindings = KeyBindings::new();
+    if let Some(path) = config.value("keys") {
+        bindings.load(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e));
+    }
+    let mut editor = MemoryEditor::new();
+    let mut tuner = ExclusionTuner::new();
+    let mut perf = PerfOverlay::new();
+    let mut god_mode = if config.flag("god-mode") {
+        Some(GodMode::new())
+    } else {
+        None
+    };
+    let mut turbo_fire = if config.flag("turbo-fire") {
+        Some(TurboFire::new())
+    } else {
+        None
+    };
+    machine.reset();
+
+    // a hand written display list replaces the game entirely, which is
+    // handy for checking the renderer in isolation
+    let dvg_program = config.value("dvg-program").is_some();
+    if let Some(path) = config.value("dvg-program") {
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|e| exit_with_error(&format!("Error reading {}: {}", path, e)));
+        let words = Dvg::parse_program(&text).unwrap_or_else(|e| exit_with_error(&e));
+        Dvg::load_program(&mut machine.memory, &words);
+    }
+    let mut gif = config.value("record-gif").map(|path| {
+        GifRecorder::create(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e))
+    });
+    let mut recorder = config.value("record").map(|path| {
+        InputRecorder::create(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e))
+    });
+    // while playing back the controls come from the recording, not the
+    // keyboard, controllers or panel
+    let mut player = config.value("play").map(|path| {
+        InputPlayer::load(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e))
+    });
     let mut next_nmi = NMI_CYCLES;
+    let mut paused = false;
+    let mut screenshot = false; // taken when the next frame has been drawn
+    let mut sound_cycle = 0; // when the sounds were last brought up to date
+    // loading a state winds the cycle count back or forward, so the cycles
+    // actually run are counted as they go
+    let mut emulated_cycles = 0;
+    let state_path = Path::new(config.value("state-file").unwrap_or(DEFAULT_STATE_FILE));
+    let mut rewind = build_rewind(&config);
+    let mut rewinding = false; // while the rewind key is held
+    let mut turbo = false; // while the fast forward key is held
+    let mut debugger = Debugger::new(config.flag("step"));
+    let mut last_rewind_step = Instant::now();
+    let mut running_time = Duration::from_millis(0); // wall time spent unpaused
 
     'main: loop {
         let now = Instant::now();
-        for _i in 0..TICKS_PER_SLEEP {
+        for i in 0..TICKS_PER_SLEEP {
             for event in events.poll_iter() {
                 match event {
                     Event::Quit { .. } => break 'main,
@@ -102,21 +417,186 @@ fn main() {
                     } => {
                         if keycode == Keycode::Escape {
                             break 'main;
+                        } else if keycode == Keycode::F2 {
+                            editor.toggle();
+                        } else if editor.active {
+                            editor.handle_key(keycode, &mut machine.memory);
+                        } else if keycode == Keycode::F7 {
+                            println!("{}", machine.state_json());
+                        } else if keycode == Keycode::F6 {
+                            for line in Dvg::disassemble(&machine.memory) {
+                                println!("{}", line);
+                            }
+                        } else if keycode == Keycode::F8 {
+                            let svg = dvg.render_to_svg(&mut machine.memory);
+                            let path = next_free_path("frame", "svg");
+                            match fs::write(&path, svg) {
+                                Ok(()) => println!("Saved {}", path.display()),
+                                Err(e) => println!("Error writing {}: {}", path.display(), e),
+                            }
+                        } else if keycode == Keycode::F10 {
+                            perf.toggle();
+                        } else if keycode == Keycode::F12 {
+                            screenshot = true;
+                        } else if keycode == Keycode::F5 {
+                            match SaveState::capture(&machine, next_nmi).save(state_path) {
+                                Ok(()) => println!("Saved state to {}", state_path.display()),
+                                Err(e) => println!("{}", e),
+                            }
+                        } else if keycode == Keycode::F9 {
+                            let loaded = SaveState::load(state_path)
+                                .and_then(|state| state.restore(&mut machine).map(|_| state));
+                            match loaded {
+                                Ok(state) => {
+                                    next_nmi = state.next_nmi;
+                                    sound_cycle = machine.cpu.cycle;
+                                    sounds.restore(&machine.memory.mapped_io);
+                                    println!("Loaded state from {}", state_path.display());
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        } else if keycode == Keycode::F4 {
+                            tuner.toggle(&dvg.serial_filter().exclusions);
+                        } else if tuner.active {
+                            tuner.handle_key(keycode, &mut dvg.serial_filter_mut().exclusions);
+                        } else if keycode == Keycode::M {
+                            let enabled = sounds.toggle_enabled();
+                            println!("Sound {}", if enabled { "on" } else { "off" });
+                        } else if keycode == Keycode::F3 {
+                            machine.power_cycle();
+                            next_nmi = machine.cpu.cycle + NMI_CYCLES;
+                            dvg.clear(&mut canvas);
+                            println!("Reset");
+                        } else if keycode == Keycode::P {
+                            paused = !paused;
+                            sounds.set_paused(paused);
+                            println!("{}", if paused { "Paused" } else { "Resumed" });
+                        } else if keycode == Keycode::R && rewind.is_some() {
+                            if !rewinding {
+                                rewinding = true;
+                                sounds.set_paused(true);
+                                last_rewind_step = Instant::now() - REWIND_STEP;
+                            }
+                        } else if keycode == Keycode::Tab {
+                            if !turbo {
+                                turbo = true;
+                                if !turbo_sound {
+                                    sounds.set_paused(true);
+                                }
+                            }
+                        } else if keycode == Keycode::H {
+                            let hidden = dvg.toggle_hud();
+                            println!(
+                                "Scores and messages {}",
+                                if hidden { "hidden" } else { "shown" }
+                            );
+                        } else if keycode == Keycode::B {
+                            let filtered = dvg.serial_filter_mut().toggle_bullets();
+                            println!(
+                                "Bullets {} on serial display",
+                                if filtered { "hidden" } else { "shown" }
+                            );
+                        } else if let Some(number) =
+                            sound_test_number(keycode).filter(|_| sound_test)
+                        {
+                            sounds.test_effect(number);
+                        } else if player.is_some() {
+                            // the recording has the controls
+                        } else if let (Some(Control::Fire), Some(turbo_fire)) =
+                            (bindings.control(keycode), turbo_fire.as_mut())
+                        {
+                            turbo_fire.held = true;
                         } else {
-                            input::update_from_input(keycode, true, &mut memory);
+                            input::update_from_input(
+                                keycode,
+                                true,
+                                &bindings,
+                                &mut machine.memory,
+                            );
+                        }
+                    }
+
+                    Event::KeyUp {
+                        keycode: Some(Keycode::R),
+                        ..
+                    } if rewinding => {
+                        rewinding = false;
+                        // the loops the game had on where it was wound back
+                        // to start again
+                        sounds.restore(&machine.memory.mapped_io);
+                        sounds.set_paused(paused);
+                        if let Some(ref mut rewind) = rewind {
+                            rewind.resume(machine.cpu.cycle);
+                        }
+                    }
+
+                    // the NMI and sounds are timed by the cycle count, so
+                    // they carry on at the normal rate as they were
+                    Event::KeyUp {
+                        keycode: Some(Keycode::Tab),
+                        ..
+                    } if turbo => {
+                        turbo = false;
+                        if !turbo_sound {
+                            sounds.restore(&machine.memory.mapped_io);
+                            sounds.set_paused(paused);
                         }
                     }
 
                     Event::KeyUp {
                         keycode: Some(keycode),
                         ..
-                    } => {
-                        input::update_from_input(keycode, false, &mut memory);
+                    } if player.is_none() => {
+                        if let (Some(Control::Fire), Some(turbo_fire)) =
+                            (bindings.control(keycode), turbo_fire.as_mut())
+                        {
+                            turbo_fire.held = false;
+                        } else {
+                            input::update_from_input(
+                                keycode,
+                                false,
+                                &bindings,
+                                &mut machine.memory,
+                            );
+                        }
+                    }
+
+                    Event::MouseButtonDown {
+                        mouse_btn: MouseButton::Left,
+                        x,
+                        y,
+                        ..
+                    } if debug => {
+                        // work out which DVG instruction drew what was clicked.
+                        // the window size can differ from the output size on
+                        // high DPI displays
+                        let (ww, wh) = canvas.window().size();
+                        let (w, h) = canvas.output_size().unwrap();
+                        let x = x * w as i32 / ww as i32;
+                        let y = y * h as i32 / wh as i32;
+                        // and the picture may be letterboxed
+                        let viewport = canvas.viewport();
+                        let (dvg_x, dvg_y) = Dvg::dvg_position(
+                            x - viewport.x(),
+                            y - viewport.y(),
+                            viewport.width(),
+                            viewport.height(),
+                        );
+                        match dvg.segment_near(dvg_x, dvg_y) {
+                            Some(segment) => println!(
+                                "Clicked {}, {}: {:?} drawn by {}",
+                                dvg_x,
+                                dvg_y,
+                                segment,
+                                Dvg::describe_instruction(&machine.memory, segment.pc)
+                            ),
+                            None => println!("Clicked {}, {}: nothing drawn", dvg_x, dvg_y),
+                        }
                     }
 
                     Event::Window { win_event, .. } => {
                         match win_event {
-                            WindowEvent::Shown => {
+                            WindowEvent::Shown if config.scale.is_none() => {
                                 //println!("Get resolution");
                                 // there must be a better way of doing this...
                                 // if we start maximised, we can then use the
@@ -138,40 +618,623 @@ fn main() {
                                     println!("Screen size {} x {}", nw, nh);
                                 }
                             }
+                            WindowEvent::SizeChanged(..) => fit_picture(&mut canvas, config.scale),
                             _ => {}
                         }
                     }
 
+                    event if player.is_none() => {
+                        controllers.handle_event(&event, &mut machine.memory)
+                    }
+
                     _ => {}
                 }
             }
 
-            let next_tick = ((cpu.cycle / 500) + 1) * 500;
-            while cpu.cycle < next_tick {
-                while cpu.cycle < next_tick && memory.mapped_io.godvg == 0 {
-                    cpu.execute_instruction(&mut memory);
-                    if cpu.cycle >= next_nmi {
-                        cpu.initiate_nmi(&mut memory);
-                        next_nmi += NMI_CYCLES;
-                    }
+            if dvg_program {
+                if i == 0 {
+                    perf.frame_drawn();
+                    render_frame(
+                        &mut dvg,
+                        &mut machine.memory,
+                        &mut canvas,
+                        &mut phosphor,
+                        &mut port,
+                        &mut sinks,
+                    );
+                    present_frame(
+                        &mut canvas,
+                        &mut perf,
+                        &tuner,
+                        &editor,
+                        &dvg,
+                        &machine.memory,
+                        &mut screenshot,
+                    );
                 }
+                continue;
+            }
+
+            // on the real board the DVG runs alongside the CPU:
+            //
+            //   CPU  ... | STA $3000 (GODVG) | next instr | ... poll HALT ... |
+            //   DVG                          |-- draw display list --| HALT
+            //   NMI                          ^ taken here if due
+            //
+            // the DVG starts on the write to GODVG, so it sees vector RAM as
+            // it was at that point, whereas an NMI is only recognised at the
+            // end of the instruction. we can't run the two side by side, so
+            // the whole display list is drawn the moment GODVG is written,
+            // before any NMI due on that instruction is taken, and HALT then
+            // reads as done straight away.
 
-                if memory.mapped_io.godvg != 0 {
-                    dvg.render(&mut memory, &mut canvas, &mut port);
+            // the cycle count stands still while paused, so the NMI and
+            // sound timing carry on from where they were
+            if paused {
+                continue;
+            }
+            // the game stands still while it's being wound back, showing
+            // the picture from each state in turn
+            if rewinding {
+                if last_rewind_step.elapsed() < REWIND_STEP {
+                    continue;
+                }
+                last_rewind_step = Instant::now();
+                let state = rewind.as_mut().and_then(|rewind| rewind.step_back());
+                if let Some(state) = state {
+                    if state.restore(&mut machine).is_ok() {
+                        next_nmi = state.next_nmi;
+                        sound_cycle = machine.cpu.cycle;
+                        render_frame(
+                            &mut dvg,
+                            &mut machine.memory,
+                            &mut canvas,
+                            &mut phosphor,
+                            &mut None,
+                            &mut [],
+                        );
+                        present_frame(
+                            &mut canvas,
+                            &mut perf,
+                            &tuner,
+                            &editor,
+                            &dvg,
+                            &machine.memory,
+                            &mut screenshot,
+                        );
+                    }
                 }
+                continue;
             }
-            sounds.play(&memory);
+            if let Some(ref mut rewind) = rewind {
+                rewind.capture(&machine, next_nmi);
+            }
+            if let Some(ref mut player) = player {
+                player.apply(machine.cpu.cycle, &mut machine.memory);
+            }
+            if player.as_ref().map_or(false, |player| player.finished()) {
+                println!("Playback finished at cycle {}", machine.cpu.cycle);
+                player = None;
+            }
+            if let Some(ref mut recorder) = recorder {
+                recorder.capture(machine.cpu.cycle, &machine.memory);
+            }
+            let tick_start = machine.cpu.cycle;
+            let next_tick = ((machine.cpu.cycle / 500) + 1) * 500;
+            // if anything goes wrong, the panic message is followed by the
+            // state of the machine for a post-mortem
+            let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+                while machine.cpu.cycle < next_tick {
+                    debugger.before_instruction(&mut machine);
+                    // a single instruction at a time, as the display and
+                    // the NMI have to be looked at after each one
+                    machine.cpu.run_for_cycles(&mut machine.memory, 1);
+                    if let Some(hit) = machine.memory.take_watch_hit() {
+                        println!(
+                            "Watch: {:04X} {} {:02X} by the instruction at {:04X}, cycle {}",
+                            hit.address,
+                            if hit.write { "written" } else { "read" },
+                            hit.value,
+                            machine.cpu.instruction_address(),
+                            hit.cycle
+                        );
+                    }
 
-            memory.mapped_io.clck3khz = ((cpu.cycle / 500) & 0xFF) as u8;
+                    if machine.memory.mapped_io.godvg != 0 {
+                        if let Some(ref mut god_mode) = god_mode {
+                            god_mode.apply(&mut machine.memory);
+                        }
+                        if let Some(ref mut player) = player {
+                            player.apply(machine.cpu.cycle, &mut machine.memory);
+                        } else {
+                            if let Some(ref mut panel) = panel {
+                                panel.poll(&mut machine.memory);
+                            }
+                            if let Some(ref mut turbo_fire) = turbo_fire {
+                                turbo_fire.apply(&mut machine.memory);
+                            }
+                        }
+                        if let Some(ref mut recorder) = recorder {
+                            recorder.capture(machine.cpu.cycle, &machine.memory);
+                        }
+                        render_frame(
+                            &mut dvg,
+                            &mut machine.memory,
+                            &mut canvas,
+                            &mut phosphor,
+                            &mut port,
+                            &mut sinks,
+                        );
+                        if let Some(ref mut gif) = gif {
+                            gif.capture(machine.cpu.cycle, |target| {
+                                dvg.render_offscreen(&mut machine.memory, target)
+                            });
+                        }
+                        perf.frame_drawn();
+                        match pacer {
+                            Some(ref mut pacer) => pacer.frame_drawn(),
+                            None => present_frame(
+                                &mut canvas,
+                                &mut perf,
+                                &tuner,
+                                &editor,
+                                &dvg,
+                                &machine.memory,
+                                &mut screenshot,
+                            ),
+                        }
+                    }
+
+                    if machine.cpu.cycle >= next_nmi {
+                        machine.nmi();
+                        next_nmi += NMI_CYCLES;
+                    }
+                    // the IRQ is level triggered, so it's taken again and
+                    // again for as long as the line is held and enabled
+                    if machine.memory.irq {
+                        machine.cpu.initiate_irq(&mut machine.memory);
+                    }
+                }
+            }));
+            if let Err(payload) = ran {
+                eprintln!("Machine state at crash: {}", machine.crash_report());
+                panic::resume_unwind(payload);
+            }
+            let sound_events = machine.memory.take_sound_events();
+            if debug {
+                for event in &sound_events {
+                    println!(
+                        "Sound {:04X} <- {:02X} at cycle {}",
+                        event.address, event.value, event.cycle
+                    );
+                }
+            }
+            // fast forwarding silently, the sounds are taken up from the
+            // registers when it stops
+            if !sound_test && (turbo_sound || !turbo) {
+                let elapsed = Duration::from_nanos(
+                    (machine.cpu.cycle - sound_cycle) * 1_000_000_000 / CPU_CLOCK_HZ,
+                );
+                sounds.play(&sound_events, elapsed);
+            }
+            sound_cycle = machine.cpu.cycle;
+            emulated_cycles += machine.cpu.cycle - tick_start;
         }
+        if let Some(ref mut pacer) = pacer {
+            if pacer.should_present(Instant::now()) {
+                present_frame(
+                    &mut canvas,
+                    &mut perf,
+                    &tuner,
+                    &editor,
+                    &dvg,
+                    &machine.memory,
+                    &mut screenshot,
+                );
+                pacer.presented(Instant::now());
+            }
+        }
+        perf.sample(Instant::now(), machine.cpu.cycle);
         // sleeping at every 3khz tick is too frequent as there can still be
         // overruns on my laptop, so we even things out over a number of ticks
         // (of course, if running in debug mode, this is all moot)
-        let delta = now.elapsed();
-        if delta < tick_time {
-            sleep(tick_time - delta);
-        } else {
-            println!("Overrun {:?}", delta - tick_time);
+        let tick_time = if turbo { turbo_tick_time } else { tick_time };
+        if let Some(tick_time) = tick_time {
+            let delta = now.elapsed();
+            if delta < tick_time {
+                sleep(tick_time - delta);
+            } else {
+                println!("Overrun {:?}", delta - tick_time);
+            }
+        }
+        if !paused {
+            running_time += now.elapsed();
+        }
+    }
+
+    let wall = running_time.as_secs() as f64 + running_time.subsec_nanos() as f64 / 1e9;
+    if wall > 0.0 {
+        let emulated = emulated_cycles as f64 / CPU_CLOCK_HZ as f64;
+        println!(
+            "Emulated {:.1}s in {:.1}s, {:.2}x real time",
+            emulated,
+            wall,
+            emulated / wall
+        );
+    }
+
+    if let Some(ref pacer) = pacer {
+        let (drawn, dropped) = pacer.counts();
+        if dropped > 0 {
+            println!(
+                "{} of {} frames were drawn over before being shown, the game drew them \
+                 faster than the display refreshes",
+                dropped, drawn
+            );
+        }
+    }
+
+    if let Some(gif) = gif {
+        match gif.finish() {
+            Ok(frames) => println!(
+                "Recorded {} frames to {}",
+                frames,
+                config.value("record-gif").unwrap_or_default()
+            ),
+            Err(e) => println!("{}", e),
         }
     }
+
+    print!("{}", machine.cpu.profile_report());
+
+    if let Some(path) = coverage_path {
+        if let Err(e) = machine.cpu.dump_coverage(path) {
+            exit_with_error(&format!("Error writing coverage to {}: {}", path, e));
+        }
+        println!("ROM coverage written to {}", path);
+    }
+}
+
+fn sound_test_number(keycode: Keycode) -> Option<u32> {
+    match keycode {
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
+// None with --rewind-depth 0, or when recording or playing back the
+// controls, as winding back would leave the recording out of step
+fn build_rewind(config: &Config) -> Option<Rewind> {
+    if config.value("record").is_some() || config.value("play").is_some() {
+        return None;
+    }
+    let interval_ms = match config.value("rewind-interval") {
+        Some(value) => match value.parse::<u64>() {
+            Ok(ms) if ms > 0 => ms,
+            _ => exit_with_error(&format!("Invalid rewind interval '{}'", value)),
+        },
+        None => rewind::DEFAULT_INTERVAL_MS,
+    };
+    let depth = match config.value("rewind-depth") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(depth) if depth <= MAX_REWIND_DEPTH => depth,
+            _ => exit_with_error(&format!(
+                "Rewind depth must be from 0 to {} states",
+                MAX_REWIND_DEPTH
+            )),
+        },
+        None => rewind::DEFAULT_DEPTH,
+    };
+    if depth == 0 {
+        return None;
+    }
+    Some(Rewind::new(interval_ms * CPU_CLOCK_HZ / 1000, depth))
+}
+
+// with a scale, the picture is drawn at that multiple of its size in the
+// middle of the window, whatever size that is. without, it fills the window
+fn fit_picture(canvas: &mut Canvas<Window>, scale: Option<u32>) {
+    if let Some(scale) = scale {
+        let output = canvas.output_size().unwrap();
+        canvas.set_viewport(render::letterbox(output, scale));
+    }
+}
+
+// put the overlays over the frame, take any screenshot that's been asked for
+// (before presenting, after which the canvas isn't defined) and show it
+fn present_frame(
+    canvas: &mut Canvas<Window>,
+    perf: &mut PerfOverlay,
+    tuner: &ExclusionTuner,
+    editor: &MemoryEditor,
+    dvg: &Dvg,
+    memory: &Memory,
+    screenshot: &mut bool,
+) {
+    tuner.draw(canvas, &dvg.serial_filter().exclusions);
+    editor.draw(canvas, memory);
+    perf.draw(canvas);
+    perf.frame_shown();
+    if *screenshot {
+        screenshot::save(canvas);
+        *screenshot = false;
+    }
+    canvas.present();
+}
+
+// draw a frame straight on the canvas, or on the phosphor if it's in use
+fn render_frame(
+    dvg: &mut Dvg,
+    memory: &mut Memory,
+    canvas: &mut Canvas<Window>,
+    phosphor: &mut Option<Phosphor>,
+    port: &mut Option<Box<dyn SerialPort>>,
+    sinks: &mut [Box<dyn VectorSink>],
+) {
+    match *phosphor {
+        Some(ref mut phosphor) => {
+            phosphor.render(canvas, |target| dvg.render(memory, target, port, sinks))
+        }
+        None => dvg.render(memory, canvas, port, sinks),
+    }
+}
+
+// the machine with the ROMs, option switches and debugging aids asked for
+fn build_machine(config: &Config) -> Machine {
+    let mut dip_settings = DipSettings::default();
+    if let Some(name) = config.value("coinage") {
+        dip_settings.coinage = Coinage::from_name(name).unwrap_or_else(|| {
+            exit_with_error(&format!(
+                "Unknown coinage '{}', expected free, 1c2c, 1c1c or 2c1c",
+                name
+            ))
+        });
+    }
+    if let Some(name) = config.value("language") {
+        dip_settings.language = Language::from_name(name).unwrap_or_else(|| {
+            exit_with_error(&format!(
+                "Unknown language '{}', expected english, german, french or spanish",
+                name
+            ))
+        });
+    }
+    if let Some(value) = config.value("lives") {
+        dip_settings.lives = match value.as_ref() {
+            "3" => 3,
+            "4" => 4,
+            _ => exit_with_error(&format!("Invalid number of lives '{}', expected 3 or 4", value)),
+        };
+    }
+    let mut memory = load_memory(config);
+    memory.set_dip_settings(dip_settings);
+    let mut machine = Machine::new(config.debug, config.flag("illegal-nop"), memory);
+    if config.value("coverage").is_some() {
+        machine.cpu.enable_coverage();
+    }
+    if config.flag("profile") {
+        machine.cpu.enable_profile();
+    }
+    if let Some(value) = config.value("watchdog") {
+        match value.parse::<u32>() {
+            Ok(0) => machine.set_watchdog(None),
+            Ok(nmis) => machine.set_watchdog(Some(nmis)),
+            Err(_) => exit_with_error(&format!("Invalid number of NMIs '{}'", value)),
+        }
+    }
+    if let Some(list) = config.value("break") {
+        for item in list.split(',').filter(|item| !item.is_empty()) {
+            match u16::from_str_radix(item.trim_start_matches('$'), 16) {
+                Ok(addr) => machine.cpu.add_breakpoint(addr),
+                Err(_) => exit_with_error(&format!("Invalid breakpoint address '{}'", item)),
+            }
+        }
+    }
+    if let Some(path) = config.value("trace") {
+        match File::create(path) {
+            Ok(file) => machine.cpu.set_trace_log(Box::new(BufWriter::new(file))),
+            Err(e) => exit_with_error(&format!("Error creating {}: {}", path, e)),
+        }
+    }
+    if let Some(range) = config.value("watch") {
+        let (start, end) = parse_address_range(range).unwrap_or_else(|e| exit_with_error(&e));
+        machine.memory.add_write_watch(start..=end);
+    }
+    if let Some(range) = config.value("watch-reads") {
+        let (start, end) = parse_address_range(range).unwrap_or_else(|e| exit_with_error(&e));
+        machine.memory.add_read_watch(start..=end);
+    }
+    // hardware quirks the game doesn't rely on, for other code that might
+    let accurate = config.flag("accurate");
+    machine.memory.set_open_bus(accurate || config.flag("open-bus"));
+    machine.cpu.set_dummy_writes(accurate);
+    machine
+}
+
+fn build_dvg(config: &Config, serialoutput: bool) -> Dvg {
+    let colors = match config.value("colors") {
+        Some(name) => ColorScheme::load(name).unwrap_or_else(|e| exit_with_error(&e)),
+        None => ColorScheme::classic(),
+    };
+    let mut dvg = Dvg::new(config.debug, serialoutput, colors);
+    if let Some(value) = config.value("serial-delay-us") {
+        let micros: u64 = value
+            .parse()
+            .unwrap_or_else(|_| exit_with_error(&format!("Invalid serial delay '{}'", value)));
+        dvg.set_serial_pacing(Duration::from_micros(micros), false);
+    }
+    if config.flag("serial-ack") {
+        dvg.set_serial_pacing(Duration::from_micros(0), true);
+    }
+    if let Some(value) = config.value("serial-packet") {
+        match value.parse::<usize>() {
+            Ok(vectors) if vectors >= 1 && vectors <= MAX_SERIAL_PACKET_VECTORS => {
+                dvg.set_serial_packet_size(vectors)
+            }
+            _ => exit_with_error(&format!(
+                "Serial packet size must be from 1 to {} vectors",
+                MAX_SERIAL_PACKET_VECTORS
+            )),
+        }
+    }
+    if let Some(value) = config.value("serial-frame-sync") {
+        let marker = parse_frame_sync_marker(value).unwrap_or_else(|e| exit_with_error(&e));
+        dvg.set_serial_frame_sync(Some(marker));
+    }
+    if let Some(value) = config.value("geometry-scale") {
+        match value.parse::<f64>() {
+            Ok(scale) if scale >= MIN_GEOMETRY_SCALE && scale <= MAX_GEOMETRY_SCALE => {
+                dvg.set_geometry_scale(scale)
+            }
+            _ => exit_with_error(&format!(
+                "Geometry scale must be between {} and {}",
+                MIN_GEOMETRY_SCALE, MAX_GEOMETRY_SCALE
+            )),
+        }
+    }
+    if let Some(value) = config.value("gamma") {
+        match value.parse::<f64>() {
+            Ok(gamma) if gamma >= MIN_GAMMA && gamma <= MAX_GAMMA => dvg.set_gamma(gamma),
+            _ => exit_with_error(&format!("Gamma must be between {} and {}", MIN_GAMMA, MAX_GAMMA)),
+        }
+    }
+    dvg.set_cocktail(config.flag("cocktail"));
+    dvg.set_strict_stack(config.flag("strict-dvg"));
+    if let Some(value) = config.value("beam-trace") {
+        match value.parse::<usize>() {
+            Ok(step) if step > 0 => dvg.set_beam_trace(Some(step)),
+            _ => exit_with_error(&format!("Invalid number of vectors '{}'", value)),
+        }
+    }
+    if let Some(path) = config.value("serial-exclusions") {
+        let rects = ExclusionRect::from_file(Path::new(path)).unwrap_or_else(|e| exit_with_error(&e));
+        dvg.serial_filter_mut().exclusions = rects;
+    }
+    if let Some(list) = config.value("serial-skip-z") {
+        for z in 0..16 {
+            dvg.serial_filter_mut().set_z_skipped(z, false);
+        }
+        for item in list.split(',').filter(|item| !item.is_empty()) {
+            match item.parse::<u16>() {
+                Ok(z) if z < 16 => dvg.serial_filter_mut().set_z_skipped(z, true),
+                _ => exit_with_error(&format!("Invalid intensity '{}'", item)),
+            }
+        }
+    }
+    dvg
+}
+
+fn load_memory(config: &Config) -> Memory {
+    let memory = match (&config.rom, &config.rom_set) {
+        (&Some(ref path), _) => Memory::from_rom_path(path),
+        (&None, &Some(ref paths)) => Memory::from_rom_set(paths),
+        (&None, &None) => Memory::new(),
+    };
+    let memory = memory.unwrap_or_else(|e| exit_with_error(&e));
+    // a bad dump tends to show up as an invalid op code well into a game,
+    // so point it out straight away
+    if memory.rom_revision().is_none() {
+        let checksums: Vec<String> = ROM_CHIP_NAMES
+            .iter()
+            .zip(memory.rom_checksums().iter())
+            .map(|(name, crc)| format!("{} {:08x}", name, crc))
+            .collect();
+        let message = format!(
+            "ROM doesn't match any known revision of Asteroids (CRC-32s {})",
+            checksums.join(", ")
+        );
+        if config.flag("strict-rom") {
+            exit_with_error(&message);
+        }
+        eprintln!("Warning: {}", message);
+    }
+    memory
+}
+
+// the vector display and control panel both use 8N1 without flow control.
+// the timeout bounds how long the display's acknowledgements are waited for
+fn serial_settings(baud_rate: u32) -> SerialPortSettings {
+    SerialPortSettings {
+        baud_rate,
+        timeout: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+// frame-1.svg, frame-2.svg and so on in the current directory, whichever
+// comes first that isn't already there
+fn next_free_path(stem: &str, extension: &str) -> PathBuf {
+    (1..)
+        .map(|number| PathBuf::from(format!("{}-{}.{}", stem, number, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn parse_audio_buffer(value: &str) -> Result<i32, String> {
+    let samples: i32 = value
+        .parse()
+        .map_err(|_| format!("Invalid audio buffer size '{}'", value))?;
+    if samples < MIN_AUDIO_BUFFER || samples > MAX_AUDIO_BUFFER || samples.count_ones() != 1 {
+        return Err(format!(
+            "Audio buffer size must be a power of two between {} and {} samples",
+            MIN_AUDIO_BUFFER, MAX_AUDIO_BUFFER
+        ));
+    }
+    Ok(samples)
+}
+
+fn parse_audio_rate(value: &str) -> Result<i32, String> {
+    let rate: i32 = value
+        .parse()
+        .map_err(|_| format!("Invalid audio sample rate '{}'", value))?;
+    if rate < MIN_AUDIO_RATE || rate > MAX_AUDIO_RATE {
+        return Err(format!(
+            "Audio sample rate must be between {} and {} Hz",
+            MIN_AUDIO_RATE, MAX_AUDIO_RATE
+        ));
+    }
+    Ok(rate)
+}
+
+// a decimal or 0x prefixed hex byte that can't be mistaken for an intensity
+fn parse_frame_sync_marker(value: &str) -> Result<u8, String> {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u8::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse()
+    };
+    match parsed {
+        Ok(marker) if marker > 15 => Ok(marker),
+        _ => Err(format!(
+            "Invalid frame sync marker '{}', expected a byte from 16 to 255",
+            value
+        )),
+    }
+}
+
+// a pair of hex addresses such as "6800-7FFF"
+fn parse_address_range(value: &str) -> Result<(u16, u16), String> {
+    let error = || format!("Invalid address range '{}', expected e.g. 6800-7FFF", value);
+    let mut parts = value.splitn(2, '-');
+    let mut next = || {
+        parts
+            .next()
+            .and_then(|part| u16::from_str_radix(part.trim_start_matches('$'), 16).ok())
+    };
+    match (next(), next()) {
+        (Some(start), Some(end)) if start <= end => Ok((start, end)),
+        _ => Err(error()),
+    }
 }
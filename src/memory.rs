@@ -1,34 +1,38 @@
 // Emulate memory and memory mapped IO for Asteroids game
 
 use find_folder;
-use std::fs::File;
+use std::cell::Cell;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
+use cpu::Bus;
+
+#[derive(Clone)]
 pub struct MappedIO {
-    pub clck3khz: u8, // from 0x2001
     pub halt: u8,
     pub swhyper: u8,
     pub swfire: u8,
-    //swdiagst: u8,
+    pub swdiagst: u8,
     //swslam: u8,
-    //swtest: u8,
+    pub swtest: u8,
 
-    //swlcoin: u8,    // from 0x2400
-    //swccoin: u8,
-    //swrcoin: u8,
+    // the coin switches, from 0x2400, hold the cycle they open again at
+    pub swlcoin: u64,
+    pub swccoin: u64,
+    pub swrcoin: u64,
     pub sw1start: u8,
     //sw2start: u8,
     pub swthrust: u8,
     pub swrotrght: u8,
     pub swrotleft: u8,
 
-    //swcoinage: u8,    // 0x2800
-    //swcnrmult: u8,
-    //swcncmult: u8,
-    //swlanguage: u8,
+    // the option switches at 0x2800-0x2803 are in Memory's DipSettings
     pub godvg: u8, // 0x3000
     //lmpscns: u8,  // 0x3200
-    //watchdog: u8, // 0x3400
+    pub watchdog_cleared: bool, // any write to 0x3400
     pub sndexp: u8,   // 0x3600
     pub sndthump: u8, // 0x3a00
     pub sndsaucr: u8, // from 0x3c00
@@ -40,20 +44,161 @@ pub struct MappedIO {
     pub sndreset: u8, // 0x3e00
 }
 
+// A15 isn't decoded, so everything at 8000-FFFF is a mirror of 0000-7FFF,
+// RAM, IO and all. that's how the reset and interrupt vectors at FFFA-FFFF
+// come from the end of the game ROM at 7FFA-7FFF
+const MIRROR_MASK: u16 = 0x7FFF;
+
+const ROM_SET_CHIPS: usize = 4;
+const ROM_CHIP_SIZE: usize = 2048;
+
+// CRC-32s of good dumps of each chip, in the order of ROM_CHIP_NAMES, as
+// listed by MAME
+pub const ROM_CHIP_NAMES: [&str; ROM_SET_CHIPS] = ["np3", "ef2", "h2", "j2"];
+const KNOWN_ROMS: [(&str, [u32; ROM_SET_CHIPS]); 3] = [
+    ("revision 1", [0x9969_9366, 0xE9BF_DA64, 0xE53C_28A9, 0x7D4E_3D05]),
+    ("revision 2", [0x8B71_FD9E, 0x0CC7_5459, 0x096E_D35C, 0x312C_AA02]),
+    ("revision 4", [0x8B71_FD9E, 0xB503_EAF7, 0x2523_3192, 0x312C_AA02]),
+];
+
+// how long a coin holds its switch closed. the game samples the switches
+// every NMI and only counts a coin that's there for several samples
+const COIN_PULSE_CYCLES: u64 = 150_000; // 100ms
+
+// the 3KHz clock read at 2001, in CPU cycles. it's a square wave, high for
+// the second half of each period
+const CLOCK_3KHZ_PERIOD: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoinSlot {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coinage {
+    FreePlay,
+    OneCoinTwoCredits,
+    OneCoinOneCredit,
+    TwoCoinsOneCredit,
+}
+
+impl Coinage {
+    pub fn from_name(name: &str) -> Option<Coinage> {
+        match name {
+            "free" => Some(Coinage::FreePlay),
+            "1c2c" => Some(Coinage::OneCoinTwoCredits),
+            "1c1c" => Some(Coinage::OneCoinOneCredit),
+            "2c1c" => Some(Coinage::TwoCoinsOneCredit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+}
+
+impl Language {
+    pub fn from_name(name: &str) -> Option<Language> {
+        match name {
+            "english" => Some(Language::English),
+            "german" => Some(Language::German),
+            "french" => Some(Language::French),
+            "spanish" => Some(Language::Spanish),
+            _ => None,
+        }
+    }
+}
+
+// the operator's option switches. the defaults are what the emulator has
+// always had, with every switch reading 0: free play with 4 ships
+#[derive(Debug, Clone, Copy)]
+pub struct DipSettings {
+    pub coinage: Coinage,
+    pub right_coin_multiplier: u8,  // 1, 4, 5 or 6
+    pub center_coin_multiplier: u8, // 1 or 2
+    pub language: Language,
+    pub lives: u8, // 3 or 4
+}
+
+impl DipSettings {
+    pub fn default() -> DipSettings {
+        DipSettings {
+            coinage: Coinage::FreePlay,
+            right_coin_multiplier: 1,
+            center_coin_multiplier: 1,
+            language: Language::English,
+            lives: 4,
+        }
+    }
+
+    // the eight switches as a byte, with the coinage in the top two bits
+    // and the language in the bottom two
+    fn switches(&self) -> u8 {
+        let coinage = match self.coinage {
+            Coinage::FreePlay => 0,
+            Coinage::OneCoinTwoCredits => 1,
+            Coinage::OneCoinOneCredit => 2,
+            Coinage::TwoCoinsOneCredit => 3,
+        };
+        let right = match self.right_coin_multiplier {
+            4 => 1,
+            5 => 2,
+            6 => 3,
+            _ => 0,
+        };
+        let center = if self.center_coin_multiplier == 2 { 1 } else { 0 };
+        let lives = if self.lives == 3 { 1 } else { 0 };
+        let language = match self.language {
+            Language::English => 0,
+            Language::German => 1,
+            Language::French => 2,
+            Language::Spanish => 3,
+        };
+        coinage << 6 | right << 4 | center << 3 | lives << 2 | language
+    }
+
+    // each of 0x2800-0x2803 reads a pair of switches in its low two bits,
+    // coinage first, with the rest of the byte pulled high
+    fn read(&self, addr: usize) -> u8 {
+        let shift = 2 * (3 - (addr & 3));
+        0xFC | (self.switches() >> shift) & 3
+    }
+}
+
+// a write to one of the sound registers, stamped with the CPU cycle
+#[derive(Debug, Clone, Copy)]
+pub struct SoundEvent {
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+}
+
 impl MappedIO {
-    fn new() -> MappedIO {
+    pub fn new() -> MappedIO {
         MappedIO {
-            clck3khz: 0,
             halt: 0,
             swhyper: 0,
             swfire: 0,
+            swdiagst: 0,
+            swtest: 0,
 
+            swlcoin: 0,
+            swccoin: 0,
+            swrcoin: 0,
             sw1start: 0,
             swthrust: 0,
             swrotrght: 0,
             swrotleft: 0,
 
             godvg: 0,
+            watchdog_cleared: false,
             sndexp: 0,
             sndthump: 0,
             sndsaucr: 0,
@@ -65,57 +210,326 @@ impl MappedIO {
             sndreset: 0,
         }
     }
+
+    // latch a write to a sound register, returning false if the address
+    // isn't one
+    pub fn write_sound(&mut self, addr: u16, byte: u8) -> bool {
+        match addr & MIRROR_MASK {
+            0x3600 => self.sndexp = byte,
+            0x3A00 => self.sndthump = byte,
+            0x3C00 => self.sndsaucr = byte,
+            0x3C01 => self.sndsfire = byte,
+            0x3C02 => self.sndselsau = byte,
+            0x3C03 => self.sndthrust = byte,
+            0x3C04 => self.sndfire = byte,
+            0x3C05 => self.sndbonus = byte,
+            0x3E00 => self.sndreset = byte,
+            _ => return false,
+        }
+        true
+    }
 }
 
+// an access to a watched address, stamped with the CPU cycle
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+    pub cycle: u64,
+}
+
+// there's no EAROM on the Asteroids board (Asteroids Deluxe was the first to
+// keep its high scores in one), so, as on the real machine, the high score
+// table lives in game RAM and is gone when the emulator exits. keeping it
+// would mean patching RAM behind the game's back after its power on code has
+// cleared it, rather than emulating hardware
 pub struct Memory {
     game_ram: [u8; 1024], // 0000-03FF / 8000-83FF
     dvg_ram: [u8; 4096],  // 4000-4FFF / C000-CFFF
     dvg_rom: [u8; 2048],  // 5000-57FF / D000-D7FF
     game_rom: [u8; 6144], // 6800-7FFF / E800-FFFF
     pub mapped_io: MappedIO,
+    // the CPU's count as of the instruction being run, passed on through
+    // Bus::set_cycle, to stamp events and for the 3KHz clock and coin switches
+    pub cycle: u64,
+    sound_events: Vec<SoundEvent>,
+    open_bus: bool,
+    dip_settings: DipSettings,
+    // level of the IRQ line, for peripherals to hold while they want
+    // service. nothing on the Asteroids board drives it
+    pub irq: bool,
+    watch_reads: Vec<RangeInclusive<u16>>,
+    watch_writes: Vec<RangeInclusive<u16>>,
+    watch_hit: Cell<Option<WatchHit>>, // set from get_byte, which only has &self
 }
 
 impl Memory {
-    pub fn new() -> Memory {
-        let mut memory = Memory {
+    // asteroids.rom in the assets folder
+    pub fn new() -> Result<Memory, String> {
+        let assets = find_folder::Search::ParentsThenKids(3, 3)
+            .for_folder("assets")
+            .map_err(|_| "Can't find the assets folder holding asteroids.rom".to_string())?;
+        Memory::from_rom_path(&assets.join("asteroids.rom"))
+    }
+
+    // the DVG ROM followed by the game ROM, as a single file
+    pub fn from_rom_path(path: &Path) -> Result<Memory, String> {
+        let mut memory = Memory::blank();
+        let mut file =
+            File::open(path).map_err(|e| format!("Error opening ROM file {}: {}", path.display(), e))?;
+        file.read_exact(&mut memory.dvg_rom)
+            .and_then(|_| file.read_exact(&mut memory.game_rom))
+            .map_err(|e| format!("Error reading ROM file {}: {}", path.display(), e))?;
+        Ok(memory)
+    }
+
+    // the chip dumps in the order they're concatenated into asteroids.rom:
+    // 035127-02.np3 (DVG), 035145-02.ef2, 035144-02.h2 and 035143-02.j2
+    pub fn from_rom_set(paths: &[PathBuf]) -> Result<Memory, String> {
+        if paths.len() != ROM_SET_CHIPS {
+            return Err(format!(
+                "Expected {} ROM chips, in the order 035127-02, 035145-02, 035144-02, 035143-02",
+                ROM_SET_CHIPS
+            ));
+        }
+        let mut image = Vec::with_capacity(ROM_SET_CHIPS * ROM_CHIP_SIZE);
+        for path in paths {
+            let chip = fs::read(path)
+                .map_err(|e| format!("Error reading ROM chip {}: {}", path.display(), e))?;
+            if chip.len() != ROM_CHIP_SIZE {
+                return Err(format!(
+                    "ROM chip {} is {} bytes, rather than {}",
+                    path.display(),
+                    chip.len(),
+                    ROM_CHIP_SIZE
+                ));
+            }
+            image.extend_from_slice(&chip);
+        }
+        let mut memory = Memory::blank();
+        let (dvg_rom, game_rom) = image.split_at(memory.dvg_rom.len());
+        memory.dvg_rom.copy_from_slice(dvg_rom);
+        memory.game_rom.copy_from_slice(game_rom);
+        Ok(memory)
+    }
+
+    // the CRC-32 of each chip's worth of ROM, in the order of ROM_CHIP_NAMES
+    pub fn rom_checksums(&self) -> [u32; ROM_SET_CHIPS] {
+        let mut checksums = [crc32(&self.dvg_rom), 0, 0, 0];
+        for (i, chip) in self.game_rom.chunks(ROM_CHIP_SIZE).enumerate() {
+            checksums[i + 1] = crc32(chip);
+        }
+        checksums
+    }
+
+    // which release of the game the ROM is, if it's a good dump of one
+    pub fn rom_revision(&self) -> Option<&'static str> {
+        let checksums = self.rom_checksums();
+        KNOWN_ROMS
+            .iter()
+            .find(|&&(_, known)| known == checksums)
+            .map(|&(name, _)| name)
+    }
+
+    // everything zero, ROM included, for the ROM to be filled in
+    pub fn blank() -> Memory {
+        Memory {
             game_ram: [0; 1024],
             dvg_ram: [0; 4096],
             dvg_rom: [0; 2048],
             game_rom: [0; 6144],
             mapped_io: MappedIO::new(),
-        };
+            cycle: 0,
+            sound_events: Vec::new(),
+            open_bus: false,
+            dip_settings: DipSettings::default(),
+            irq: false,
+            watch_reads: Vec::new(),
+            watch_writes: Vec::new(),
+            watch_hit: Cell::new(None),
+        }
+    }
 
-        let assets = find_folder::Search::ParentsThenKids(3, 3)
-            .for_folder("assets")
-            .unwrap();
-        let rom_file = assets.join("asteroids.rom");
-        let mut file = File::open(rom_file).expect("Error opening ROM file");
-        file.read_exact(&mut memory.dvg_rom).unwrap();
-        file.read_exact(&mut memory.game_rom).unwrap();
-        memory
+    // game RAM and vector RAM, for save states
+    pub fn game_ram(&self) -> &[u8] {
+        &self.game_ram
+    }
+
+    pub fn dvg_ram(&self) -> &[u8] {
+        &self.dvg_ram
+    }
+
+    pub fn restore_ram(&mut self, game_ram: &[u8], dvg_ram: &[u8]) -> Result<(), String> {
+        if game_ram.len() != self.game_ram.len() || dvg_ram.len() != self.dvg_ram.len() {
+            return Err("Save state RAM is the wrong size".to_string());
+        }
+        self.game_ram.copy_from_slice(game_ram);
+        self.dvg_ram.copy_from_slice(dvg_ram);
+        Ok(())
+    }
+
+    // the IO back as it is at power on, for a reset from the keyboard. ROM
+    // and RAM are left alone, as the game clears RAM itself, and the self
+    // test switch stays where it was set
+    pub fn reset_io(&mut self) {
+        let swtest = self.mapped_io.swtest;
+        self.mapped_io = MappedIO::new();
+        self.mapped_io.swtest = swtest;
+        self.irq = false;
+    }
+
+    // the switches can be changed at any time, as on the board, although
+    // the game only reads some of them as it starts up
+    pub fn set_dip_settings(&mut self, dip_settings: DipSettings) {
+        self.dip_settings = dip_settings;
+    }
+
+    // write little endian DVG words into vector RAM starting at a DVG word
+    // address (i.e. 0x4000 + word_addr * 2 as seen by the 6502)
+    pub fn load_dvg_words(&mut self, word_addr: u16, words: &[u16]) {
+        for (i, word) in words.iter().enumerate() {
+            let offset = (word_addr as usize + i) * 2;
+            if offset + 1 < self.dvg_ram.len() {
+                self.dvg_ram[offset] = (word & 0xFF) as u8;
+                self.dvg_ram[offset + 1] = (word >> 8) as u8;
+            }
+        }
+    }
+
+    // a span of memory as the CPU would read it, but without setting off
+    // watchpoints, for debuggers and the like. wraps at FFFF
+    pub fn dump(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.read_mapped(start.wrapping_add(i as u16)))
+            .collect()
+    }
+
+    // a single byte of dump
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.read_mapped(addr)
+    }
+
+    // little endian, also without setting off watchpoints
+    pub fn read_word(&self, addr: u16) -> u16 {
+        self.read_mapped(addr) as u16 | (self.read_mapped(addr.wrapping_add(1)) as u16) << 8
+    }
+
+    // addresses are watched as the CPU sees them, so a mirror of a watched
+    // location needs watching separately
+    pub fn add_read_watch(&mut self, range: RangeInclusive<u16>) {
+        self.watch_reads.push(range);
+    }
+
+    pub fn add_write_watch(&mut self, range: RangeInclusive<u16>) {
+        self.watch_writes.push(range);
+    }
+
+    // the latest watched access since the last call, if any
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn check_watch(&self, watches: &[RangeInclusive<u16>], addr: u16, value: u8, write: bool) {
+        if watches.iter().any(|range| range.contains(&addr)) {
+            self.watch_hit.set(Some(WatchHit {
+                address: addr,
+                value,
+                write,
+                cycle: self.cycle,
+            }));
+        }
     }
 
     pub fn get_byte(&self, addr: u16) -> u8 {
-        let addr = addr as usize & 0x7FFF;
+        let value = self.read_mapped(addr);
+        if !self.watch_reads.is_empty() {
+            self.check_watch(&self.watch_reads, addr, value, false);
+        }
+        value
+    }
+
+    fn read_mapped(&self, addr: u16) -> u8 {
+        let addr = (addr & MIRROR_MASK) as usize;
         match addr {
             a if a < 0x400 => self.game_ram[a],
             a if a >= 0x4000 && a < 0x5000 => self.dvg_ram[a - 0x4000],
             a if a >= 0x5000 && a < 0x5800 => self.dvg_rom[a - 0x5000],
             a if a >= 0x6800 => self.game_rom[a - 0x6800],
-            0x2001 => self.mapped_io.clck3khz,
+            0x2001 => self.clock_3khz(),
             0x2002 => self.mapped_io.halt,
             0x2403 => self.mapped_io.sw1start,
             0x2004 => self.mapped_io.swfire,
+            0x2005 => self.mapped_io.swdiagst,
+            0x2007 => self.mapped_io.swtest,
             0x2405 => self.mapped_io.swthrust,
             0x2406 => self.mapped_io.swrotrght,
             0x2407 => self.mapped_io.swrotleft,
             0x2003 => self.mapped_io.swhyper,
+            0x2400 => self.coin_switch(self.mapped_io.swlcoin),
+            0x2401 => self.coin_switch(self.mapped_io.swccoin),
+            0x2402 => self.coin_switch(self.mapped_io.swrcoin),
+            a if a >= 0x2800 && a < 0x2804 => self.dip_settings.read(a),
+            _ if self.open_bus => (addr >> 8) as u8,
             _ => 0,
         }
     }
 
+    // a square wave worked out from the cycle count, high for the second
+    // half of each period
+    fn clock_3khz(&self) -> u8 {
+        if self.cycle % CLOCK_3KHZ_PERIOD >= CLOCK_3KHZ_PERIOD / 2 {
+            0xFF
+        } else {
+            0
+        }
+    }
+
+    fn coin_switch(&self, until: u64) -> u8 {
+        if self.cycle < until {
+            0xFF
+        } else {
+            0
+        }
+    }
+
+    pub fn insert_coin(&mut self, slot: CoinSlot) {
+        let until = self.cycle + COIN_PULSE_CYCLES;
+        match slot {
+            CoinSlot::Left => self.mapped_io.swlcoin = until,
+            CoinSlot::Center => self.mapped_io.swccoin = until,
+            CoinSlot::Right => self.mapped_io.swrcoin = until,
+        }
+    }
+
+    // reads of unmapped or write only locations (such as the sound registers
+    // and godvg) return 0 by default. on the real hardware nothing drives the
+    // data bus, so the 6502 sees whatever was last on it, which for the usual
+    // absolute addressing is the high byte of the address just fetched. the
+    // game never relies on either, but other code might
+    pub fn set_open_bus(&mut self, open_bus: bool) {
+        self.open_bus = open_bus;
+    }
+
+    // every sound register write since the last call, oldest first, so that
+    // sounds can't be missed however the main loop batches up cycles
+    pub fn take_sound_events(&mut self) -> Vec<SoundEvent> {
+        mem::replace(&mut self.sound_events, Vec::new())
+    }
+
     pub fn set_byte(&mut self, addr: u16, byte: u8) {
-        let addr = addr as usize & 0x7FFF;
+        if !self.watch_writes.is_empty() {
+            self.check_watch(&self.watch_writes, addr, byte, true);
+        }
+        if self.mapped_io.write_sound(addr, byte) {
+            self.sound_events.push(SoundEvent {
+                cycle: self.cycle,
+                address: addr & MIRROR_MASK,
+                value: byte,
+            });
+            return;
+        }
+        let addr = (addr & MIRROR_MASK) as usize;
         match addr {
             a if a < 0x400 => {
                 self.game_ram[a] = byte;
@@ -123,37 +537,70 @@ impl Memory {
             a if a >= 0x4000 && a < 0x5000 => {
                 self.dvg_ram[a - 0x4000] = byte;
             }
-            0x2001 => {
-                self.mapped_io.clck3khz = byte;
-            }
             0x3000 => {
                 self.mapped_io.godvg = byte;
             }
-            0x3600 => {
-                self.mapped_io.sndexp = byte;
-            }
-            0x3A00 => {
-                self.mapped_io.sndthump = byte;
-            }
-            0x3C00 => {
-                self.mapped_io.sndsaucr = byte;
-            }
-            0x3C01 => {
-                self.mapped_io.sndsfire = byte;
-            }
-            0x3C02 => {
-                self.mapped_io.sndselsau = byte;
-            }
-            0x3C03 => {
-                self.mapped_io.sndthrust = byte;
-            }
-            0x3C04 => {
-                self.mapped_io.sndfire = byte;
-            }
-            0x3C05 => {
-                self.mapped_io.sndbonus = byte;
+            0x3400 => {
+                self.mapped_io.watchdog_cleared = true;
             }
             _ => {}
         }
     }
 }
+
+// the mapped IO is all handled by get_byte and set_byte, and the cycle count
+// the CPU passes on keeps the 3KHz clock and coin switches going
+impl Bus for Memory {
+    fn read(&self, addr: u16) -> u8 {
+        self.get_byte(addr)
+    }
+
+    fn write(&mut self, addr: u16, byte: u8) {
+        self.set_byte(addr, byte);
+    }
+
+    fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+}
+
+// the usual CRC-32, as used by zip files and ROM listings
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    #[test]
+    fn clock_3khz_has_a_period_of_500_cycles() {
+        let mut memory = Memory::blank();
+        let levels = [(0, 0), (249, 0), (250, 0xFF), (499, 0xFF), (500, 0), (750, 0xFF)];
+        for &(cycle, level) in &levels {
+            memory.cycle = cycle;
+            assert_eq!(memory.get_byte(0x2001), level, "cycle {}", cycle);
+        }
+    }
+
+    #[test]
+    fn cpu_passes_on_its_cycle_count() {
+        let mut memory = Memory::blank();
+        for addr in 0..0x100 {
+            memory.set_byte(addr, 0xEA); // NOP
+        }
+        let mut cpu = Cpu::new(false, false);
+        cpu.run_for_cycles(&mut memory, 300);
+        // as of the start of the last NOP
+        assert_eq!(memory.cycle, cpu.cycle - 2);
+        assert_eq!(memory.get_byte(0x2001), 0xFF);
+    }
+}
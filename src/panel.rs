@@ -0,0 +1,135 @@
+// read the game controls from a microcontroller over serial, for cabinets
+// with physical buttons
+//
+// mirroring the 5 byte commands sent to the serial display, the control
+// panel sends 5 byte packets:
+//
+//   0: PANEL_SYNC, which can't be mistaken for an intensity (0-15)
+//   1: buttons, a bit set while held
+//        bit 0 - 1 player start
+//        bit 1 - fire
+//        bit 2 - thrust
+//        bit 3 - hyperspace
+//        bit 4 - rotate left
+//        bit 5 - rotate right
+//        bits 6, 7 - reserved, send 0
+//   2: rotary encoder movement since the last packet, as a signed byte
+//      with positive being clockwise
+//   3, 4: reserved, send 0
+//
+// the panel only needs to send a packet when something changes. the game
+// only has rotate buttons, so each step of the encoder holds rotate in its
+// direction for one frame
+
+use serialport::prelude::*;
+use std::io;
+
+use memory::Memory;
+
+const PANEL_SYNC: u8 = 0xA5;
+const PACKET_SIZE: usize = 5;
+
+const BUTTON_START: u8 = 1 << 0;
+const BUTTON_FIRE: u8 = 1 << 1;
+const BUTTON_THRUST: u8 = 1 << 2;
+const BUTTON_HYPERSPACE: u8 = 1 << 3;
+const BUTTON_LEFT: u8 = 1 << 4;
+const BUTTON_RIGHT: u8 = 1 << 5;
+
+pub struct ControlPanel {
+    port: Box<dyn SerialPort>,
+    packet: Vec<u8>,
+    buttons: u8,
+    rotation: i32, // encoder steps still to be turned into frames of rotation
+    rotating: bool,
+}
+
+impl ControlPanel {
+    pub fn new(port: Box<dyn SerialPort>) -> ControlPanel {
+        ControlPanel {
+            port,
+            packet: Vec::with_capacity(PACKET_SIZE),
+            buttons: 0,
+            rotation: 0,
+            rotating: false,
+        }
+    }
+
+    // the switches read 0xFF while pressed
+    fn switch(buttons: u8, mask: u8) -> u8 {
+        if buttons & mask != 0 {
+            0xFF
+        } else {
+            0
+        }
+    }
+
+    // only switches whose button changed are written, so the keyboard keeps
+    // working alongside the panel
+    fn set_buttons(&mut self, buttons: u8, memory: &mut Memory) {
+        let changed = buttons ^ self.buttons;
+        self.buttons = buttons;
+        let io = &mut memory.mapped_io;
+        if changed & BUTTON_START != 0 {
+            io.sw1start = ControlPanel::switch(buttons, BUTTON_START);
+        }
+        if changed & BUTTON_FIRE != 0 {
+            io.swfire = ControlPanel::switch(buttons, BUTTON_FIRE);
+        }
+        if changed & BUTTON_THRUST != 0 {
+            io.swthrust = ControlPanel::switch(buttons, BUTTON_THRUST);
+        }
+        if changed & BUTTON_HYPERSPACE != 0 {
+            io.swhyper = ControlPanel::switch(buttons, BUTTON_HYPERSPACE);
+        }
+        if changed & BUTTON_LEFT != 0 {
+            io.swrotleft = ControlPanel::switch(buttons, BUTTON_LEFT);
+        }
+        if changed & BUTTON_RIGHT != 0 {
+            io.swrotrght = ControlPanel::switch(buttons, BUTTON_RIGHT);
+        }
+    }
+
+    fn read_packets(&mut self, memory: &mut Memory) -> io::Result<()> {
+        let available = self
+            .port
+            .bytes_to_read()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            as usize;
+        let mut bytes = vec![0; available];
+        self.port.read_exact(&mut bytes)?;
+        for byte in bytes {
+            // anything before a sync byte is noise or half a packet
+            if self.packet.is_empty() && byte != PANEL_SYNC {
+                continue;
+            }
+            self.packet.push(byte);
+            if self.packet.len() == PACKET_SIZE {
+                let (buttons, steps) = (self.packet[1], self.packet[2] as i8);
+                self.set_buttons(buttons, memory);
+                self.rotation += steps as i32;
+                self.packet.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // called once a frame
+    pub fn poll(&mut self, memory: &mut Memory) {
+        if let Err(e) = self.read_packets(memory) {
+            println!("Error reading control panel: {}", e);
+        }
+        if self.rotation != 0 {
+            let clockwise = self.rotation > 0;
+            memory.mapped_io.swrotrght = if clockwise { 0xFF } else { 0 };
+            memory.mapped_io.swrotleft = if clockwise { 0 } else { 0xFF };
+            self.rotation -= self.rotation.signum();
+            self.rotating = true;
+        } else if self.rotating {
+            // let go, unless the buttons are being held
+            self.rotating = false;
+            memory.mapped_io.swrotleft = ControlPanel::switch(self.buttons, BUTTON_LEFT);
+            memory.mapped_io.swrotrght = ControlPanel::switch(self.buttons, BUTTON_RIGHT);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// an overlay of how fast things are going, to see how close the emulation
+// is to keeping up and where the time goes when it doesn't:
+//
+//   game fps - frames the game draws per second of emulated time, about 60
+//   shown fps - frames presented per second of real time
+//   cpu - the cycle rate against the real 1.5MHz
+//
+// each is averaged over the last second
+
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::pixels;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use machine::CPU_CLOCK_HZ;
+
+const CHAR_SIZE: i16 = 8; // the gfx built in font is 8x8
+const MARGIN: i16 = 8;
+
+const AVERAGE_OVER: Duration = Duration::from_secs(1);
+
+// the rate a running total goes up at, over a sliding window of time
+pub struct RollingRate {
+    samples: VecDeque<(Instant, u64)>,
+    window: Duration,
+}
+
+impl RollingRate {
+    pub fn new(window: Duration) -> RollingRate {
+        RollingRate {
+            samples: VecDeque::new(),
+            window,
+        }
+    }
+
+    // the oldest sample kept is the last one at least a window old, so the
+    // rate always covers a full window once there's been time
+    pub fn record(&mut self, now: Instant, total: u64) {
+        self.samples.push_back((now, total));
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    // per second, or 0 until there are two samples
+    pub fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(&(first, first_total)), Some(&(last, last_total))) if last > first => {
+                let elapsed = last.duration_since(first);
+                let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+                // the total can go back, when a save state is loaded
+                last_total.saturating_sub(first_total) as f64 / seconds
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+pub struct PerfOverlay {
+    pub active: bool,
+    drawn: u64, // frames drawn by the game
+    shown: u64, // frames presented
+    drawn_rate: RollingRate,
+    shown_rate: RollingRate,
+    cycle_rate: RollingRate,
+}
+
+impl PerfOverlay {
+    pub fn new() -> PerfOverlay {
+        PerfOverlay {
+            active: false,
+            drawn: 0,
+            shown: 0,
+            drawn_rate: RollingRate::new(AVERAGE_OVER),
+            shown_rate: RollingRate::new(AVERAGE_OVER),
+            cycle_rate: RollingRate::new(AVERAGE_OVER),
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+    }
+
+    pub fn frame_drawn(&mut self) {
+        self.drawn += 1;
+    }
+
+    pub fn frame_shown(&mut self) {
+        self.shown += 1;
+    }
+
+    // called once per pass of the main loop with the CPU's cycle count
+    pub fn sample(&mut self, now: Instant, cycle: u64) {
+        self.drawn_rate.record(now, self.drawn);
+        self.shown_rate.record(now, self.shown);
+        self.cycle_rate.record(now, cycle);
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas<Window>) {
+        if !self.active {
+            return;
+        }
+        let cycle_rate = self.cycle_rate.rate();
+        // frames per second of emulated time, rather than real time
+        let game_fps = if cycle_rate > 0.0 {
+            self.drawn_rate.rate() * CPU_CLOCK_HZ as f64 / cycle_rate
+        } else {
+            0.0
+        };
+        let lines = [
+            format!("game fps  {:5.1}", game_fps),
+            format!("shown fps {:5.1}", self.shown_rate.rate()),
+            format!(
+                "cpu {:.3}MHz {:3.0}%",
+                cycle_rate / 1e6,
+                cycle_rate * 100.0 / CPU_CLOCK_HZ as f64
+            ),
+        ];
+        let text = pixels::Color::RGBA(0, 255, 0, 255);
+        let viewport = canvas.viewport();
+        let height = viewport.height() as i16;
+        let width = 18 * CHAR_SIZE;
+        let top = height - MARGIN * 2 - lines.len() as i16 * CHAR_SIZE * 2;
+        let _ = canvas.box_(0, top, MARGIN * 2 + width, height, pixels::Color::RGBA(0, 0, 0, 200));
+        for (i, line) in lines.iter().enumerate() {
+            let y = top + MARGIN + i as i16 * CHAR_SIZE * 2;
+            let _ = canvas.string(MARGIN, y, line, text);
+        }
+    }
+}
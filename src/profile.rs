@@ -0,0 +1,82 @@
+// how many times each op code and each instruction address has been
+// executed, to see where the ROM spends its time: the loops waiting on the
+// DVG or the next NMI, and the routines worth looking at closely
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use cpu::Instruction;
+
+// the busiest addresses listed in the report
+const REPORT_ADDRESSES: usize = 40;
+
+pub struct Profile {
+    op_codes: [u64; 256],
+    instructions: [Instruction; 256], // what each op code decoded as
+    addresses: HashMap<u16, (u64, Instruction)>,
+    total: u64,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            op_codes: [0; 256],
+            instructions: [Instruction::INVALID; 256],
+            addresses: HashMap::new(),
+            total: 0,
+        }
+    }
+
+    pub fn record(&mut self, address: u16, op_code: u8, instruction: Instruction) {
+        self.op_codes[op_code as usize] += 1;
+        self.instructions[op_code as usize] = instruction;
+        self.addresses.entry(address).or_insert((0, instruction)).0 += 1;
+        self.total += 1;
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+
+    // the op codes that were executed, then the busiest addresses, each with
+    // its count and share of all instructions, most executed first
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "{} instructions executed", self.total);
+
+        let mut op_codes: Vec<usize> = (0..256).filter(|&op| self.op_codes[op] > 0).collect();
+        op_codes.sort_by_key(|&op| (u64::max_value() - self.op_codes[op], op));
+        let _ = writeln!(report, "By op code:");
+        for op in op_codes {
+            let count = self.op_codes[op];
+            let _ = writeln!(
+                report,
+                "  {:02X} {:<4} {:>12} {:6.2}%",
+                op,
+                format!("{:?}", self.instructions[op]),
+                count,
+                self.percent(count)
+            );
+        }
+
+        let mut addresses: Vec<(&u16, &(u64, Instruction))> = self.addresses.iter().collect();
+        addresses.sort_by_key(|&(&address, &(count, _))| (u64::max_value() - count, address));
+        let _ = writeln!(
+            report,
+            "By address ({} of {}):",
+            addresses.len().min(REPORT_ADDRESSES),
+            addresses.len()
+        );
+        for (address, &(count, instruction)) in addresses.into_iter().take(REPORT_ADDRESSES) {
+            let _ = writeln!(
+                report,
+                "  {:04X} {:<4} {:>12} {:6.2}%",
+                address,
+                format!("{:?}", instruction),
+                count,
+                self.percent(count)
+            );
+        }
+        report
+    }
+}
@@ -0,0 +1,201 @@
+// record the game's input switches to a file and play them back, so a
+// session can be replayed exactly, e.g. to go with a bug report
+//
+// the emulation is deterministic, so a run is reproduced just by setting
+// the switches at the same cycles as before. a recording is a line for each
+// change of the switches:
+//
+//   <cycle> <switches>
+//
+// with the cycle in decimal and the switches as 4 hex digits, a bit set
+// while held:
+//
+//   bit 0 - 1 player start
+//   bit 1 - fire
+//   bit 2 - thrust
+//   bit 3 - hyperspace
+//   bit 4 - rotate left
+//   bit 5 - rotate right
+//   bit 6 - self test
+//   bit 7 - diagnostic step
+//   bits 8, 9, 10 - left, center and right coin
+//
+// lines starting with # are comments
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use memory::{CoinSlot, Memory};
+
+const SWITCH_START: u16 = 1 << 0;
+const SWITCH_FIRE: u16 = 1 << 1;
+const SWITCH_THRUST: u16 = 1 << 2;
+const SWITCH_HYPERSPACE: u16 = 1 << 3;
+const SWITCH_LEFT: u16 = 1 << 4;
+const SWITCH_RIGHT: u16 = 1 << 5;
+const SWITCH_TEST: u16 = 1 << 6;
+const SWITCH_DIAG: u16 = 1 << 7;
+const SWITCH_COINS: [(u16, CoinSlot); 3] = [
+    (1 << 8, CoinSlot::Left),
+    (1 << 9, CoinSlot::Center),
+    (1 << 10, CoinSlot::Right),
+];
+
+fn switch_bit(value: u8, bit: u16) -> u16 {
+    if value != 0 {
+        bit
+    } else {
+        0
+    }
+}
+
+fn switch_value(switches: u16, bit: u16) -> u8 {
+    if switches & bit != 0 {
+        0xFF
+    } else {
+        0
+    }
+}
+
+// the switches as they are at the cycle, coins included while they're held
+fn switch_state(cycle: u64, memory: &Memory) -> u16 {
+    let io = &memory.mapped_io;
+    let mut switches = switch_bit(io.sw1start, SWITCH_START)
+        | switch_bit(io.swfire, SWITCH_FIRE)
+        | switch_bit(io.swthrust, SWITCH_THRUST)
+        | switch_bit(io.swhyper, SWITCH_HYPERSPACE)
+        | switch_bit(io.swrotleft, SWITCH_LEFT)
+        | switch_bit(io.swrotrght, SWITCH_RIGHT)
+        | switch_bit(io.swtest, SWITCH_TEST)
+        | switch_bit(io.swdiagst, SWITCH_DIAG);
+    for &(bit, slot) in SWITCH_COINS.iter() {
+        let release = match slot {
+            CoinSlot::Left => io.swlcoin,
+            CoinSlot::Center => io.swccoin,
+            CoinSlot::Right => io.swrcoin,
+        };
+        if release > cycle {
+            switches |= bit;
+        }
+    }
+    switches
+}
+
+// a coin is dropped as its switch closes, and then drops through by itself
+fn set_switch_state(switches: u16, previous: u16, memory: &mut Memory) {
+    {
+        let io = &mut memory.mapped_io;
+        io.sw1start = switch_value(switches, SWITCH_START);
+        io.swfire = switch_value(switches, SWITCH_FIRE);
+        io.swthrust = switch_value(switches, SWITCH_THRUST);
+        io.swhyper = switch_value(switches, SWITCH_HYPERSPACE);
+        io.swrotleft = switch_value(switches, SWITCH_LEFT);
+        io.swrotrght = switch_value(switches, SWITCH_RIGHT);
+        io.swtest = switch_value(switches, SWITCH_TEST);
+        io.swdiagst = switch_value(switches, SWITCH_DIAG);
+    }
+    for &(bit, slot) in SWITCH_COINS.iter() {
+        if switches & bit != 0 && previous & bit == 0 {
+            memory.insert_coin(slot);
+        }
+    }
+}
+
+pub struct InputRecorder {
+    file: Option<BufWriter<File>>,
+    switches: u16,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> Result<InputRecorder, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Error creating {}: {}", path.display(), e))?;
+        let mut file = BufWriter::new(file);
+        writeln!(file, "# asteroids-emu input recording: cycle switches")
+            .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+        Ok(InputRecorder {
+            file: Some(file),
+            switches: 0,
+        })
+    }
+
+    // called wherever the switches might have been changed, before the CPU
+    // runs on. a write error stops the recording
+    pub fn capture(&mut self, cycle: u64, memory: &Memory) {
+        let switches = switch_state(cycle, memory);
+        if switches == self.switches {
+            return;
+        }
+        self.switches = switches;
+        let written = match self.file {
+            Some(ref mut file) => writeln!(file, "{} {:04X}", cycle, switches),
+            None => return,
+        };
+        if let Err(e) = written {
+            println!("Error writing input recording, stopping: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+pub struct InputPlayer {
+    changes: Vec<(u64, u16)>,
+    next: usize,
+    switches: u16,
+}
+
+impl InputPlayer {
+    pub fn parse(text: &str) -> Result<InputPlayer, String> {
+        let mut changes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let cycle = fields.next().and_then(|field| field.parse::<u64>().ok());
+            let switches = fields
+                .next()
+                .and_then(|field| u16::from_str_radix(field, 16).ok());
+            match (cycle, switches, fields.next()) {
+                (Some(cycle), Some(switches), None) => changes.push((cycle, switches)),
+                _ => {
+                    return Err(format!(
+                        "Invalid input recording on line {}: '{}'",
+                        number + 1,
+                        line
+                    ))
+                }
+            }
+        }
+        Ok(InputPlayer {
+            changes,
+            next: 0,
+            switches: 0,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<InputPlayer, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        InputPlayer::parse(&text)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.changes.len()
+    }
+
+    // sets the switches recorded up to now, at the same places in the loop
+    // they were captured
+    pub fn apply(&mut self, cycle: u64, memory: &mut Memory) {
+        while let Some(&(at, switches)) = self.changes.get(self.next) {
+            if at > cycle {
+                break;
+            }
+            set_switch_state(switches, self.switches, memory);
+            self.switches = switches;
+            self.next += 1;
+        }
+    }
+}
@@ -0,0 +1,304 @@
+// what the DVG draws its vectors on. coordinates are in pixels of the
+// target, with y downwards
+
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use std::fmt::Write;
+
+// the size of the picture the game uses, in DVG units
+pub const PICTURE_WIDTH: u32 = 1024;
+pub const PICTURE_HEIGHT: u32 = 832;
+
+// where to draw the picture at a whole multiple of its size, centred on an
+// output of the given size with black bars around it. the multiple is the
+// one asked for, or the largest that fits if that's too big
+pub fn letterbox(output: (u32, u32), scale: u32) -> Rect {
+    let (w, h) = output;
+    let fits = (w / PICTURE_WIDTH).min(h / PICTURE_HEIGHT).max(1);
+    let scale = scale.min(fits).max(1);
+    let (pw, ph) = (PICTURE_WIDTH * scale, PICTURE_HEIGHT * scale);
+    Rect::new((w as i32 - pw as i32) / 2, (h as i32 - ph as i32) / 2, pw, ph)
+}
+
+pub trait RenderTarget {
+    // width and height in pixels
+    fn size(&self) -> (u32, u32);
+    fn clear(&mut self);
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color);
+    // a line more than a pixel wide. targets that can't are given a thin one
+    fn draw_thick_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, width: u8, color: Color) {
+        let _ = width;
+        self.draw_line(x0, y0, x1, y1, color);
+    }
+    // a filled dot, for points and the beam
+    fn draw_point(&mut self, x: i16, y: i16, radius: i16, color: Color);
+    fn present(&mut self);
+}
+
+// drawing is within the canvas's viewport, which is the whole window
+// unless the picture is letterboxed
+impl RenderTarget for Canvas<Window> {
+    fn size(&self) -> (u32, u32) {
+        let viewport = self.viewport();
+        (viewport.width(), viewport.height())
+    }
+
+    fn clear(&mut self) {
+        self.set_draw_color(Color::RGB(0, 0, 0));
+        Canvas::clear(self);
+    }
+
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color) {
+        let _ = self.line(x0, y0, x1, y1, color);
+    }
+
+    fn draw_thick_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, width: u8, color: Color) {
+        let _ = self.thick_line(x0, y0, x1, y1, width, color);
+    }
+
+    fn draw_point(&mut self, x: i16, y: i16, radius: i16, color: Color) {
+        let _ = self.filled_circle(x, y, radius, color);
+    }
+
+    fn present(&mut self) {
+        Canvas::present(self);
+    }
+}
+
+// the picture is drawn on a texture that's kept from frame to frame, and
+// rather than being cleared each frame it's darkened a little, so vectors
+// glow on and fade away like on the monitor's phosphor
+pub struct Phosphor<'a> {
+    creator: &'a TextureCreator<WindowContext>,
+    texture: Option<Texture<'a>>, // None until the first frame, or if unsupported
+    fade: u8,                     // alpha of the black laid over the last frame
+}
+
+impl<'a> Phosphor<'a> {
+    // decay is the fraction of its brightness a vector loses each frame,
+    // from 0 (never fades) to 1 (gone straight away, as without phosphor)
+    pub fn new(creator: &'a TextureCreator<WindowContext>, decay: f64) -> Phosphor<'a> {
+        Phosphor {
+            creator,
+            texture: None,
+            fade: (decay.max(0.0).min(1.0) * 255.0).round() as u8,
+        }
+    }
+
+    // the texture is the size of the viewport, and is remade whenever that
+    // changes
+    fn update_texture(&mut self, canvas: &mut Canvas<Window>) {
+        let (w, h) = RenderTarget::size(canvas);
+        let stale = match self.texture {
+            Some(ref texture) => {
+                let query = texture.query();
+                query.width != w || query.height != h
+            }
+            None => true,
+        };
+        if !stale {
+            return;
+        }
+        self.texture = match self.creator.create_texture_target(None, w, h) {
+            Ok(mut texture) => {
+                let _ = canvas.with_texture_canvas(&mut texture, |texture_canvas| {
+                    texture_canvas.set_draw_color(Color::RGB(0, 0, 0));
+                    texture_canvas.clear();
+                });
+                Some(texture)
+            }
+            Err(e) => {
+                println!("Error creating phosphor texture, drawing without it: {}", e);
+                None
+            }
+        };
+    }
+
+    // draw is given the target to draw the frame on, which ends up on the
+    // canvas ready for anything else to be drawn over it
+    pub fn render<F: FnOnce(&mut FadingCanvas)>(&mut self, canvas: &mut Canvas<Window>, draw: F) {
+        self.update_texture(canvas);
+        let fade = self.fade;
+        match self.texture {
+            Some(ref mut texture) => {
+                let _ = canvas.with_texture_canvas(texture, |texture_canvas| {
+                    draw(&mut FadingCanvas {
+                        canvas: texture_canvas,
+                        fade,
+                    })
+                });
+                let _ = canvas.copy(texture, None, None);
+            }
+            None => draw(&mut FadingCanvas { canvas, fade: 255 }),
+        }
+    }
+}
+
+// a canvas whose clear darkens what's on it rather than wiping it
+pub struct FadingCanvas<'c> {
+    canvas: &'c mut Canvas<Window>,
+    fade: u8,
+}
+
+impl<'c> RenderTarget for FadingCanvas<'c> {
+    fn size(&self) -> (u32, u32) {
+        RenderTarget::size(self.canvas)
+    }
+
+    fn clear(&mut self) {
+        self.canvas.set_blend_mode(BlendMode::Blend);
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, self.fade));
+        let _ = self.canvas.fill_rect(None);
+        self.canvas.set_blend_mode(BlendMode::None);
+    }
+
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color) {
+        RenderTarget::draw_line(self.canvas, x0, y0, x1, y1, color);
+    }
+
+    fn draw_thick_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, width: u8, color: Color) {
+        RenderTarget::draw_thick_line(self.canvas, x0, y0, x1, y1, width, color);
+    }
+
+    fn draw_point(&mut self, x: i16, y: i16, radius: i16, color: Color) {
+        RenderTarget::draw_point(self.canvas, x, y, radius, color);
+    }
+
+    // the texture is shown when the frame is finished, so --beam-trace
+    // doesn't animate with phosphor on
+    fn present(&mut self) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrawnLine {
+    pub x0: i16,
+    pub y0: i16,
+    pub x1: i16,
+    pub y1: i16,
+    pub color: Color,
+}
+
+// keeps what was drawn since the last clear instead of showing it, for
+// running without a window and for checking the renderer. points are kept
+// as lines of no length
+pub struct RecordingTarget {
+    width: u32,
+    height: u32,
+    pub lines: Vec<DrawnLine>,
+    pub presents: u64,
+}
+
+impl RecordingTarget {
+    pub fn new(width: u32, height: u32) -> RecordingTarget {
+        RecordingTarget {
+            width,
+            height,
+            lines: Vec::new(),
+            presents: 0,
+        }
+    }
+}
+
+impl RenderTarget for RecordingTarget {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color) {
+        self.lines.push(DrawnLine {
+            x0,
+            y0,
+            x1,
+            y1,
+            color,
+        });
+    }
+
+    fn draw_point(&mut self, x: i16, y: i16, _radius: i16, color: Color) {
+        self.draw_line(x, y, x, y, color);
+    }
+
+    fn present(&mut self) {
+        self.presents += 1;
+    }
+}
+
+// builds an SVG document of what's drawn since the last clear, on a black
+// background. the size is in SVG user units
+pub struct SvgTarget {
+    width: u32,
+    height: u32,
+    elements: String,
+}
+
+impl SvgTarget {
+    pub fn new(width: u32, height: u32) -> SvgTarget {
+        SvgTarget {
+            width,
+            height,
+            elements: String::new(),
+        }
+    }
+
+    // the whole document
+    pub fn finish(self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n\
+             <rect width=\"{w}\" height=\"{h}\" fill=\"black\"/>\n{}</svg>\n",
+            self.elements,
+            w = self.width,
+            h = self.height
+        )
+    }
+}
+
+// the colour as an SVG paint, with the opacity alongside it
+fn svg_color(color: Color) -> (String, f32) {
+    (
+        format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b),
+        color.a as f32 / 255.0,
+    )
+}
+
+impl RenderTarget for SvgTarget {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn clear(&mut self) {
+        self.elements.clear();
+    }
+
+    fn draw_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, color: Color) {
+        self.draw_thick_line(x0, y0, x1, y1, 1, color);
+    }
+
+    fn draw_thick_line(&mut self, x0: i16, y0: i16, x1: i16, y1: i16, width: u8, color: Color) {
+        let (paint, opacity) = svg_color(color);
+        let _ = writeln!(
+            self.elements,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\" \
+             stroke-opacity=\"{:.3}\" stroke-width=\"{}\"/>",
+            x0, y0, x1, y1, paint, opacity, width
+        );
+    }
+
+    fn draw_point(&mut self, x: i16, y: i16, radius: i16, color: Color) {
+        let (paint, opacity) = svg_color(color);
+        let _ = writeln!(
+            self.elements,
+            "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\" fill-opacity=\"{:.3}\"/>",
+            x, y, radius, paint, opacity
+        );
+    }
+
+    fn present(&mut self) {}
+}
@@ -0,0 +1,78 @@
+// winding the game back a few seconds. a save state is taken every so often
+// as the game runs, keeping only the most recent, and holding the rewind key
+// steps back through them, newest first. RAM is small, so even a long
+// history doesn't take much memory
+
+use std::collections::VecDeque;
+
+use machine::Machine;
+use savestate::SaveState;
+
+pub const DEFAULT_INTERVAL_MS: u64 = 500;
+pub const DEFAULT_DEPTH: usize = 20;
+
+pub struct Rewind {
+    states: VecDeque<SaveState>, // oldest first
+    depth: usize,
+    interval: u64, // cycles between states
+    next_capture: u64,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, depth: usize) -> Rewind {
+        Rewind {
+            states: VecDeque::with_capacity(depth),
+            depth,
+            interval,
+            next_capture: 0,
+        }
+    }
+
+    // called between ticks, this keeps a state once per interval, making
+    // room by forgetting the oldest
+    pub fn capture(&mut self, machine: &Machine, next_nmi: u64) {
+        if machine.cpu.cycle < self.next_capture {
+            return;
+        }
+        if self.states.len() >= self.depth {
+            self.states.pop_front();
+        }
+        self.states.push_back(SaveState::capture(machine, next_nmi));
+        self.next_capture = machine.cpu.cycle + self.interval;
+    }
+
+    // the latest state, which is then forgotten so the next step goes back
+    // further. None once there's nothing older
+    pub fn step_back(&mut self) -> Option<SaveState> {
+        self.states.pop_back()
+    }
+
+    // carry on capturing from where the game has been wound back to
+    pub fn resume(&mut self, cycle: u64) {
+        self.next_capture = cycle + self.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    #[test]
+    fn keeps_the_latest_states_and_steps_back_newest_first() {
+        let mut machine = Machine::new(false, false, Memory::blank());
+        let mut rewind = Rewind::new(100, 3);
+        // next_nmi tells the states apart. the one at cycle 50 is too soon
+        // after the first to be kept
+        for &(cycle, next_nmi) in &[(0, 0), (50, 99), (100, 1), (200, 2), (300, 3), (400, 4)] {
+            machine.cpu.cycle = cycle;
+            rewind.capture(&machine, next_nmi);
+        }
+        let order: Vec<u64> = (0..4)
+            .filter_map(|_| rewind.step_back())
+            .map(|state| state.next_nmi)
+            .collect();
+        assert_eq!(order, vec![4, 3, 2]);
+        assert!(rewind.step_back().is_none());
+    }
+}
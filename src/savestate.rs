@@ -0,0 +1,330 @@
+// saving the whole machine to a file and picking up again from it later,
+// exactly where it left off. a save state holds:
+//
+//   the CPU's registers and cycle count
+//   game and vector RAM, the memory mapped IO (switches, sound registers and
+//   so on) and the IRQ line
+//   the watchdog count and when the next NMI is due
+//
+// the ROMs aren't saved, only their checksums, so a state can only be
+// loaded with the ROMs it was saved with. the DVG has nothing to save, as
+// it starts afresh each frame, and the option switches and debugging
+// settings come from the command line as usual
+//
+// the file is binary: a header and version, then the fields in the order
+// above, multi-byte values little endian
+
+use std::fs;
+use std::path::Path;
+
+use cpu::CpuState;
+use machine::Machine;
+use memory::MappedIO;
+
+const MAGIC: &[u8; 8] = b"ASTSTATE";
+const VERSION: u8 = 2;
+
+pub struct SaveState {
+    rom_checksums: [u32; 4],
+    cpu: CpuState,
+    game_ram: Vec<u8>,
+    dvg_ram: Vec<u8>,
+    mapped_io: MappedIO,
+    irq: bool,
+    nmis_since_watchdog: u32,
+    pub next_nmi: u64, // the cycle, kept by the main loop
+}
+
+// builds up the file
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn block(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+// takes the file apart again, failing if it's cut short
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < length {
+            return Err("Save state is cut short".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn block(&mut self) -> Result<Vec<u8>, String> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+fn write_mapped_io(out: &mut Writer, io: &MappedIO) {
+    for &byte in &[io.halt, io.swhyper, io.swfire, io.swdiagst, io.swtest] {
+        out.u8(byte);
+    }
+    for &cycle in &[io.swlcoin, io.swccoin, io.swrcoin] {
+        out.u64(cycle);
+    }
+    for &byte in &[
+        io.sw1start,
+        io.swthrust,
+        io.swrotrght,
+        io.swrotleft,
+        io.godvg,
+        io.watchdog_cleared as u8,
+        io.sndexp,
+        io.sndthump,
+        io.sndsaucr,
+        io.sndsfire,
+        io.sndselsau,
+        io.sndthrust,
+        io.sndfire,
+        io.sndbonus,
+        io.sndreset,
+    ] {
+        out.u8(byte);
+    }
+}
+
+fn read_mapped_io(input: &mut Reader) -> Result<MappedIO, String> {
+    let mut io = MappedIO::new();
+    io.halt = input.u8()?;
+    io.swhyper = input.u8()?;
+    io.swfire = input.u8()?;
+    io.swdiagst = input.u8()?;
+    io.swtest = input.u8()?;
+    io.swlcoin = input.u64()?;
+    io.swccoin = input.u64()?;
+    io.swrcoin = input.u64()?;
+    io.sw1start = input.u8()?;
+    io.swthrust = input.u8()?;
+    io.swrotrght = input.u8()?;
+    io.swrotleft = input.u8()?;
+    io.godvg = input.u8()?;
+    io.watchdog_cleared = input.u8()? != 0;
+    io.sndexp = input.u8()?;
+    io.sndthump = input.u8()?;
+    io.sndsaucr = input.u8()?;
+    io.sndsfire = input.u8()?;
+    io.sndselsau = input.u8()?;
+    io.sndthrust = input.u8()?;
+    io.sndfire = input.u8()?;
+    io.sndbonus = input.u8()?;
+    io.sndreset = input.u8()?;
+    Ok(io)
+}
+
+impl SaveState {
+    // between instructions, as the main loop does between ticks
+    pub fn capture(machine: &Machine, next_nmi: u64) -> SaveState {
+        let memory = &machine.memory;
+        SaveState {
+            rom_checksums: memory.rom_checksums(),
+            cpu: machine.cpu.snapshot(),
+            game_ram: memory.game_ram().to_vec(),
+            dvg_ram: memory.dvg_ram().to_vec(),
+            mapped_io: memory.mapped_io.clone(),
+            irq: memory.irq,
+            nmis_since_watchdog: machine.nmis_since_watchdog(),
+            next_nmi,
+        }
+    }
+
+    // refuses to put the state into a machine with different ROMs, as the
+    // game would carry on running code that isn't there
+    pub fn restore(&self, machine: &mut Machine) -> Result<(), String> {
+        if machine.memory.rom_checksums() != self.rom_checksums {
+            return Err("The save state is from different ROMs".to_string());
+        }
+        machine.cpu.restore(self.cpu);
+        machine.memory.restore_ram(&self.game_ram, &self.dvg_ram)?;
+        machine.memory.mapped_io = self.mapped_io.clone();
+        machine.memory.irq = self.irq;
+        machine.memory.cycle = self.cpu.cycle;
+        machine.set_nmis_since_watchdog(self.nmis_since_watchdog);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer { bytes: MAGIC.to_vec() };
+        out.u8(VERSION);
+        for &checksum in &self.rom_checksums {
+            out.u32(checksum);
+        }
+        let cpu = &self.cpu;
+        for &byte in &[cpu.a, cpu.x, cpu.y, cpu.s, cpu.p] {
+            out.u8(byte);
+        }
+        out.u16(cpu.pc);
+        out.u64(cpu.cycle);
+        out.block(&self.game_ram);
+        out.block(&self.dvg_ram);
+        write_mapped_io(&mut out, &self.mapped_io);
+        out.u8(self.irq as u8);
+        out.u32(self.nmis_since_watchdog);
+        out.u64(self.next_nmi);
+        out.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SaveState, String> {
+        let mut input = Reader { bytes };
+        if input.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err("Not a save state".to_string());
+        }
+        let version = input.u8()?;
+        if version != VERSION {
+            return Err(format!("Unsupported save state version {}", version));
+        }
+        let mut rom_checksums = [0; 4];
+        for checksum in rom_checksums.iter_mut() {
+            *checksum = input.u32()?;
+        }
+        let (a, x, y, s, p) = (input.u8()?, input.u8()?, input.u8()?, input.u8()?, input.u8()?);
+        let pc = input.u16()?;
+        let cycle = input.u64()?;
+        let state = SaveState {
+            rom_checksums,
+            cpu: CpuState { a, x, y, pc, s, p, cycle },
+            game_ram: input.block()?,
+            dvg_ram: input.block()?,
+            mapped_io: read_mapped_io(&mut input)?,
+            irq: input.u8()? != 0,
+            nmis_since_watchdog: input.u32()?,
+            next_nmi: input.u64()?,
+        };
+        if !input.bytes.is_empty() {
+            return Err("Save state has extra data at the end".to_string());
+        }
+        Ok(state)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Error writing {}: {}", path.display(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<SaveState, String> {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        SaveState::from_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::Memory;
+
+    // a loop at 0200 adding up in zero page: LDA $10, CLC, ADC #$07,
+    // STA $10, ADC $11, STA $11, JMP $0200
+    const PROGRAM: [u8; 14] = [
+        0xA5, 0x10, 0x18, 0x69, 0x07, 0x85, 0x10, 0x65, 0x11, 0x85, 0x11, 0x4C, 0x00, 0x02,
+    ];
+
+    fn machine() -> Machine {
+        let mut machine = Machine::new(false, false, Memory::blank());
+        for (i, &byte) in PROGRAM.iter().enumerate() {
+            machine.memory.set_byte(0x0200 + i as u16, byte);
+        }
+        machine.cpu.restore(CpuState { a: 0, x: 0, y: 0, pc: 0x0200, s: 0xFF, p: 0x24, cycle: 0 });
+        machine
+    }
+
+    fn run(machine: &mut Machine, instructions: usize) {
+        for _ in 0..instructions {
+            machine.cpu.execute_instruction(&mut machine.memory);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut machine = machine();
+        run(&mut machine, 10);
+        // every register a different value, so fields read back in the
+        // wrong order would show
+        let io = &mut machine.memory.mapped_io;
+        let mut bytes = [
+            &mut io.halt, &mut io.swhyper, &mut io.swfire, &mut io.swdiagst, &mut io.swtest,
+            &mut io.sw1start, &mut io.swthrust, &mut io.swrotrght, &mut io.swrotleft,
+            &mut io.godvg, &mut io.sndexp, &mut io.sndthump, &mut io.sndsaucr,
+            &mut io.sndsfire, &mut io.sndselsau, &mut io.sndthrust, &mut io.sndfire,
+            &mut io.sndbonus, &mut io.sndreset,
+        ];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            **byte = i as u8 + 1;
+        }
+        io.swlcoin = 1000;
+        io.swccoin = 2000;
+        io.swrcoin = 3000;
+        io.watchdog_cleared = true;
+        machine.memory.irq = true;
+        machine.set_nmis_since_watchdog(3);
+        let bytes = SaveState::capture(&machine, 6000).to_bytes();
+        let state = SaveState::from_bytes(&bytes).unwrap();
+        assert_eq!(state.to_bytes(), bytes);
+        assert_eq!(state.next_nmi, 6000);
+        assert!(SaveState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn loading_replays_the_same() {
+        let mut machine = machine();
+        run(&mut machine, 25);
+        let bytes = SaveState::capture(&machine, 0).to_bytes();
+        run(&mut machine, 50);
+        let (cpu, ram) = (machine.cpu.snapshot(), machine.memory.game_ram().to_vec());
+
+        SaveState::from_bytes(&bytes).unwrap().restore(&mut machine).unwrap();
+        run(&mut machine, 50);
+        assert_eq!(machine.cpu.snapshot(), cpu);
+        assert_eq!(machine.memory.game_ram(), &ram[..]);
+    }
+}
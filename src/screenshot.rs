@@ -0,0 +1,123 @@
+// save what's in the window as a PNG. the pixels are read straight after
+// the frame is drawn, then compressed and written on a thread of their own
+// so the game doesn't stutter
+//
+// the PNG is written without compression (stored deflate blocks), which
+// keeps the encoder small at the cost of bigger files
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use memory::crc32;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// the most a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// a PNG of 8 bit RGB pixels, rows top to bottom with nothing between them
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let row_length = width as usize * 3;
+    assert_eq!(rgb.len(), row_length * height as usize);
+
+    // each row is preceded by its filter type, which is always none
+    let mut raw = Vec::with_capacity((row_length + 1) * height as usize);
+    for row in rgb.chunks(row_length.max(1)) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // a zlib stream of stored blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        zlib.push(last as u8);
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGB, then the standard compression, filtering
+    // and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+// screenshot-<milliseconds since 1970>.png beside the executable, or in the
+// current directory if it can't be found
+fn screenshot_path() -> PathBuf {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() * 1000 + time.subsec_millis() as u64)
+        .unwrap_or(0);
+    let name = format!("screenshot-{}.png", millis);
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(&name)))
+        .unwrap_or_else(|| PathBuf::from(&name))
+}
+
+// called with the frame drawn but not yet presented, as what's in the
+// canvas after presenting isn't defined. captures the picture at the size
+// it's drawn, leaving out any letterbox bars. the viewport is passed
+// explicitly, as without a rectangle the pixels are read from the viewport
+// into a buffer sized for the whole window
+pub fn save(canvas: &Canvas<Window>) {
+    let viewport = canvas.viewport();
+    let captured = canvas
+        .read_pixels(Some(viewport), PixelFormatEnum::RGB24)
+        .map(|pixels| ((viewport.width(), viewport.height()), pixels));
+    let ((width, height), pixels) = match captured {
+        Ok(captured) => captured,
+        Err(e) => {
+            println!("Error taking screenshot: {}", e);
+            return;
+        }
+    };
+    let path = screenshot_path();
+    thread::spawn(move || {
+        let png = encode_png(width, height, &pixels);
+        match fs::write(&path, png) {
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => println!("Error writing {}: {}", path.display(), e),
+        }
+    });
+}
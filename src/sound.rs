@@ -1,41 +1,130 @@
 use find_folder;
-use sdl2::mixer::{Chunk, Channel};
-use memory::Memory;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sdl2::mixer::{Chunk, Channel, MAX_VOLUME};
+use memory::{MappedIO, SoundEvent};
 
-struct SoundEffect {
-    signal: u8,
+// how a sound effect is heard. the edge detection in Sounds only drives
+// this, so it can be pointed at something other than the mixer
+pub trait Voice {
+    fn play(&mut self);
+    fn play_continuous(&mut self);
+    fn stop(&mut self); // fades a continuous sound out
+    fn halt(&mut self); // stops a continuous sound dead
+    fn playing(&self) -> bool; // only tracked for continuous play
+    fn set_volume(&mut self, volume: i32); // takes effect straight away
+    fn set_fade(&mut self, fade: Duration); // for continuous sounds
+}
+
+// once the extra life sound has started it isn't started again for this
+// long, however long the game holds the signal up
+const EXTRA_LIFE_COOLDOWN: Duration = Duration::from_millis(3_300);
+
+// one mixer channel for each effect, so that a short sound can't take over
+// the channel of a loop such as thrust and leave it cut off or stuck on
+pub const MIXER_CHANNELS: i32 = 9;
+
+// stopping a loop dead clicks, where the hardware's sounds decay
+pub const DEFAULT_FADE: Duration = Duration::from_millis(30);
+
+// a sample played through the SDL mixer on a channel of its own
+struct ChunkVoice {
     chunk: Chunk,
-    channel: Option<Channel>,
+    channel: Channel,
+    looping: bool,
+    fade_ms: i32,
 }
 
-impl SoundEffect {
-    fn new(file_name: &str) -> SoundEffect {
-        let assets = find_folder::Search::ParentsThenKids(3, 3)
-            .for_folder("assets").unwrap();
-        let path = assets.join(file_name);
-        let sound_chunk_res = Chunk::from_file(&path);
-        let chunk = match sound_chunk_res {
-            Ok(sound_chunk) => sound_chunk,
-            _ => {panic!("Failed to load sound file {:?}", path);},
-        };
-        SoundEffect {signal: 0, chunk, channel: None}
+impl ChunkVoice {
+    fn new(path: &Path, channel: i32) -> Result<ChunkVoice, String> {
+        let chunk = Chunk::from_file(path)
+            .map_err(|e| format!("Failed to load sound file {}: {}", path.display(), e))?;
+        Ok(ChunkVoice {chunk, channel: Channel(channel), looping: false,
+                       fade_ms: DEFAULT_FADE.as_millis() as i32})
     }
+}
 
-    fn play(&self) {
-        let _play_res = Channel::all().play(&self.chunk, 0);
+impl Voice for ChunkVoice {
+    fn play(&mut self) {
+        let _play_res = self.channel.play(&self.chunk, 0);
     }
 
     fn play_continuous(&mut self) {
-        let play_res = Channel::all().play(&self.chunk, 1000);
-        self.channel = if let Ok(ch) = play_res {Some(ch)} else {None};
+        self.looping = self.channel.fade_in(&self.chunk, 1000, self.fade_ms).is_ok();
     }
 
     fn stop(&mut self) {
-        if let Some(ch) = self.channel {
-            ch.halt();
-            self.channel = None;
+        if self.looping {
+            self.channel.fade_out(self.fade_ms);
+            self.looping = false;
+        }
+    }
+
+    fn halt(&mut self) {
+        if self.looping {
+            self.channel.halt();
+            self.looping = false;
         }
     }
+
+    fn playing(&self) -> bool {
+        self.looping
+    }
+
+    // the mixer applies a chunk's volume as it mixes, so this reaches a
+    // loop that's already playing
+    fn set_volume(&mut self, volume: i32) {
+        self.chunk.set_volume(volume);
+    }
+
+    fn set_fade(&mut self, fade: Duration) {
+        self.fade_ms = fade.as_millis() as i32;
+    }
+}
+
+// stands in for an effect whose sample couldn't be loaded
+struct SilentVoice;
+
+impl Voice for SilentVoice {
+    fn play(&mut self) {}
+
+    fn play_continuous(&mut self) {}
+
+    fn stop(&mut self) {}
+
+    fn halt(&mut self) {}
+
+    fn playing(&self) -> bool {
+        false
+    }
+
+    fn set_volume(&mut self, _volume: i32) {}
+
+    fn set_fade(&mut self, _fade: Duration) {}
+}
+
+struct SoundEffect {
+    signal: u8,
+    voice: Box<dyn Voice>,
+    volume: i32, // before the master volume is applied
+}
+
+impl SoundEffect {
+    fn play(&mut self) {
+        self.voice.play();
+    }
+
+    fn play_continuous(&mut self) {
+        self.voice.play_continuous();
+    }
+
+    fn stop(&mut self) {
+        self.voice.stop();
+    }
+
+    fn halt(&mut self) {
+        self.voice.halt();
+    }
 }
 
 pub struct Sounds {
@@ -48,47 +137,245 @@ pub struct Sounds {
     thump_low: SoundEffect,
     thump_high: SoundEffect,
     thrust: SoundEffect,
-    extra_life_countdown: u32,
+    extra_life_cooldown: Duration, // left before it can sound again
+    master_volume: i32,
+    enabled: bool,
+    paused: bool,
+    io: MappedIO, // our copy of the sound registers, replayed from events
 }
 
 impl Sounds {
+    // the samples in the assets folder. needs MIXER_CHANNELS channels
+    // allocated
     pub fn new() -> Sounds {
+        let dir = find_folder::Search::ParentsThenKids(3, 3)
+            .for_folder("assets")
+            .unwrap_or_else(|_| PathBuf::from("assets"));
+        Sounds::new_with_dir(&dir)
+    }
+
+    // sound is optional, so an effect whose sample is missing or won't load
+    // is silent rather than an error
+    pub fn new_with_dir(dir: &Path) -> Sounds {
+        Sounds::with_voices(|file_name, channel| {
+            match ChunkVoice::new(&dir.join(file_name), channel) {
+                Ok(voice) => Box::new(voice),
+                Err(e) => {
+                    println!("{}, so it will be silent", e);
+                    Box::new(SilentVoice)
+                }
+            }
+        })
+    }
+
+    // make_voice is given the name of each effect's sample file and the
+    // mixer channel set aside for it
+    fn with_voices<F: Fn(&str, i32) -> Box<dyn Voice>>(make_voice: F) -> Sounds {
+        let effect = |file_name, channel| SoundEffect {
+            signal: 0,
+            voice: make_voice(file_name, channel),
+            volume: MAX_VOLUME,
+        };
         Sounds {
-            ship_fire: SoundEffect::new("ship_fire.ogg"),
-            explosion: SoundEffect::new("explosion.ogg"),
-            large_ufo: SoundEffect::new("large_ufo.ogg"),
-            small_ufo: SoundEffect::new("small_ufo.ogg"),
-            ufo_fire: SoundEffect::new("ufo_fire.ogg"),
-            extra_life: SoundEffect::new("extra_life.ogg"),
-            thump_low: SoundEffect::new("thump_low.ogg"),
-            thump_high: SoundEffect::new("thump_high.ogg"),
-            thrust: SoundEffect::new("thrust.ogg"),
-            extra_life_countdown: 0,
+            ship_fire: effect("ship_fire.ogg", 0),
+            explosion: effect("explosion.ogg", 1),
+            large_ufo: effect("large_ufo.ogg", 2),
+            small_ufo: effect("small_ufo.ogg", 3),
+            ufo_fire: effect("ufo_fire.ogg", 4),
+            extra_life: effect("extra_life.ogg", 5),
+            thump_low: effect("thump_low.ogg", 6),
+            thump_high: effect("thump_high.ogg", 7),
+            thrust: effect("thrust.ogg", 8),
+            extra_life_cooldown: Duration::from_millis(0),
+            master_volume: MAX_VOLUME,
+            enabled: true,
+            paused: false,
+            io: MappedIO::new(),
+        }
+    }
+
+    fn effects_mut(&mut self) -> [(&'static str, &mut SoundEffect); 9] {
+        [
+            ("ship_fire", &mut self.ship_fire),
+            ("explosion", &mut self.explosion),
+            ("large_ufo", &mut self.large_ufo),
+            ("small_ufo", &mut self.small_ufo),
+            ("ufo_fire", &mut self.ufo_fire),
+            ("extra_life", &mut self.extra_life),
+            ("thump_low", &mut self.thump_low),
+            ("thump_high", &mut self.thump_high),
+            ("thrust", &mut self.thrust),
+        ]
+    }
+
+    // give every voice its effect's volume scaled by the master volume
+    fn apply_volumes(&mut self) {
+        let master = self.master_volume;
+        for (_, effect) in self.effects_mut().iter_mut() {
+            let volume = effect.volume * master / MAX_VOLUME;
+            effect.voice.set_volume(volume);
+        }
+    }
+
+    // levels run from 0 to MAX_VOLUME, with anything outside that clamped.
+    // effects are named after their sample files, e.g. "thump_low"
+    pub fn set_volume(&mut self, name: &str, level: i32) -> Result<(), String> {
+        let level = level.max(0).min(MAX_VOLUME);
+        match self.effects_mut().iter_mut().find(|(n, _)| *n == name) {
+            Some((_, effect)) => effect.volume = level,
+            None => return Err(format!("Unknown sound effect '{}'", name)),
+        }
+        self.apply_volumes();
+        Ok(())
+    }
+
+    pub fn set_master_volume(&mut self, level: i32) {
+        self.master_volume = level.max(0).min(MAX_VOLUME);
+        self.apply_volumes();
+    }
+
+    // how long the loops take to fade in and out
+    pub fn set_fade(&mut self, fade: Duration) {
+        for (_, effect) in self.effects_mut().iter_mut() {
+            effect.voice.set_fade(fade);
+        }
+    }
+
+    // while disabled the game's sound signals are still followed, but
+    // nothing is played
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.enabled {
+            return;
+        }
+        self.enabled = enabled;
+        self.resync();
+    }
+
+    // pausing stops the loops until it's resumed, leaving sound on or off
+    // as it was
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
         }
+        self.paused = paused;
+        self.resync();
+    }
+
+    fn resync(&mut self) {
+        if !self.enabled || self.paused {
+            self.large_ufo.halt();
+            self.small_ufo.halt();
+            self.thrust.halt();
+            return;
+        }
+        // start any loops the game has on now, without replaying the one
+        // shot effects whose signals are still up
+        self.ship_fire.signal = self.io.sndfire;
+        self.explosion.signal = self.io.sndexp & 0x3F;
+        self.ufo_fire.signal = self.io.sndsfire;
+        self.thump_low.signal = self.io.sndthump;
+        if self.io.sndbonus > 0 {
+            self.extra_life_cooldown = EXTRA_LIFE_COOLDOWN;
+        }
+        self.large_ufo.signal = 0;
+        self.thrust.signal = 0;
+        self.update();
+    }
+
+    // after loading a save state, take up the sound registers as they were
+    // saved: the loops the game had on play, the others stop, and one shot
+    // effects aren't replayed
+    pub fn restore(&mut self, io: &MappedIO) {
+        self.large_ufo.halt();
+        self.small_ufo.halt();
+        self.thrust.halt();
+        self.io = io.clone();
+        self.resync();
+    }
+
+    // returns whether sound is now on
+    pub fn toggle_enabled(&mut self) -> bool {
+        let enabled = !self.enabled;
+        self.set_enabled(enabled);
+        enabled
     }
 
-    pub fn play(&mut self, memory: &Memory) {
+    // sound test mode: play one of the effects directly, numbered 1 to 9.
+    // the looping sounds toggle on and off with each press
+    pub fn test_effect(&mut self, number: u32) {
+        let (effect, continuous) = match number {
+            1 => (&mut self.ship_fire, false),
+            2 => (&mut self.explosion, false),
+            3 => (&mut self.large_ufo, true),
+            4 => (&mut self.small_ufo, true),
+            5 => (&mut self.ufo_fire, false),
+            6 => (&mut self.extra_life, false),
+            7 => (&mut self.thump_low, false),
+            8 => (&mut self.thump_high, false),
+            9 => (&mut self.thrust, true),
+            _ => return,
+        };
+        if !continuous {
+            effect.play();
+        } else if effect.voice.playing() {
+            effect.stop();
+        } else {
+            effect.play_continuous();
+        }
+    }
+
+    // called once per tick with the sound register writes made since the
+    // last call and the emulated time that's gone by. each write is applied
+    // in turn so that a signal raised and dropped again within a tick still
+    // produces its sound
+    pub fn play(&mut self, events: &[SoundEvent], elapsed: Duration) {
+        if !self.enabled {
+            for event in events {
+                self.io.write_sound(event.address, event.value);
+            }
+            return;
+        }
+        for event in events {
+            self.io.write_sound(event.address, event.value);
+            self.update();
+        }
+        self.extra_life();
+        self.extra_life_cooldown = self
+            .extra_life_cooldown
+            .checked_sub(elapsed)
+            .unwrap_or(Duration::from_millis(0));
+    }
+
+    fn extra_life(&mut self) {
+        let signal = self.io.sndbonus;
+        if signal > 0 && self.extra_life_cooldown == Duration::from_millis(0) {
+            self.extra_life.play();
+            self.extra_life_cooldown = EXTRA_LIFE_COOLDOWN;
+        }
+    }
+
+    fn update(&mut self) {
         // generally a sound effect is off at zero signal and endures for
         // a non-zero signal. we use that transition from low to high to
         // initiate a pre-prepared sound 
-        let signal = memory.mapped_io.sndfire;
+        let signal = self.io.sndfire;
         if self.ship_fire.signal < signal {
             self.ship_fire.play();
         }
         self.ship_fire.signal = signal;
 
         // explosion
-        let signal = memory.mapped_io.sndexp & 0x3F;
+        let signal = self.io.sndexp & 0x3F;
         if self.explosion.signal < signal {
             self.explosion.play();
         }
         self.explosion.signal = signal;
 
         // ufo (use large ufo to store last signal)
-        let signal = memory.mapped_io.sndsaucr;
+        let signal = self.io.sndsaucr;
         if self.large_ufo.signal < signal {
             // start ufo sound
-            if memory.mapped_io.sndselsau == 160 {
+            if self.io.sndselsau == 160 {
                 self.large_ufo.play_continuous();
             }
             else {
@@ -102,24 +389,17 @@ impl Sounds {
         self.large_ufo.signal = signal;
 
         // ufo fire
-        let signal = memory.mapped_io.sndsfire;
+        let signal = self.io.sndsfire;
         if self.ufo_fire.signal < signal {
             self.ufo_fire.play();
         }
         self.ufo_fire.signal = signal;
 
         // extra life
-        let signal = memory.mapped_io.sndbonus;
-        if signal > 0 && self.extra_life_countdown == 0 {
-            self.extra_life.play();
-            self.extra_life_countdown = 10000;
-        }
-        if self.extra_life_countdown > 0 {
-            self.extra_life_countdown -= 1;
-        }
+        self.extra_life();
 
         // thump
-        let signal = memory.mapped_io.sndthump;
+        let signal = self.io.sndthump;
         if signal > 4 && self.thump_low.signal <= 4 {
             if signal == 16 {
                 self.thump_low.play();
@@ -131,7 +411,7 @@ impl Sounds {
         self.thump_low.signal = signal;
 
         // thrust
-        let signal = memory.mapped_io.sndthrust;
+        let signal = self.io.sndthrust;
         if self.thrust.signal < signal {
             self.thrust.play_continuous();
         }
@@ -141,3 +421,129 @@ impl Sounds {
         self.thrust.signal = signal;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    // writes down what it's asked to do, as "effect call"
+    struct RecordingVoice {
+        name: String,
+        log: Log,
+    }
+
+    impl RecordingVoice {
+        fn record(&self, call: &str) {
+            self.log.borrow_mut().push(format!("{} {}", self.name, call));
+        }
+    }
+
+    impl Voice for RecordingVoice {
+        fn play(&mut self) {
+            self.record("play");
+        }
+
+        fn play_continuous(&mut self) {
+            self.record("play_continuous");
+        }
+
+        fn stop(&mut self) {
+            self.record("stop");
+        }
+
+        fn halt(&mut self) {
+            self.record("halt");
+        }
+
+        fn playing(&self) -> bool {
+            false
+        }
+
+        fn set_volume(&mut self, _volume: i32) {}
+
+        fn set_fade(&mut self, _fade: Duration) {}
+    }
+
+    fn recorded_sounds() -> (Sounds, Log) {
+        let log: Log = Rc::new(RefCell::new(Vec::new()));
+        let voice_log = log.clone();
+        let sounds = Sounds::with_voices(move |file_name, _| {
+            Box::new(RecordingVoice {
+                name: file_name.trim_end_matches(".ogg").to_string(),
+                log: voice_log.clone(),
+            })
+        });
+        (sounds, log)
+    }
+
+    fn writes(writes: &[(u16, u8)]) -> Vec<SoundEvent> {
+        writes
+            .iter()
+            .map(|&(address, value)| SoundEvent { cycle: 0, address, value })
+            .collect()
+    }
+
+    #[test]
+    fn sound_signals_drive_the_voices() {
+        let (mut sounds, log) = recorded_sounds();
+        let tick = Duration::from_millis(4);
+        let script: [&[(u16, u8)]; 9] = [
+            // fire, held for a tick, which doesn't fire again
+            &[(0x3C04, 0x80)],
+            &[(0x3C04, 0x80), (0x3C04, 0x00)],
+            // an explosion, and the large saucer coming and going
+            &[(0x3600, 0x3D), (0x3C02, 160), (0x3C00, 0x80)],
+            &[(0x3C00, 0x00)],
+            // then the small one
+            &[(0x3C02, 0), (0x3C00, 0x80)],
+            &[(0x3C00, 0x00)],
+            // thrust on and off, within a tick
+            &[(0x3C03, 0x80), (0x3C03, 0x00)],
+            // the low thump, then the high one
+            &[(0x3A00, 16), (0x3A00, 0), (0x3A00, 15), (0x3A00, 0)],
+            // an extra life
+            &[(0x3C05, 0x80)],
+        ];
+        for events in script.iter() {
+            sounds.play(&writes(events), tick);
+        }
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "ship_fire play",
+                "explosion play",
+                "large_ufo play_continuous",
+                "large_ufo stop",
+                "small_ufo stop",
+                "small_ufo play_continuous",
+                "large_ufo stop",
+                "small_ufo stop",
+                "thrust play_continuous",
+                "thrust stop",
+                "thump_low play",
+                "thump_high play",
+                "extra_life play",
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_life_cooldown() {
+        let (mut sounds, log) = recorded_sounds();
+        let second = Duration::from_secs(1);
+        sounds.play(&writes(&[(0x3C05, 0x80)]), second);
+        // dropped and raised again a second later, and held on past the
+        // end of the cooldown
+        sounds.play(&writes(&[(0x3C05, 0x00)]), second);
+        sounds.play(&writes(&[(0x3C05, 0x80)]), second);
+        assert_eq!(log.borrow().len(), 1);
+        sounds.play(&[], second);
+        assert_eq!(log.borrow().len(), 1);
+        sounds.play(&[], second);
+        assert_eq!(*log.borrow(), vec!["extra_life play", "extra_life play"]);
+    }
+}
@@ -0,0 +1,70 @@
+// showing frames in step with the monitor. the game draws a frame whenever
+// it likes, which is about 60 times a second but not in time with the
+// monitor's refresh, so presenting each one as it's drawn can tear. with
+// vsync the canvas waits for the refresh when presenting, so rather than
+// hold up the CPU each time, frames are only drawn as they come and the
+// latest is presented once the refresh it's due for is near
+//
+// a frame drawn over another before that was presented is dropped, which
+// happens when the game draws faster than the monitor refreshes
+
+use std::time::{Duration, Instant};
+
+// used if the display doesn't say how fast it refreshes
+const DEFAULT_REFRESH_RATE: u32 = 60;
+
+pub struct FramePacer {
+    period: Duration, // between refreshes
+    last_present: Option<Instant>,
+    pending: u64, // frames drawn since the last present
+    presented: u64,
+    dropped: u64,
+}
+
+impl FramePacer {
+    // the refresh rate is in Hz, 0 if not known
+    pub fn new(refresh_rate: u32) -> FramePacer {
+        let rate = if refresh_rate > 0 {
+            refresh_rate
+        } else {
+            DEFAULT_REFRESH_RATE
+        };
+        FramePacer {
+            period: Duration::from_nanos(1_000_000_000 / rate as u64),
+            last_present: None,
+            pending: 0,
+            presented: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn frame_drawn(&mut self) {
+        self.pending += 1;
+    }
+
+    // whether there's a new frame and the next refresh is close enough to
+    // wait for. presenting blocks until the refresh, so waiting for half a
+    // period at most keeps to one present a refresh without holding the CPU
+    // up for long
+    pub fn should_present(&self, now: Instant) -> bool {
+        self.pending > 0
+            && self
+                .last_present
+                .map_or(true, |last| now.duration_since(last) >= self.period / 2)
+    }
+
+    // called with the time the present returned, just after the refresh
+    pub fn presented(&mut self, now: Instant) {
+        if self.pending > 1 {
+            self.dropped += self.pending - 1;
+        }
+        self.pending = 0;
+        self.presented += 1;
+        self.last_present = Some(now);
+    }
+
+    // frames drawn and frames never shown
+    pub fn counts(&self) -> (u64, u64) {
+        (self.presented + self.dropped, self.dropped)
+    }
+}
@@ -0,0 +1,96 @@
+// serve each frame's display list as JSON to any connected WebSocket
+// clients, e.g. a browser based vector viewer
+
+use display::{Segment, VectorSink};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tungstenite::{self, Message, WebSocket};
+
+// how long a slow client can hold up a frame
+const WRITE_TIMEOUT: Duration = Duration::from_millis(20);
+
+// the most a client can stall the emulation by connecting and not sending
+// its upgrade request, as the handshake is done on the main thread
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct DisplayListServer {
+    listener: TcpListener,
+    clients: Vec<WebSocket<TcpStream>>,
+    frame: u64,
+}
+
+impl DisplayListServer {
+    pub fn bind(addr: &str) -> io::Result<DisplayListServer> {
+        let listener = TcpListener::bind(addr)?;
+        // we poll for new clients once a frame rather than blocking
+        listener.set_nonblocking(true)?;
+        Ok(DisplayListServer {
+            listener,
+            clients: Vec::new(),
+            frame: 0,
+        })
+    }
+
+    fn accept_clients(&mut self) {
+        while let Ok((stream, peer)) = self.listener.accept() {
+            // a slow client shouldn't be able to stall the emulation, so
+            // the handshake's reads time out and the client is turned away,
+            // and later writes time out and the client is dropped
+            let ready = stream.set_nonblocking(false).is_ok()
+                && stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).is_ok()
+                && stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok();
+            if !ready {
+                continue;
+            }
+            match tungstenite::accept(stream) {
+                Ok(client) => {
+                    println!("Display list client connected from {}", peer);
+                    self.clients.push(client);
+                }
+                Err(e) => println!("Display list handshake with {} failed: {}", peer, e),
+            }
+        }
+    }
+
+    // the message is {"frame":n,"space":{...},"segments":[[x0,y0,x1,y1,z],...]}
+    // where coordinates are in DVG units with y increasing upwards and the
+    // visible area bounded by space. a z of 0 is a blanked beam movement
+    fn frame_json(frame: u64, segments: &[Segment]) -> String {
+        let mut json = format!(
+            "{{\"frame\":{},\"space\":{{\"x_min\":0,\"x_max\":1023,\"y_min\":96,\"y_max\":927}},\"segments\":[",
+            frame
+        );
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(&format!(
+                "[{},{},{},{},{}]",
+                segment.x0, segment.y0, segment.x1, segment.y1, segment.z
+            ));
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl VectorSink for DisplayListServer {
+    fn send_frame(&mut self, segments: &[Segment]) {
+        self.accept_clients();
+        self.frame += 1;
+        if self.clients.is_empty() {
+            return;
+        }
+        let json = DisplayListServer::frame_json(self.frame, segments);
+        let mut connected = Vec::with_capacity(self.clients.len());
+        for mut client in self.clients.drain(..) {
+            if client.write_message(Message::Text(json.clone())).is_ok() {
+                connected.push(client);
+            } else {
+                println!("Display list client disconnected");
+            }
+        }
+        self.clients = connected;
+    }
+}
@@ -0,0 +1,108 @@
+// Tom Harte's single step tests (https://github.com/SingleStepTests/65x02),
+// run through --single-step-tests with a test for each op code, so one can
+// be looked at on its own, e.g. cargo test --test harte 0x69. the 6502/v1
+// files go in assets/single_step_tests, and an op code whose file isn't
+// there passes without running
+
+use std::path::Path;
+use std::process::Command;
+
+fn run_op_code(name: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("assets")
+        .join("single_step_tests")
+        .join(format!("{}.json", name));
+    if !path.exists() {
+        println!("{} not found, skipping", path.display());
+        return;
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_asteroids-emu"))
+        .arg("--single-step-tests")
+        .arg(&path)
+        .output()
+        .expect("running the emulator");
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+macro_rules! op_codes {
+    ($($test:ident $name:expr,)*) => {
+        $(
+            #[test]
+            fn $test() {
+                run_op_code($name);
+            }
+        )*
+    };
+}
+
+op_codes! {
+    harte_0x00 "00", harte_0x01 "01", harte_0x02 "02", harte_0x03 "03",
+    harte_0x04 "04", harte_0x05 "05", harte_0x06 "06", harte_0x07 "07",
+    harte_0x08 "08", harte_0x09 "09", harte_0x0a "0a", harte_0x0b "0b",
+    harte_0x0c "0c", harte_0x0d "0d", harte_0x0e "0e", harte_0x0f "0f",
+    harte_0x10 "10", harte_0x11 "11", harte_0x12 "12", harte_0x13 "13",
+    harte_0x14 "14", harte_0x15 "15", harte_0x16 "16", harte_0x17 "17",
+    harte_0x18 "18", harte_0x19 "19", harte_0x1a "1a", harte_0x1b "1b",
+    harte_0x1c "1c", harte_0x1d "1d", harte_0x1e "1e", harte_0x1f "1f",
+    harte_0x20 "20", harte_0x21 "21", harte_0x22 "22", harte_0x23 "23",
+    harte_0x24 "24", harte_0x25 "25", harte_0x26 "26", harte_0x27 "27",
+    harte_0x28 "28", harte_0x29 "29", harte_0x2a "2a", harte_0x2b "2b",
+    harte_0x2c "2c", harte_0x2d "2d", harte_0x2e "2e", harte_0x2f "2f",
+    harte_0x30 "30", harte_0x31 "31", harte_0x32 "32", harte_0x33 "33",
+    harte_0x34 "34", harte_0x35 "35", harte_0x36 "36", harte_0x37 "37",
+    harte_0x38 "38", harte_0x39 "39", harte_0x3a "3a", harte_0x3b "3b",
+    harte_0x3c "3c", harte_0x3d "3d", harte_0x3e "3e", harte_0x3f "3f",
+    harte_0x40 "40", harte_0x41 "41", harte_0x42 "42", harte_0x43 "43",
+    harte_0x44 "44", harte_0x45 "45", harte_0x46 "46", harte_0x47 "47",
+    harte_0x48 "48", harte_0x49 "49", harte_0x4a "4a", harte_0x4b "4b",
+    harte_0x4c "4c", harte_0x4d "4d", harte_0x4e "4e", harte_0x4f "4f",
+    harte_0x50 "50", harte_0x51 "51", harte_0x52 "52", harte_0x53 "53",
+    harte_0x54 "54", harte_0x55 "55", harte_0x56 "56", harte_0x57 "57",
+    harte_0x58 "58", harte_0x59 "59", harte_0x5a "5a", harte_0x5b "5b",
+    harte_0x5c "5c", harte_0x5d "5d", harte_0x5e "5e", harte_0x5f "5f",
+    harte_0x60 "60", harte_0x61 "61", harte_0x62 "62", harte_0x63 "63",
+    harte_0x64 "64", harte_0x65 "65", harte_0x66 "66", harte_0x67 "67",
+    harte_0x68 "68", harte_0x69 "69", harte_0x6a "6a", harte_0x6b "6b",
+    harte_0x6c "6c", harte_0x6d "6d", harte_0x6e "6e", harte_0x6f "6f",
+    harte_0x70 "70", harte_0x71 "71", harte_0x72 "72", harte_0x73 "73",
+    harte_0x74 "74", harte_0x75 "75", harte_0x76 "76", harte_0x77 "77",
+    harte_0x78 "78", harte_0x79 "79", harte_0x7a "7a", harte_0x7b "7b",
+    harte_0x7c "7c", harte_0x7d "7d", harte_0x7e "7e", harte_0x7f "7f",
+    harte_0x80 "80", harte_0x81 "81", harte_0x82 "82", harte_0x83 "83",
+    harte_0x84 "84", harte_0x85 "85", harte_0x86 "86", harte_0x87 "87",
+    harte_0x88 "88", harte_0x89 "89", harte_0x8a "8a", harte_0x8b "8b",
+    harte_0x8c "8c", harte_0x8d "8d", harte_0x8e "8e", harte_0x8f "8f",
+    harte_0x90 "90", harte_0x91 "91", harte_0x92 "92", harte_0x93 "93",
+    harte_0x94 "94", harte_0x95 "95", harte_0x96 "96", harte_0x97 "97",
+    harte_0x98 "98", harte_0x99 "99", harte_0x9a "9a", harte_0x9b "9b",
+    harte_0x9c "9c", harte_0x9d "9d", harte_0x9e "9e", harte_0x9f "9f",
+    harte_0xa0 "a0", harte_0xa1 "a1", harte_0xa2 "a2", harte_0xa3 "a3",
+    harte_0xa4 "a4", harte_0xa5 "a5", harte_0xa6 "a6", harte_0xa7 "a7",
+    harte_0xa8 "a8", harte_0xa9 "a9", harte_0xaa "aa", harte_0xab "ab",
+    harte_0xac "ac", harte_0xad "ad", harte_0xae "ae", harte_0xaf "af",
+    harte_0xb0 "b0", harte_0xb1 "b1", harte_0xb2 "b2", harte_0xb3 "b3",
+    harte_0xb4 "b4", harte_0xb5 "b5", harte_0xb6 "b6", harte_0xb7 "b7",
+    harte_0xb8 "b8", harte_0xb9 "b9", harte_0xba "ba", harte_0xbb "bb",
+    harte_0xbc "bc", harte_0xbd "bd", harte_0xbe "be", harte_0xbf "bf",
+    harte_0xc0 "c0", harte_0xc1 "c1", harte_0xc2 "c2", harte_0xc3 "c3",
+    harte_0xc4 "c4", harte_0xc5 "c5", harte_0xc6 "c6", harte_0xc7 "c7",
+    harte_0xc8 "c8", harte_0xc9 "c9", harte_0xca "ca", harte_0xcb "cb",
+    harte_0xcc "cc", harte_0xcd "cd", harte_0xce "ce", harte_0xcf "cf",
+    harte_0xd0 "d0", harte_0xd1 "d1", harte_0xd2 "d2", harte_0xd3 "d3",
+    harte_0xd4 "d4", harte_0xd5 "d5", harte_0xd6 "d6", harte_0xd7 "d7",
+    harte_0xd8 "d8", harte_0xd9 "d9", harte_0xda "da", harte_0xdb "db",
+    harte_0xdc "dc", harte_0xdd "dd", harte_0xde "de", harte_0xdf "df",
+    harte_0xe0 "e0", harte_0xe1 "e1", harte_0xe2 "e2", harte_0xe3 "e3",
+    harte_0xe4 "e4", harte_0xe5 "e5", harte_0xe6 "e6", harte_0xe7 "e7",
+    harte_0xe8 "e8", harte_0xe9 "e9", harte_0xea "ea", harte_0xeb "eb",
+    harte_0xec "ec", harte_0xed "ed", harte_0xee "ee", harte_0xef "ef",
+    harte_0xf0 "f0", harte_0xf1 "f1", harte_0xf2 "f2", harte_0xf3 "f3",
+    harte_0xf4 "f4", harte_0xf5 "f5", harte_0xf6 "f6", harte_0xf7 "f7",
+    harte_0xf8 "f8", harte_0xf9 "f9", harte_0xfa "fa", harte_0xfb "fb",
+    harte_0xfc "fc", harte_0xfd "fd", harte_0xfe "fe", harte_0xff "ff",
+}